@@ -0,0 +1,119 @@
+use std::io::{self, Read};
+use fast_web_server_types::{HttpRequest, HttpRequestError};
+
+use crate::middleware::Encoding;
+
+
+/// The codings of a request's `Content-Encoding`, in the order they were
+/// applied. Checked before the body is read, so a body the server cannot
+/// decode is refused with `415 Unsupported Media Type` without reading it.
+pub(crate) fn content_codings(request: &HttpRequest) -> Result<Vec<Encoding>, HttpRequestError> {
+    let codings = match request.headers.get("Content-Encoding") {
+        Some(codings) => codings,
+        None => return Ok(vec![]),
+    };
+    codings.split(',')
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+        .map(|coding| Encoding::from_name(coding).ok_or_else(|| HttpRequestError::UnsupportedEncoding(coding.to_string())))
+        .collect()
+}
+
+/// The codings to list in `Accept-Encoding` when refusing a request body,
+/// RFC 9110 section 15.5.16.
+pub(crate) fn accepted_codings() -> String {
+    match Encoding::ALL {
+        [] => "identity".to_string(),
+        all => all.iter().map(Encoding::as_str).collect::<Vec<_>>().join(", "),
+    }
+}
+
+/// Sets the request's body to `body` with its `Content-Encoding` undone,
+/// so handlers see what the client meant to send. The route's body limit
+/// `max_body` applies to every decoded stage, so a small compressed body
+/// cannot expand into an unbounded one.
+pub(crate) fn decode_body(request: &mut HttpRequest, mut body: Vec<u8>, max_body: usize) -> Result<(), HttpRequestError> {
+    let codings = content_codings(request)?;
+    if codings.is_empty() || body.is_empty() {
+        return request.set_body(body);
+    }
+    for &encoding in codings.iter().rev() {
+        body = decode(encoding, &body, max_body)?;
+    }
+    request.headers.remove("Content-Encoding");
+    request.headers.insert("Content-Length".to_string(), body.len().to_string());
+    request.set_body(body)
+}
+
+fn decode(encoding: Encoding, body: &[u8], max_body: usize) -> Result<Vec<u8>, HttpRequestError> {
+    let mut decoded = vec![];
+    // One byte past the limit tells an oversized body from one that fits exactly.
+    decoder(encoding, body)
+        .and_then(|decoder| decoder.take(max_body as u64 + 1).read_to_end(&mut decoded))
+        .map_err(|e| HttpRequestError::Decoding(encoding.as_str(), e.to_string()))?;
+    if decoded.len() > max_body {
+        return Err(HttpRequestError::DecodedBodyTooLarge(max_body));
+    }
+    Ok(decoded)
+}
+
+fn decoder(encoding: Encoding, body: &[u8]) -> io::Result<Box<dyn Read + '_>> {
+    match encoding {
+        #[cfg(feature = "br")]
+        Encoding::Brotli => Ok(Box::new(brotli::Decompressor::new(body, 4096))),
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(body)?)),
+        // Concatenated gzip members form one body, as with `cat a.gz b.gz`.
+        #[cfg(feature = "gzip")]
+        Encoding::Gzip => Ok(Box::new(flate2::read::MultiGzDecoder::new(body))),
+        #[cfg(feature = "deflate")]
+        Encoding::Deflate => Ok(Box::new(flate2::read::ZlibDecoder::new(body))),
+    }
+}
+
+#[cfg(all(test, feature = "gzip"))]
+mod tests {
+    use std::io::{Cursor, Write};
+    use flate2::write::GzEncoder;
+
+    use super::*;
+
+    fn request(content_encoding: &str) -> HttpRequest {
+        let raw = format!("POST / HTTP/1.1\r\nContent-Encoding: {}\r\n\r\n", content_encoding);
+        HttpRequest::new(&mut Cursor::new(raw.as_bytes())).unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decode_body() {
+        let mut json = request("gzip");
+        decode_body(&mut json, gzip(b"{\"id\": 1}"), 1024).unwrap();
+        assert_eq!(json.body, "{\"id\": 1}");
+        assert_eq!(json.headers.get("Content-Encoding"), None);
+        assert_eq!(json.headers.get("Content-Length"), Some(&"9".to_string()));
+
+        // Codings are undone in reverse order of application.
+        let mut twice = request("gzip, identity, x-gzip");
+        decode_body(&mut twice, gzip(&gzip(b"twice")), 1024).unwrap();
+        assert_eq!(twice.body, "twice");
+    }
+
+    #[test]
+    fn test_limits_and_errors() {
+        let bomb = gzip(&vec![0; 1 << 20]);
+        assert!(bomb.len() < 4096);
+        assert!(matches!(decode_body(&mut request("gzip"), bomb, 4096), Err(HttpRequestError::DecodedBodyTooLarge(4096))));
+        assert!(decode_body(&mut request("gzip"), gzip(&[b'a'; 4096]), 4096).is_ok());
+
+        let error = decode_body(&mut request("gzip"), b"not gzip".to_vec(), 4096).unwrap_err();
+        assert!(matches!(error, HttpRequestError::Decoding("gzip", _)));
+        let error = content_codings(&request("gzip, compress")).unwrap_err();
+        assert!(matches!(&error, HttpRequestError::UnsupportedEncoding(coding) if coding == "compress"));
+        assert_eq!(error.status_code(), Some(fast_web_server_types::StatusCode::Code415));
+    }
+}
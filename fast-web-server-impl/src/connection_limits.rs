@@ -0,0 +1,90 @@
+use std::time::Duration;
+use fast_web_server_types::{HttpResponse, StatusCode};
+
+
+/// What the server does with a connection that arrives while it is at capacity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Overload {
+    /// Accept it and answer `503 Service Unavailable` with a `Retry-After`
+    /// header right away, without reading the request.
+    Reject { retry_after: Duration },
+    /// Stop accepting until a connection finishes, leaving new connections
+    /// in the kernel's listen backlog.
+    Backlog,
+}
+
+/// Bounds on the connections the server holds at once: those being served,
+/// including those streaming a response body, plus those waiting for a
+/// free worker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Connections accepted and not yet finished, queued or active.
+    pub max_connections: Option<usize>,
+    /// Connections waiting in the thread pool queue for a worker.
+    pub max_queued: Option<usize>,
+    pub overload: Overload,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_queued: Some(1024),
+            overload: Overload::Reject { retry_after: Duration::from_secs(1) },
+        }
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn max_queued(mut self, max_queued: usize) -> Self {
+        self.max_queued = Some(max_queued);
+        self
+    }
+
+    pub fn overload(mut self, overload: Overload) -> Self {
+        self.overload = overload;
+        self
+    }
+
+    pub(crate) fn is_exceeded(&self, queued: usize, active: usize) -> bool {
+        self.max_queued.is_some_and(|max| queued >= max)
+            || self.max_connections.is_some_and(|max| queued + active >= max)
+    }
+}
+
+pub(crate) fn overload_response(retry_after: Duration) -> HttpResponse {
+    let mut response = HttpResponse::json_error(StatusCode::Code503, "service_unavailable", Some("the server is overloaded"));
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response.headers.insert("Retry-After".to_string(), seconds.to_string());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_exceeded() {
+        let limits = ConnectionLimits::new().max_connections(10).max_queued(4);
+        assert!(!limits.is_exceeded(3, 6));
+        assert!(limits.is_exceeded(4, 0));
+        assert!(limits.is_exceeded(2, 8));
+        assert!(!ConnectionLimits { max_queued: None, ..ConnectionLimits::new() }.is_exceeded(10_000, 4));
+    }
+
+    #[test]
+    fn test_overload_response() {
+        let response = overload_response(Duration::from_millis(1500));
+        assert_eq!(response.status_line.status_code, StatusCode::Code503);
+        assert_eq!(response.headers.get("Retry-After"), Some(&"2".to_string()));
+    }
+}
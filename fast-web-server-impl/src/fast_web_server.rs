@@ -1,15 +1,140 @@
-use std::collections::HashMap;
-use std::io::{Write, BufWriter, ErrorKind};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, RwLock};
+use std::io::{self, BufRead, Read, Write, BufReader, ErrorKind};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 use rayon::ThreadPool;
-use fast_web_server_types::{HttpFn, HttpRequest, HttpResponse, HttpVersion, RequestType, StatusCode, StatusLine, HttpHeaders};
+use fast_web_server_types::{Extensions, Handler, HttpRequest, HttpRequestError, HttpResponse, HttpVersion, RequestId, RequestLimits, RequestType, StatusCode, StreamingBody, FileBody};
+use tracing::{debug, info_span, warn, Span};
 
+use crate::body_decoding;
+use crate::http2::{self, Http2Settings, Upgrade};
+use crate::connection_limits::{overload_response, ConnectionLimits, Overload};
+use crate::metrics::{Metrics, UNMATCHED_ROUTE};
+use crate::middleware::{Middleware, Next};
+use crate::route_group::RouteGroup;
+use crate::response_writer;
+use crate::router::{Endpoint, MatchedRoute, Router};
+use crate::sendfile;
+use crate::streaming::{StreamConnection, Streamer};
+use crate::timeouts::{DeadlineStream, SetTimeout, Timeouts};
+use crate::websocket::{self, UpgradeSlot, WebSocketConfig};
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsAcceptor, TlsConfig, TlsError};
+use crate::Routes;
+
+
+/// The interim response asking a client that sent `Expect: 100-continue`
+/// for the body.
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Everything a worker needs to serve a connection. Only mutable until the
+/// server starts running and shares it with the workers.
+pub(crate) struct ServerContext {
+    pub(crate) routes: Router,
+    state: Arc<Extensions>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) limits: RequestLimits,
+    connection_limits: ConnectionLimits,
+    pub(crate) http2: Http2Settings,
+    pub(crate) websocket: WebSocketConfig,
+    streamer: Streamer,
+    /// Runs handlers with a deadline and those of HTTP/2 streams, started on
+    /// first use with `handler_threads` threads, or one per CPU if that is 0.
+    handler_pool: OnceLock<ThreadPool>,
+    pub(crate) handler_threads: usize,
+    /// Signalled whenever a connection leaves the queue or finishes.
+    capacity_freed: Arc<(Mutex<()>, Condvar)>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<TlsAcceptor>>,
+}
+
+impl ServerContext {
+    fn is_overloaded(&self) -> bool {
+        self.connection_limits.is_exceeded(self.metrics.queued_connections(), self.metrics.active_connections())
+    }
+
+    fn wait_for_capacity(&self) {
+        let (lock, condvar) = &*self.capacity_freed;
+        let mut guard = lock.lock().unwrap();
+        while self.is_overloaded() {
+            guard = condvar.wait_timeout(guard, Duration::from_millis(100)).unwrap().0;
+        }
+    }
+
+    pub(crate) fn spawn_handler(&self, job: impl FnOnce() + Send + 'static) {
+        let pool = self.handler_pool.get_or_init(|| rayon::ThreadPoolBuilder::new()
+            .num_threads(self.handler_threads)
+            .thread_name(|i| format!("fast-web-server-handler-{}", i))
+            // Handler panics are caught by `run_handler`; this keeps any
+            // other panic in a job from aborting the process.
+            .panic_handler(|_| {})
+            .build()
+            .unwrap());
+        pool.spawn(job);
+    }
+}
+
+impl Default for ServerContext {
+    fn default() -> Self {
+        let metrics = Arc::new(Metrics::default());
+        let mut state = Extensions::new();
+        state.insert(metrics.clone());
+        Self {
+            routes: Router::default(),
+            state: Arc::new(state),
+            middlewares: vec![],
+            metrics,
+            timeouts: Timeouts::default(),
+            limits: RequestLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            http2: Http2Settings::default(),
+            websocket: WebSocketConfig::default(),
+            streamer: Streamer::default(),
+            handler_pool: OnceLock::new(),
+            handler_threads: 0,
+            capacity_freed: Arc::new((Mutex::new(()), Condvar::new())),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// Address of the client that sent a request, stored in the request extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// The body of an HTTP/1 response that is sent after its head by the
+/// caller of `handle_client`, which owns the connection.
+pub(crate) enum Tail {
+    Stream(StreamingBody),
+    File(FileBody),
+}
+
+/// Counts the bytes read from a connection.
+struct CountingReader<'a, R> {
+    inner: &'a mut R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
 
 pub struct FastWebServer {
     listener: TcpListener,
-    thread_pool: ThreadPool,
-    routes: Arc<RwLock<HashMap<(RequestType, String), HttpFn>>>,
+    thread_pool: Arc<ThreadPool>,
+    context: Arc<ServerContext>,
+    #[cfg(feature = "tls")]
+    redirect_listener: Option<TcpListener>,
 }
 
 impl FastWebServer {
@@ -23,79 +148,853 @@ impl FastWebServer {
         Self {
             listener: TcpListener::bind(addr).unwrap(),
             // thread_pool: ThreadPool::new(num_workers),
-            thread_pool: pool,
-            routes: Arc::new(RwLock::new(HashMap::default())),
+            thread_pool: Arc::new(pool),
+            context: Arc::new(ServerContext { handler_threads: num_workers, ..ServerContext::default() }),
+            #[cfg(feature = "tls")]
+            redirect_listener: None,
         }
     }
 
-    pub fn bind(&mut self, request_type: RequestType, route: &str, func: HttpFn) {
-        let mut routes = self.routes.write().unwrap();
-        routes.insert((request_type, route.to_string()), func);
+    /// The metrics registry the server and `#[instrument]`ed handlers record into.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.context.metrics.clone()
+    }
+
+    /// Replaces the default read, handler and write deadlines.
+    pub fn timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.context_mut().timeouts = timeouts;
+        self
+    }
+
+    /// Replaces the default bounds on concurrent and queued connections.
+    pub fn connection_limits(&mut self, connection_limits: ConnectionLimits) -> &mut Self {
+        self.context_mut().connection_limits = connection_limits;
+        self
+    }
+
+    /// Replaces the default request size limits.
+    pub fn limits(&mut self, limits: RequestLimits) -> &mut Self {
+        self.context_mut().limits = limits;
+        self
+    }
+
+    /// Replaces the default HTTP/2 settings.
+    pub fn http2(&mut self, settings: Http2Settings) -> &mut Self {
+        self.context_mut().http2 = settings;
+        self
+    }
+
+    /// Replaces the default settings of WebSocket connections.
+    pub fn websocket(&mut self, config: WebSocketConfig) -> &mut Self {
+        self.context_mut().websocket = config;
+        self
+    }
+
+    /// Overrides the body size limit of an already bound route, e.g. to
+    /// allow large uploads on a single endpoint.
+    pub fn body_limit(&mut self, request_type: RequestType, route: &str, max_body: usize) -> &mut Self {
+        match self.context_mut().routes.endpoint_mut(&request_type, route) {
+            Some(endpoint) => endpoint.max_body = Some(max_body),
+            None => panic!("Cannot set the body limit of unbound route {} {:?}", request_type, route),
+        }
+        self
+    }
+
+    /// Serves the metrics registry in the Prometheus text format at `route`.
+    pub fn expose_metrics(&mut self, route: &str) -> &mut Self {
+        let metrics = self.metrics();
+        self.bind(RequestType::GET, route, move |_request: HttpRequest| {
+            let mut response = HttpResponse::from_body(metrics.render());
+            response.headers.insert("Content-Type".to_string(), "text/plain; version=0.0.4".to_string());
+            response
+        });
+        self
+    }
+
+    /// Serves HTTPS instead of plain HTTP. Certificates are read now and
+    /// again on `SIGHUP` or `reload_certificates`.
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, config: TlsConfig) -> Result<&mut Self, TlsError> {
+        self.context_mut().tls = Some(Arc::new(TlsAcceptor::new(config)?));
+        Ok(self)
+    }
+
+    /// Rereads all certificates and keys. Connections that are already
+    /// established keep theirs; on error the old certificates stay in use.
+    #[cfg(feature = "tls")]
+    pub fn reload_certificates(&self) -> Result<(), TlsError> {
+        match &self.context.tls {
+            Some(acceptor) => acceptor.reload(),
+            None => Ok(()),
+        }
+    }
+
+    /// Also listens for plain HTTP on `addr` and redirects every request
+    /// there to the same URL over HTTPS with `308 Permanent Redirect`.
+    #[cfg(feature = "tls")]
+    pub fn redirect_http(&mut self, addr: &str) -> io::Result<&mut Self> {
+        self.redirect_listener = Some(TcpListener::bind(addr)?);
+        Ok(self)
+    }
+
+    fn context_mut(&mut self) -> &mut ServerContext {
+        Arc::get_mut(&mut self.context)
+            .expect("The server can only be configured before it runs")
+    }
+
+    /// Registers shared application state, available to handlers through
+    /// the `State<T>` extractor or a `&T` argument.
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Self {
+        Arc::get_mut(&mut self.context_mut().state)
+            .expect("Application state must be registered before the server runs")
+            .insert(Arc::new(state));
+        self
+    }
+
+    /// Adds a middleware that runs for every request, including requests
+    /// that match no route. Middlewares run in registration order.
+    pub fn wrap<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.context_mut().middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Binds a handler to a route. Besides macro-generated handlers this
+    /// accepts any `Fn(HttpRequest) -> impl Responder + Send + Sync` closure.
+    pub fn bind<H: Handler + 'static>(&mut self, request_type: RequestType, route: &str, handler: H) {
+        self.bind_arc(request_type, route, Arc::new(handler));
+    }
+
+    pub fn bind_boxed(&mut self, request_type: RequestType, route: &str, handler: Box<dyn Handler>) {
+        self.bind_arc(request_type, route, Arc::from(handler));
+    }
+
+    /// Binds all routes of a group below its prefix, wrapped in its
+    /// middlewares. Services like `StaticFiles` are mounted the same way.
+    pub fn mount(&mut self, group: impl Into<RouteGroup>) {
+        let group = group.into();
+        let middlewares: Arc<[Arc<dyn Middleware>]> = group.middlewares.clone().into();
+        for (request_type, route, handler) in &group.routes {
+            let endpoint = Endpoint {
+                handler: handler.clone(),
+                middlewares: middlewares.clone(),
+                route: None,
+                max_body: group.max_body,
+            };
+            self.insert_route(request_type.clone(), &group.path(route), endpoint);
+        }
+    }
+
+    fn insert_route(&mut self, request_type: RequestType, route: &str, endpoint: Endpoint) {
+        if let Err(e) = self.context_mut().routes.insert(request_type, route, endpoint) {
+            panic!("Could not bind route {:?}: {}", route, e);
+        }
     }
 
     pub fn run(&self) -> Result<(), String> {
-        for stream in self.listener.incoming() {
-            self.handle_connection(stream.unwrap());
+        #[cfg(feature = "tls")]
+        self.start_tls_services()?;
+        loop {
+            if self.context.connection_limits.overload == Overload::Backlog {
+                self.context.wait_for_capacity();
+            }
+            match self.listener.accept() {
+                Ok((stream, _)) => self.handle_connection(stream),
+                Err(e) => warn!(error = %e, "could not accept connection"),
+            }
+        }
+    }
+
+    /// Starts the certificate reloader and the HTTP redirect listener.
+    #[cfg(feature = "tls")]
+    fn start_tls_services(&self) -> Result<(), String> {
+        #[cfg(unix)]
+        if let Some(acceptor) = self.context.tls.clone() {
+            let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])
+                .map_err(|e| e.to_string())?;
+            thread::spawn(move || for _ in signals.forever() {
+                match acceptor.reload() {
+                    Ok(_) => tracing::info!("reloaded TLS certificates"),
+                    Err(e) => warn!(error = %e, "could not reload TLS certificates"),
+                }
+            });
+        }
+        if let Some(listener) = &self.redirect_listener {
+            let listener = listener.try_clone().map_err(|e| e.to_string())?;
+            let https_port = self.listener.local_addr().map_err(|e| e.to_string())?.port();
+            let (context, thread_pool) = (self.context.clone(), self.thread_pool.clone());
+            // Redirects share the workers and connection limits of the server.
+            thread::spawn(move || loop {
+                if context.connection_limits.overload == Overload::Backlog {
+                    context.wait_for_capacity();
+                }
+                match listener.accept() {
+                    Ok((stream, _)) => Self::spawn_connection(&context, &thread_pool, stream, move |context, stream, _| {
+                        Self::redirect(context, stream, https_port);
+                        Ok(())
+                    }),
+                    Err(e) => warn!(error = %e, "could not accept connection"),
+                }
+            });
         }
         Ok(())
     }
 
-    fn handle_connection(&self, 
-        // routes: Arc<RwLock<HashMap<(RequestType, String), HttpFn>>>, 
-        stream: TcpStream) {
+    #[cfg(feature = "tls")]
+    fn redirect(context: &ServerContext, stream: TcpStream, https_port: u16) {
+        let mut stream = DeadlineStream::new(stream, context.timeouts.header_read);
+        let response = match HttpRequest::read_head(&mut BufReader::new(&mut stream), &context.limits) {
+            Ok(request) => tls::https_redirect(&request, https_port),
+            Err(e) => match e.status_code() {
+                Some(status_code) => HttpResponse::json_error(status_code, "bad_request", None),
+                None => return,
+            },
+        };
+        stream.set_timeout(context.timeouts.write);
+        if let Err(e) = Self::write_response(context, &mut stream, response) {
+            debug!(error = %e, "could not send redirect");
+        }
+    }
+
+    /// Answers 503 from the accepting thread, without reading the request.
+    /// The write does not block: a client whose socket cannot take the few
+    /// bytes right away is dropped instead of stalling the accept loop.
+    fn reject(context: &ServerContext, mut stream: TcpStream, retry_after: Duration) {
+        context.metrics.connection_rejected();
+        debug!("rejected connection, server at capacity");
+        let result = stream.set_nonblocking(true)
+            .and_then(|_| Self::write_response(context, &mut stream, overload_response(retry_after)))
+            .and_then(|_| stream.shutdown(Shutdown::Write));
+        if let Err(e) = result {
+            debug!(error = %e, "could not send 503");
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream) {
+        Self::spawn_connection(&self.context, &self.thread_pool, stream, Self::serve);
+    }
+
+    /// Queues `serve` for an accepted connection on the worker pool, or
+    /// rejects the connection while the server is at capacity.
+    fn spawn_connection<F>(context: &Arc<ServerContext>, thread_pool: &ThreadPool, stream: TcpStream, serve: F)
+    where F: FnOnce(&Arc<ServerContext>, TcpStream, Option<SocketAddr>) -> io::Result<()> + Send + 'static {
+        if let Overload::Reject { retry_after } = context.connection_limits.overload {
+            if context.is_overloaded() {
+                return Self::reject(context, stream, retry_after);
+            }
+        }
 
-            let routes = self.routes.clone();
-            self.thread_pool.spawn(||  {
-            match Self::handle_client(routes, stream) {
+        let context = context.clone();
+        context.metrics.connection_queued();
+        thread_pool.spawn(move || {
+            context.metrics.connection_started();
+            context.capacity_freed.1.notify_one();
+            let peer = stream.peer_addr().ok();
+            let span = info_span!("connection", peer = %peer.map_or_else(|| "-".to_string(), |addr| addr.to_string()));
+            let _guard = span.enter();
+            match serve(&context, stream, peer) {
                 Ok(_) => {},
-                Err(e) => eprintln!("{}", e),
+                Err(e) => warn!(error = %e, "connection failed"),
             }
+            context.metrics.connection_finished();
+            context.capacity_freed.1.notify_one();
         });
     }
 
+    #[cfg(feature = "tls")]
+    fn serve(context: &Arc<ServerContext>, mut stream: TcpStream, peer: Option<SocketAddr>) -> io::Result<()> {
+        match &context.tls {
+            Some(acceptor) => {
+                let mut stream = acceptor.accept(stream, context.timeouts.header_read)?;
+                let tail = match stream.alpn_protocol() {
+                    Some(b"h2") => http2::serve(context, &mut stream, peer, vec![], None).map(|_| None)?,
+                    _ => Self::handle_client(context, &mut stream, peer, true)?,
+                };
+                match tail {
+                    Some(Tail::Stream(body)) => Self::stream(context, stream, body),
+                    Some(Tail::File(file)) => {
+                        stream.set_write_timeout(Some(context.timeouts.write))?;
+                        sendfile::copy_file(&mut stream, &file, &context.metrics)?;
+                        stream.close()
+                    },
+                    None => stream.close(),
+                }
+            },
+            None => match Self::handle_client(context, &mut stream, peer, false)? {
+                Some(Tail::Stream(body)) => Self::stream(context, stream, body),
+                Some(Tail::File(file)) => Self::send_file(context, &mut stream, file),
+                None => Ok(()),
+            },
+        }
+    }
 
+    #[cfg(not(feature = "tls"))]
+    fn serve(context: &Arc<ServerContext>, mut stream: TcpStream, peer: Option<SocketAddr>) -> io::Result<()> {
+        match Self::handle_client(context, &mut stream, peer, false)? {
+            Some(Tail::Stream(body)) => Self::stream(context, stream, body),
+            Some(Tail::File(file)) => Self::send_file(context, &mut stream, file),
+            None => Ok(()),
+        }
+    }
 
-    fn handle_client(
-        routes: Arc<RwLock<HashMap<(RequestType, String), HttpFn>>>, 
-        mut stream: TcpStream) -> std::io::Result<()> {
+    /// Hands a connection whose response head was sent to the streamer,
+    /// freeing the worker while the body is produced. The connection counts
+    /// against the connection limits until its stream ends.
+    fn stream(context: &ServerContext, connection: impl StreamConnection + 'static, body: StreamingBody) -> io::Result<()> {
+        context.metrics.connection_streaming();
+        let (metrics, capacity_freed) = (context.metrics.clone(), context.capacity_freed.clone());
+        let on_end = move || {
+            metrics.connection_finished();
+            capacity_freed.1.notify_one();
+        };
+        context.streamer.push(Box::new(connection), body, &context.metrics, Box::new(on_end))
+    }
+
+    /// Sends a file body after its response head on a plain connection.
+    fn send_file(context: &ServerContext, stream: &mut TcpStream, file: FileBody) -> io::Result<()> {
+        stream.set_write_timeout(Some(context.timeouts.write))?;
+        sendfile::send_file(stream, &file, &context.metrics)
+    }
+
+    /// Serves an HTTP/1.x request, or switches to HTTP/2 when a plain
+    /// connection starts with the HTTP/2 preface or asks for an h2c upgrade,
+    /// and to WebSocket when the handler accepts a WebSocket handshake.
+    /// Returns the streamed or file body of a response whose head has been sent.
+    pub(crate) fn handle_client<S: Read + Write + SetTimeout + http2::Socket>(
+        context: &Arc<ServerContext>,
+        stream: &mut S,
+        peer: Option<SocketAddr>,
+        tls: bool) -> std::io::Result<Option<Tail>> {
 
-        let http_request = match HttpRequest::new(&mut stream) {
-            Ok(request) => request,
-            Err(e) => return Err(std::io::Error::new(ErrorKind::Other, e.to_string())),
+        let timeouts = &context.timeouts;
+        let h2c = context.http2.h2c && !tls;
+        let mut stream = DeadlineStream::new(stream, timeouts.header_read);
+        let mut reader = BufReader::new(CountingReader { inner: &mut stream, count: 0 });
+        if h2c && reader.fill_buf().is_ok_and(http2::is_preface) {
+            let buffered = reader.buffer().to_vec();
+            context.metrics.add_bytes_received(reader.get_ref().count);
+            drop(reader);
+            return http2::serve(context, stream.into_inner(), peer, buffered, None).map(|_| None);
+        }
+        let parsed = HttpRequest::read_head(&mut reader, &context.limits).and_then(|mut request| {
+            let endpoint = Self::route(context, &mut request);
+            let max_body = endpoint.max_body.unwrap_or(context.limits.max_body);
+            body_decoding::content_codings(&request)?;
+            if Self::expects_continue(&request, max_body)? {
+                let stream = &mut reader.get_mut().inner;
+                stream.set_timeout(timeouts.write);
+                stream.write_all(CONTINUE).and_then(|_| stream.flush())?;
+                context.metrics.add_bytes_sent(CONTINUE.len() as u64);
+            }
+            reader.get_mut().inner.set_timeout(timeouts.body_read);
+            let body = request.read_raw_body(&mut reader, max_body)?;
+            body_decoding::decode_body(&mut request, body, max_body).map(|_| (request, endpoint))
+        });
+        let buffered = reader.buffer().to_vec();
+        context.metrics.add_bytes_received(reader.get_ref().count);
+        drop(reader);
+        let (mut http_request, endpoint) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                context.metrics.parse_error(e.kind());
+                if let Some(status_code) = e.status_code() {
+                    stream.set_timeout(timeouts.write);
+                    Self::write_response(context, &mut stream, Self::error_response(status_code, &e))?;
+                }
+                return Err(std::io::Error::new(ErrorKind::Other, e.to_string()));
+            },
         };
 
-        let request_type = http_request.start_line.request_type.to_owned();
-        let path = http_request.start_line.request_target.uri.to_owned();
-        let key = (request_type, path);
+        if let Some(settings) = http2::h2c_upgrade(&http_request).filter(|_| h2c) {
+            let upgrade = Upgrade { request: http_request, endpoint, settings };
+            return http2::serve(context, stream.into_inner(), peer, buffered, Some(upgrade)).map(|_| None);
+        }
+        let slot = UpgradeSlot::for_request(&http_request, &context.websocket);
+        if let Some(slot) = &slot {
+            http_request.extensions.insert(slot.clone());
+        }
+        let head = http_request.start_line.request_type == RequestType::HEAD;
+        let mut http_response = Self::dispatch(context, &endpoint, http_request, peer);
+        let tail = match (http_response.stream.take(), http_response.file.take()) {
+            // A HEAD response has the headers of a GET but no content.
+            _ if head => None,
+            (Some(body), _) => Some(Tail::Stream(body)),
+            (None, file) => file.map(Tail::File),
+        };
+        // Taken even if a middleware replaced the response, so the callback
+        // and the request it may hold are dropped.
+        let accepted = slot.and_then(|slot| slot.take())
+            .filter(|_| http_response.status_line.status_code == StatusCode::Code101);
+        stream.set_timeout(timeouts.write);
+        Self::write_response(context, &mut stream, http_response)?;
+        match accepted {
+            Some(accepted) => websocket::serve(context, stream.into_inner(), buffered, accepted).map(|_| None),
+            None => Ok(tail),
+        }
+    }
 
-        let response = match routes.read().unwrap().get(&key) {
-            Some(func) => func(http_request),
-            None => Self::get_404().into(),
+    /// Decides on a request's `Expect` header before its body is read, RFC
+    /// 9110 section 10.1.1. Returns whether the client waits for `100
+    /// Continue` before sending the body. Expectations other than
+    /// `100-continue` fail with `417`, and a body announced larger than the
+    /// route's limit with `413`, so the client never sends it.
+    pub(crate) fn expects_continue(request: &HttpRequest, max_body: usize) -> Result<bool, HttpRequestError> {
+        let expect = match request.headers.get("Expect") {
+            Some(expect) => expect,
+            None => return Ok(false),
         };
-        // let response = Self::get_404().into();
-        let http_response = HttpResponse::from_body(String::from_utf8(response).unwrap());
-        let response_vec: Vec<u8> = http_response.into();
-
-        let mut writer = BufWriter::new(stream);
-        match writer.write_all(&response_vec) {
-            Ok(_) => {},
-            Err(e) => return Err(e),
-        }
-        writer.flush()
-    }
-
-    fn get_404() -> HttpResponse {
-        let mut headers = HttpHeaders::default();
-        headers.insert("Content-Type".to_string(), "application/json".to_string());
-        headers.insert("Connection".to_string(), "close".to_string());
-        
-        HttpResponse {
-            status_line: StatusLine {
-                protocol: HttpVersion::HTTP1_1,
-                status_code: StatusCode::Code404
+        if expect.split(',').any(|expectation| !expectation.trim().eq_ignore_ascii_case("100-continue")) {
+            return Err(HttpRequestError::ExpectationFailed(expect.clone()));
+        }
+        let content_length = request.content_length()?;
+        if content_length > max_body {
+            return Err(HttpRequestError::BodyTooLarge(content_length, max_body));
+        }
+        // HTTP/1.0 clients do not wait for 100 Continue.
+        Ok(content_length > 0 && request.start_line.http_version != HttpVersion::HTTP1_0)
+    }
+
+    /// The response to a request that could not be read.
+    pub(crate) fn error_response(status_code: StatusCode, error: &HttpRequestError) -> HttpResponse {
+        match status_code {
+            StatusCode::Code408 => HttpResponse::json_error(status_code, "request_timeout", None),
+            StatusCode::Code413 => HttpResponse::json_error(status_code, "content_too_large", Some(&error.to_string())),
+            StatusCode::Code414 => HttpResponse::json_error(status_code, "uri_too_long", None),
+            StatusCode::Code415 => {
+                let mut response = HttpResponse::json_error(status_code, "unsupported_media_type", Some(&error.to_string()));
+                response.headers.insert("Accept-Encoding".to_string(), body_decoding::accepted_codings());
+                response
             },
-            headers: headers,
-            body: "{\"error\": \"not_found\"}".to_string(),
+            StatusCode::Code417 => HttpResponse::json_error(status_code, "expectation_failed", Some(&error.to_string())),
+            StatusCode::Code431 => HttpResponse::json_error(status_code, "request_header_fields_too_large", None),
+            _ => HttpResponse::json_error(status_code, "bad_request", Some(&error.to_string())),
+        }
+    }
+
+    /// Runs a request through the middlewares and its handler, tagging it
+    /// with the peer address, state and request id and recording metrics.
+    pub(crate) fn dispatch(context: &Arc<ServerContext>, endpoint: &Endpoint, http_request: HttpRequest, peer: Option<SocketAddr>) -> HttpResponse {
+        Self::dispatch_with(context, endpoint, http_request, peer, Self::respond)
+    }
+
+    /// Like `dispatch`, with `respond` running the middlewares and handler.
+    pub(crate) fn dispatch_with(
+        context: &Arc<ServerContext>,
+        endpoint: &Endpoint,
+        mut http_request: HttpRequest,
+        peer: Option<SocketAddr>,
+        respond: fn(&Arc<ServerContext>, &Endpoint, HttpRequest) -> HttpResponse) -> HttpResponse {
+
+        if let Some(addr) = peer {
+            http_request.extensions.insert(PeerAddr(addr));
+        }
+        http_request.state = context.state.clone();
+
+        let request_id = RequestId::for_request(&http_request);
+        http_request.extensions.insert(request_id.clone());
+        let request_type = http_request.start_line.request_type.clone();
+        let span = info_span!("request", method = %request_type, path = %http_request.start_line.request_target.uri, %request_id);
+        let _guard = span.enter();
+
+        let start = Instant::now();
+        let mut http_response = respond(context, endpoint, http_request);
+        http_response.headers.insert(RequestId::HEADER.to_string(), request_id.to_string());
+        let route = endpoint.route.as_ref().map_or(UNMATCHED_ROUTE, |MatchedRoute(route)| route);
+        let status = http_response.status_line.status_code.as_u16();
+        let elapsed = start.elapsed();
+        context.metrics.observe_request(&request_type, route, status, elapsed);
+        debug!(status, ?elapsed, "request finished");
+        http_response
+    }
+
+    /// Finds the endpoint for a request and records the matched route in it.
+    pub(crate) fn route(context: &ServerContext, request: &mut HttpRequest) -> Endpoint {
+        let request_type = &request.start_line.request_type;
+        let path = &request.start_line.request_target.uri;
+        match context.routes.lookup(request_type, path) {
+            Some((endpoint, path_params)) => {
+                request.path_params = path_params;
+                if let Some(route) = &endpoint.route {
+                    request.extensions.insert(route.clone());
+                }
+                endpoint
+            },
+            None => Endpoint::new(Arc::new(Self::get_404)),
+        }
+    }
+
+    /// Runs the middlewares and handler, giving up after the handler deadline.
+    /// With a deadline they run on the bounded handler pool, where a request
+    /// still queued when its deadline passes is dropped without running.
+    fn respond(context: &Arc<ServerContext>, endpoint: &Endpoint, request: HttpRequest) -> HttpResponse {
+        let timeout = match context.timeouts.handler {
+            Some(timeout) => timeout,
+            None => return Self::run_handler(context, endpoint, request),
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let deadline = Instant::now() + timeout;
+        let (job_context, endpoint, span) = (context.clone(), endpoint.clone(), Span::current());
+        context.spawn_handler(move || span.in_scope(|| {
+            let response = (Instant::now() < deadline).then(|| Self::run_handler(&job_context, &endpoint, request));
+            let _ = sender.send(response);
+        }));
+        match receiver.recv_timeout(timeout) {
+            Ok(Some(response)) => response,
+            Ok(None) | Err(RecvTimeoutError::Timeout) => Self::handler_timed_out(timeout),
+            Err(RecvTimeoutError::Disconnected) => HttpResponse::json_error(StatusCode::Code500, "internal_server_error", None),
+        }
+    }
+
+    /// Runs the middlewares and handler on the calling thread, answering
+    /// with `500` should they panic instead of taking the worker down.
+    pub(crate) fn run_handler(context: &Arc<ServerContext>, endpoint: &Endpoint, request: HttpRequest) -> HttpResponse {
+        let next = Next::new(&context.middlewares, &endpoint.middlewares, endpoint.handler.as_ref());
+        panic::catch_unwind(AssertUnwindSafe(|| next.run(request))).unwrap_or_else(|_| {
+            warn!("handler panicked");
+            HttpResponse::json_error(StatusCode::Code500, "internal_server_error", None)
+        })
+    }
+
+    /// The response to a request whose handler missed its deadline.
+    pub(crate) fn handler_timed_out(timeout: Duration) -> HttpResponse {
+        warn!(?timeout, "handler exceeded its deadline");
+        HttpResponse::json_error(StatusCode::Code503, "service_unavailable", Some("the handler timed out"))
+    }
+
+    fn write_response(context: &ServerContext, stream: &mut impl Write, response: HttpResponse) -> std::io::Result<()> {
+        let bytes_sent = response_writer::write_response(stream, &response)?;
+        context.metrics.add_bytes_sent(bytes_sent);
+        Ok(())
+    }
+
+    fn get_404(_request: HttpRequest) -> HttpResponse {
+        HttpResponse::json_error(StatusCode::Code404, "not_found", None)
+    }
+}
+
+impl Routes for FastWebServer {
+    fn bind_arc(&mut self, request_type: RequestType, route: &str, handler: Arc<dyn Handler>) {
+        self.insert_route(request_type, route, Endpoint::new(handler));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::timeouts::tests::SlowStream;
+
+    fn context(timeouts: Timeouts) -> Arc<ServerContext> {
+        let mut context = ServerContext { timeouts, ..ServerContext::default() };
+        let slow = |_request: HttpRequest| {
+            thread::sleep(Duration::from_millis(200));
+            "slow"
+        };
+        context.routes.insert(RequestType::GET, "/slow", Endpoint::new(Arc::new(slow))).unwrap();
+        context.routes.insert(RequestType::POST, "/echo", Endpoint::new(Arc::new(|request: HttpRequest| request.body))).unwrap();
+        Arc::new(context)
+    }
+
+    fn serve(context: &Arc<ServerContext>, stream: &mut SlowStream) -> (std::io::Result<()>, String) {
+        let result = FastWebServer::handle_client(context, stream, None, false).map(|_| ());
+        (result, String::from_utf8_lossy(&stream.output).into_owned())
+    }
+
+    #[test]
+    fn test_header_read_timeout() {
+        let context = context(Timeouts::new().header_read(Duration::from_millis(50)));
+        let mut stream = SlowStream::new(b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi", 1, Duration::from_millis(5));
+        let (result, output) = serve(&context, &mut stream);
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert_eq!(context.metrics.parse_errors("timeout"), 1);
+    }
+
+    #[test]
+    fn test_body_read_timeout() {
+        let head = "POST /echo HTTP/1.1\r\nContent-Length: 100\r\n\r\n";
+        let request = format!("{}{}", head, "a".repeat(100));
+        let context = context(Timeouts::new().body_read(Duration::from_millis(50)));
+        let mut stream = SlowStream::new(request.as_bytes(), head.len(), Duration::from_millis(20));
+        let (result, output) = serve(&context, &mut stream);
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn test_within_deadlines() {
+        let context = context(Timeouts::new().header_read(Duration::from_secs(1)));
+        let mut stream = SlowStream::new(b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi", 8, Duration::from_millis(1));
+        let (result, output) = serve(&context, &mut stream);
+        assert!(result.is_ok());
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\nhi"));
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let mut context = context(Timeouts::default());
+        let context_mut = Arc::get_mut(&mut context).unwrap();
+        context_mut.limits = RequestLimits::new().max_start_line(32).max_body(4);
+        context_mut.routes.insert(RequestType::POST, "/upload", Endpoint::new(Arc::new(|request: HttpRequest| request.body))).unwrap();
+        context_mut.routes.endpoint_mut(&RequestType::POST, "/upload").unwrap().max_body = Some(16);
+
+        let serve_raw = |raw: &str| serve(&context, &mut SlowStream::new(raw.as_bytes(), 64, Duration::ZERO)).1;
+        let output = serve_raw(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40)));
+        assert!(output.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+        let output = serve_raw("POST /echo HTTP/1.1\r\nContent-Length: 8\r\n\r\n12345678");
+        assert!(output.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        let output = serve_raw("POST /upload HTTP/1.1\r\nContent-Length: 8\r\n\r\n12345678");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("12345678"));
+        assert_eq!(context.metrics.parse_errors("uri_too_long"), 1);
+        assert_eq!(context.metrics.parse_errors("body_too_large"), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_encoded_body() {
+        use std::io::Write;
+        let mut context = context(Timeouts::default());
+        Arc::get_mut(&mut context).unwrap().limits = RequestLimits::new().max_body(1024);
+        let gzip = |data: &[u8]| {
+            let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let serve_encoded = |coding: &str, body: &[u8]| {
+            let mut raw = format!("POST /echo HTTP/1.1\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n", coding, body.len()).into_bytes();
+            raw.extend_from_slice(body);
+            serve(&context, &mut SlowStream::new(&raw, 64, Duration::ZERO)).1
+        };
+
+        let output = serve_encoded("gzip", &gzip(b"decoded"));
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\ndecoded"));
+        let output = serve_encoded("compress", b"data");
+        assert!(output.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+        assert!(output.contains("\r\nAccept-Encoding: "));
+        let output = serve_encoded("gzip", &gzip(&[b'a'; 4096]));
+        assert!(output.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        assert_eq!(context.metrics.parse_errors("decoded_body_too_large"), 1);
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let mut context = context(Timeouts::default());
+        Arc::get_mut(&mut context).unwrap().limits = RequestLimits::new().max_body(4);
+        let serve_raw = |raw: &str| serve(&context, &mut SlowStream::new(raw.as_bytes(), 64, Duration::ZERO)).1;
+
+        let output = serve_raw("POST /echo HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nhi");
+        assert!(output.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\nhi"));
+        // Rejected from the headers alone, without asking for the body.
+        let output = serve_raw("POST /echo HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 8\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        let output = serve_raw("POST /echo HTTP/1.1\r\nExpect: 200-ok\r\nContent-Length: 2\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+        assert_eq!(context.metrics.parse_errors("expectation_failed"), 1);
+        // HTTP/1.0 clients and bodiless requests get no interim response.
+        let output = serve_raw("POST /echo HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nhi");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        let output = serve_raw("POST /echo HTTP/1.1\r\nExpect: 100-continue\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_handler_panic() {
+        let mut context = context(Timeouts::default());
+        let routes = &mut Arc::get_mut(&mut context).unwrap().routes;
+        routes.insert(RequestType::GET, "/panic", Endpoint::new(Arc::new(|_request: HttpRequest| -> &'static str { panic!("boom") }))).unwrap();
+        let mut stream = SlowStream::new(b"GET /panic HTTP/1.1\r\n\r\n", 64, Duration::ZERO);
+        let (result, output) = serve(&context, &mut stream);
+        assert!(result.is_ok());
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    }
+
+    #[test]
+    fn test_head_drops_tail() {
+        let dir = TempDir::new("head-tail");
+        let path = dir.file("file", "hello");
+        let mut context = context(Timeouts::default());
+        let file = move |_request: HttpRequest| HttpResponse::from_file(FileBody::open(&path).unwrap());
+        let routes = &mut Arc::get_mut(&mut context).unwrap().routes;
+        routes.insert(RequestType::GET, "/file", Endpoint::new(Arc::new(file.clone()))).unwrap();
+        routes.insert(RequestType::HEAD, "/file", Endpoint::new(Arc::new(file))).unwrap();
+
+        let mut stream = SlowStream::new(b"GET /file HTTP/1.1\r\n\r\n", 64, Duration::ZERO);
+        assert!(matches!(FastWebServer::handle_client(&context, &mut stream, None, false), Ok(Some(Tail::File(_)))));
+        let mut stream = SlowStream::new(b"HEAD /file HTTP/1.1\r\n\r\n", 64, Duration::ZERO);
+        assert!(matches!(FastWebServer::handle_client(&context, &mut stream, None, false), Ok(None)));
+        let output = String::from_utf8_lossy(&stream.output);
+        assert!(output.contains("\r\nContent-Length: 5\r\n") && output.ends_with("\r\n\r\n"));
+    }
+
+    /// A server whose `/wait` handler blocks until the returned sender sends.
+    fn start_server(connection_limits: ConnectionLimits) -> (SocketAddr, Arc<Metrics>, mpsc::Sender<()>) {
+        let mut server = FastWebServer::new("127.0.0.1:0", 1);
+        server.connection_limits(connection_limits);
+        let (sender, receiver) = mpsc::channel::<()>();
+        let receiver = Mutex::new(receiver);
+        server.bind(RequestType::GET, "/wait", move |_request: HttpRequest| {
+            let _ = receiver.lock().unwrap().recv();
+            "done"
+        });
+        let (addr, metrics) = (server.listener.local_addr().unwrap(), server.metrics());
+        thread::spawn(move || server.run());
+        (addr, metrics, sender)
+    }
+
+    fn send_request(addr: SocketAddr) -> TcpStream {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"GET /wait HTTP/1.1\r\n\r\n").unwrap();
+        client
+    }
+
+    fn read_response(client: &mut TcpStream) -> String {
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_overload_reject() {
+        let limits = ConnectionLimits::new().max_connections(1).overload(Overload::Reject { retry_after: Duration::from_secs(2) });
+        let (addr, metrics, release) = start_server(limits);
+        let mut busy = send_request(addr);
+        wait_until(|| metrics.active_connections() == 1);
+
+        let rejected = read_response(&mut send_request(addr));
+        assert!(rejected.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(rejected.contains("\r\nRetry-After: 2\r\n"));
+        assert_eq!(metrics.rejected_connections(), 1);
+
+        release.send(()).unwrap();
+        assert!(read_response(&mut busy).ends_with("\r\n\r\ndone"));
+    }
+
+    #[test]
+    fn test_overload_backlog() {
+        let (addr, metrics, release) = start_server(ConnectionLimits::new().max_connections(1).overload(Overload::Backlog));
+        let mut busy = send_request(addr);
+        wait_until(|| metrics.active_connections() == 1);
+
+        // Left in the listen backlog until the first connection finishes.
+        let mut waiting = send_request(addr);
+        waiting.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let error = waiting.read(&mut [0; 1]).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut));
+        assert_eq!(metrics.queued_connections() + metrics.active_connections(), 1);
+
+        release.send(()).unwrap();
+        assert!(read_response(&mut busy).ends_with("\r\n\r\ndone"));
+        release.send(()).unwrap();
+        waiting.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert!(read_response(&mut waiting).ends_with("\r\n\r\ndone"));
+        assert_eq!(metrics.rejected_connections(), 0);
+    }
+
+    #[test]
+    fn test_streams_count_as_connections() {
+        let mut server = FastWebServer::new("127.0.0.1:0", 1);
+        server.connection_limits(ConnectionLimits::new().max_connections(1).overload(Overload::Reject { retry_after: Duration::from_secs(1) }));
+        let (events, receiver) = mpsc::channel();
+        let receiver = Mutex::new(Some(receiver));
+        server.bind(RequestType::GET, "/events", move |_request: HttpRequest| {
+            crate::Sse::from_channel(receiver.lock().unwrap().take().unwrap())
+        });
+        server.bind(RequestType::GET, "/wait", |_request: HttpRequest| "done");
+        let (addr, metrics) = (server.listener.local_addr().unwrap(), server.metrics());
+        thread::spawn(move || server.run());
+
+        let mut streaming = TcpStream::connect(addr).unwrap();
+        streaming.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        streaming.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+        events.send(crate::Event::data("hi")).unwrap();
+        let mut received = vec![];
+        while !received.ends_with(b"data: hi\n\n") {
+            let mut buf = [0; 256];
+            let len = streaming.read(&mut buf).unwrap();
+            received.extend_from_slice(&buf[..len]);
+        }
+        // The streamer writes the body, while the connection still counts.
+        wait_until(|| metrics.active_connections() == 1);
+        assert!(read_response(&mut send_request(addr)).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        drop(events);
+        read_response(&mut streaming);
+        wait_until(|| metrics.active_connections() == 0);
+        assert!(read_response(&mut send_request(addr)).ends_with("\r\n\r\ndone"));
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_redirect_listener() {
+        let dir = crate::test_util::TempDir::new("redirect");
+        let certificate = crate::tls::tests::self_signed(&dir, "localhost");
+        let redirect = |connection_limits: ConnectionLimits| {
+            let mut server = FastWebServer::new("127.0.0.1:0", 1);
+            server.tls(TlsConfig::new(&certificate.cert, &certificate.key)).unwrap();
+            server.redirect_http("127.0.0.1:0").unwrap().connection_limits(connection_limits);
+            let https_port = server.listener.local_addr().unwrap().port();
+            let (addr, metrics) = (server.redirect_listener.as_ref().unwrap().local_addr().unwrap(), server.metrics());
+            thread::spawn(move || server.run());
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            client.write_all(b"GET /a?b=c HTTP/1.1\r\nHost: localhost:80\r\n\r\n").unwrap();
+            (read_response(&mut client), https_port, metrics)
+        };
+
+        let (response, https_port, _) = redirect(ConnectionLimits::new());
+        assert!(response.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"));
+        assert!(response.contains(&format!("\r\nLocation: https://localhost:{}/a?b=c\r\n", https_port)));
+        // Redirects are subject to the connection limits of the server.
+        let (response, _, metrics) = redirect(ConnectionLimits::new().max_queued(0));
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(metrics.rejected_connections(), 1);
+    }
+
+    #[test]
+    fn test_handler_timeout() {
+        let context = context(Timeouts::new().handler(Duration::from_millis(20)));
+        let mut stream = SlowStream::new(b"GET /slow HTTP/1.1\r\n\r\n", 64, Duration::ZERO);
+        let (result, output) = serve(&context, &mut stream);
+        assert!(result.is_ok());
+        assert!(output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+
+    #[test]
+    fn test_handler_pool() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut context = context(Timeouts::new().handler(Duration::from_millis(20)));
+        let context_mut = Arc::get_mut(&mut context).unwrap();
+        context_mut.handler_threads = 1;
+        let counted = runs.clone();
+        let stuck = move |_request: HttpRequest| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(200));
+            "late"
+        };
+        context_mut.routes.insert(RequestType::GET, "/stuck", Endpoint::new(Arc::new(stuck))).unwrap();
+
+        // The second request waits out its deadline behind the first and never runs.
+        for _ in 0..2 {
+            let (_, output) = serve(&context, &mut SlowStream::new(b"GET /stuck HTTP/1.1\r\n\r\n", 64, Duration::ZERO));
+            assert!(output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
         }
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
     }
 }
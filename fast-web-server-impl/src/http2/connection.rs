@@ -0,0 +1,1042 @@
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use fast_web_server_types::{Chunk, HttpRequest, HttpRequestError, HttpResponse, StatusCode, StreamingBody};
+use tracing::{debug, Span};
+
+use crate::body_decoding;
+use crate::fast_web_server::{FastWebServer, ServerContext};
+use crate::http2::frame::*;
+use crate::http2::hpack::{self, Decoder, HpackError};
+use crate::http2::{build_request, is_connection_header};
+use crate::router::Endpoint;
+use crate::timeouts::SetTimeout;
+
+
+/// How often streamed response bodies are polled for more data.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An HTTP/1.1 request that asked to continue the connection as h2c. It
+/// becomes stream 1, with the peer settings from its `HTTP2-Settings`.
+pub(crate) struct Upgrade {
+    pub(crate) request: HttpRequest,
+    pub(crate) endpoint: Endpoint,
+    pub(crate) settings: Vec<u8>,
+}
+
+struct Stream {
+    /// The request while its body is being received, `None` once it has
+    /// been dispatched or answered early.
+    request: Option<(HttpRequest, Endpoint)>,
+    body: Vec<u8>,
+    max_body: usize,
+    recv_window: i64,
+    send_window: i64,
+    /// Whether the client has ended its side of the stream.
+    remote_closed: bool,
+    /// Response body not yet sent for lack of flow-control window.
+    pending: Option<(Vec<u8>, usize)>,
+    /// Polled for more response data once `pending` is sent.
+    body_stream: Option<StreamingBody>,
+}
+
+/// A connection the HTTP/2 loop can watch for input from a second thread.
+pub(crate) trait Socket {
+    /// A handle to the underlying TCP socket.
+    fn tcp_stream(&self) -> io::Result<TcpStream>;
+
+    /// Whether input was already read from the socket but not yet returned,
+    /// as with TLS when one read brings in several records.
+    fn has_buffered(&mut self) -> bool {
+        false
+    }
+}
+
+impl Socket for TcpStream {
+    fn tcp_stream(&self) -> io::Result<TcpStream> {
+        self.try_clone()
+    }
+}
+
+impl<S: Socket + ?Sized> Socket for &mut S {
+    fn tcp_stream(&self) -> io::Result<TcpStream> {
+        (**self).tcp_stream()
+    }
+
+    fn has_buffered(&mut self) -> bool {
+        (**self).has_buffered()
+    }
+}
+
+/// What wakes the connection loop.
+enum Wake {
+    /// The socket has input, has been closed or failed.
+    Readable,
+    /// The handler of a stream returned.
+    Response(u32, HttpResponse),
+}
+
+/// Peeks at the socket on its own thread and sends `Wake::Readable` when
+/// input arrives, so the loop can wait for frames and handler responses on
+/// one channel. It peeks again once the loop has read, and stops when
+/// dropped.
+struct Watcher {
+    socket: TcpStream,
+    resume: Sender<()>,
+}
+
+impl Watcher {
+    fn spawn(socket: TcpStream, wake: Sender<Wake>) -> io::Result<Self> {
+        let (resume, resumed) = mpsc::channel();
+        let watched = socket.try_clone()?;
+        thread::Builder::new()
+            .name("fast-web-server-h2-watcher".to_string())
+            .spawn(move || loop {
+                // Peeks time out with the connection's read timeout.
+                if let Err(e) = watched.peek(&mut [0]) {
+                    if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted) {
+                        continue;
+                    }
+                }
+                if wake.send(Wake::Readable).is_err() || resumed.recv().is_err() {
+                    return;
+                }
+            })?;
+        Ok(Self { socket, resume })
+    }
+
+    /// Lets the watcher peek again after the loop read the input.
+    fn resume(&self) {
+        let _ = self.resume.send(());
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // Ends a pending peek. Writing stays possible, e.g. for TLS `close_notify`.
+        let _ = self.socket.shutdown(Shutdown::Read);
+    }
+}
+
+/// Serves an HTTP/2 connection until the client closes it, goes away or
+/// stays idle. `buffered` holds bytes already read from the connection.
+pub(crate) fn serve<S: Read + Write + SetTimeout + Socket>(
+    context: &Arc<ServerContext>,
+    stream: &mut S,
+    peer: Option<SocketAddr>,
+    buffered: Vec<u8>,
+    upgrade: Option<Upgrade>) -> io::Result<()> {
+
+    let mut connection = Connection::new(context, peer, buffered);
+    stream.set_write_timeout(Some(context.timeouts.write))?;
+    if let Some(upgrade) = upgrade {
+        stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n")?;
+        if connection.peer_settings.apply(&upgrade.settings).is_err() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid HTTP2-Settings"));
+        }
+        connection.upgrade(upgrade.request, upgrade.endpoint);
+    }
+    connection.start(stream)?;
+    connection.run(stream)
+}
+
+struct Connection<'a> {
+    context: &'a Arc<ServerContext>,
+    peer: Option<SocketAddr>,
+    reader: FrameReader,
+    decoder: Decoder,
+    peer_settings: PeerSettings,
+    streams: BTreeMap<u32, Stream>,
+    last_stream_id: u32,
+    send_window: i64,
+    recv_window: i64,
+    /// A header block continued in `CONTINUATION` frames: stream, flags and fragments so far.
+    header_block: Option<(u32, u8, Vec<u8>)>,
+    sender: Sender<Wake>,
+    receiver: Receiver<Wake>,
+    /// Streams whose handler is running or queued, with its deadline. They
+    /// count against the concurrency limit even once the client reset them.
+    in_flight: BTreeMap<u32, Option<Instant>>,
+    outgoing: Vec<Frame>,
+    going_away: bool,
+    last_frame: Instant,
+}
+
+impl<'a> Connection<'a> {
+    fn new(context: &'a Arc<ServerContext>, peer: Option<SocketAddr>, buffered: Vec<u8>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let mut reader = FrameReader::new(buffered);
+        reader.max_frame_size = context.http2.max_frame_size;
+        Self {
+            context,
+            peer,
+            reader,
+            decoder: Decoder::default(),
+            peer_settings: PeerSettings::default(),
+            streams: BTreeMap::new(),
+            last_stream_id: 0,
+            send_window: DEFAULT_WINDOW_SIZE as i64,
+            recv_window: DEFAULT_WINDOW_SIZE as i64,
+            header_block: None,
+            sender,
+            receiver,
+            in_flight: BTreeMap::new(),
+            outgoing: vec![],
+            going_away: false,
+            last_frame: Instant::now(),
+        }
+    }
+
+    /// Sends the server preface and reads the client's.
+    fn start<S: Read + Write + SetTimeout>(&mut self, stream: &mut S) -> io::Result<()> {
+        let settings = &self.context.http2;
+        let payload = encode_settings(&[
+            (SETTINGS_HEADER_TABLE_SIZE, hpack::DEFAULT_TABLE_SIZE as u32),
+            (SETTINGS_ENABLE_PUSH, 0),
+            (SETTINGS_MAX_CONCURRENT_STREAMS, settings.max_concurrent_streams),
+            (SETTINGS_INITIAL_WINDOW_SIZE, settings.initial_window_size),
+            (SETTINGS_MAX_FRAME_SIZE, settings.max_frame_size),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, self.context.limits.max_header_bytes as u32),
+        ]);
+        self.outgoing.insert(0, Frame::new(SETTINGS, 0, 0, payload));
+        self.flush(stream)?;
+        stream.set_read_timeout(Some(self.context.timeouts.header_read))?;
+        self.reader.read_preface(stream)
+    }
+
+    /// Waits for input and handler responses until the connection ends.
+    /// Reads block for at most the header read timeout, should a TLS record
+    /// arrive in pieces.
+    fn run<S: Read + Write + SetTimeout + Socket>(&mut self, stream: &mut S) -> io::Result<()> {
+        let watcher = Watcher::spawn(stream.tcp_stream()?, self.sender.clone())?;
+        let mut readable = false;
+        loop {
+            if !self.receive(stream, readable)? {
+                return Ok(());
+            }
+            if readable {
+                watcher.resume();
+                readable = false;
+            }
+            self.flush(stream)?;
+            if self.going_away && self.streams.is_empty() && self.in_flight.is_empty() {
+                return Ok(());
+            }
+            let timeout = if self.streams.values().any(|stream| stream.body_stream.is_some()) {
+                Some(STREAM_POLL_INTERVAL)
+            } else if self.in_flight.is_empty() {
+                match self.context.http2.idle_timeout.checked_sub(self.last_frame.elapsed()) {
+                    Some(remaining) if !remaining.is_zero() => Some(remaining),
+                    _ => return self.close(stream, ErrorCode::NoError),
+                }
+            } else {
+                None
+            };
+            let deadline = self.in_flight.values().flatten().min().map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let wake = match timeout.into_iter().chain(deadline).min() {
+                Some(timeout) => self.receiver.recv_timeout(timeout).ok(),
+                None => self.receiver.recv().ok(),
+            };
+            match wake {
+                Some(Wake::Readable) => readable = true,
+                Some(Wake::Response(stream_id, response)) if self.in_flight.remove(&stream_id).is_some() => {
+                    self.respond(stream_id, response);
+                },
+                // A response arriving after its deadline was already answered.
+                Some(Wake::Response(..)) | None => {},
+            }
+            self.expire_handlers();
+        }
+    }
+
+    /// Handles the frames buffered whole, reading only when the socket is
+    /// `readable` or the stream has input buffered, so that reads do not
+    /// block. Returns whether the connection stays open.
+    fn receive<S: Read + Write + Socket>(&mut self, stream: &mut S, mut readable: bool) -> io::Result<bool> {
+        loop {
+            let bytes_read = self.reader.bytes_read;
+            let polled = match readable || stream.has_buffered() {
+                true => self.reader.poll(stream),
+                false => self.reader.take(),
+            };
+            readable = false;
+            self.context.metrics.add_bytes_received(self.reader.bytes_read - bytes_read);
+            match polled {
+                Ok(Some(frame)) => {
+                    self.last_frame = Instant::now();
+                    if let Err(code) = self.handle(frame) {
+                        self.close(stream, code)?;
+                        return Ok(false);
+                    }
+                },
+                Ok(None) => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    self.close(stream, ErrorCode::FrameSize)?;
+                    return Ok(false);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Answers the streams whose handler missed its deadline with `503`.
+    fn expire_handlers(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u32> = self.in_flight.iter()
+            .filter(|(_, deadline)| deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(&stream_id, _)| stream_id)
+            .collect();
+        for stream_id in expired {
+            self.in_flight.remove(&stream_id);
+            let timeout = self.context.timeouts.handler.unwrap_or_default();
+            self.respond(stream_id, FastWebServer::handler_timed_out(timeout));
+        }
+    }
+
+    /// Sends `GOAWAY` and ends the connection.
+    fn close<S: Write>(&mut self, stream: &mut S, code: ErrorCode) -> io::Result<()> {
+        if code != ErrorCode::NoError {
+            debug!(?code, "closing HTTP/2 connection");
+        }
+        self.outgoing.push(Frame::goaway(self.last_stream_id, code));
+        self.flush(stream)
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), ErrorCode> {
+        if let Some((stream_id, ..)) = self.header_block {
+            if frame.kind != CONTINUATION || frame.stream_id != stream_id {
+                return Err(ErrorCode::Protocol);
+            }
+        }
+        match frame.kind {
+            DATA => self.on_data(frame),
+            HEADERS => self.on_headers(frame),
+            CONTINUATION => self.on_continuation(frame),
+            PRIORITY if frame.stream_id == 0 => Err(ErrorCode::Protocol),
+            RST_STREAM => {
+                if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+                    return Err(ErrorCode::Protocol);
+                }
+                if frame.payload.len() != 4 {
+                    return Err(ErrorCode::FrameSize);
+                }
+                self.streams.remove(&frame.stream_id);
+                Ok(())
+            },
+            SETTINGS => self.on_settings(frame),
+            PUSH_PROMISE => Err(ErrorCode::Protocol),
+            PING => {
+                if frame.stream_id != 0 {
+                    return Err(ErrorCode::Protocol);
+                }
+                if frame.payload.len() != 8 {
+                    return Err(ErrorCode::FrameSize);
+                }
+                if !frame.has(ACK) {
+                    self.outgoing.push(Frame::new(PING, ACK, 0, frame.payload));
+                }
+                Ok(())
+            },
+            GOAWAY if frame.stream_id != 0 => Err(ErrorCode::Protocol),
+            GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            },
+            WINDOW_UPDATE => self.on_window_update(frame),
+            // Unknown frame types and PRIORITY are ignored.
+            _ => Ok(()),
+        }
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), ErrorCode> {
+        if frame.stream_id != 0 {
+            return Err(ErrorCode::Protocol);
+        }
+        if frame.has(ACK) {
+            return if frame.payload.is_empty() { Ok(()) } else { Err(ErrorCode::FrameSize) };
+        }
+        let old_window = self.peer_settings.initial_window_size as i64;
+        self.peer_settings.apply(&frame.payload)?;
+        let delta = self.peer_settings.initial_window_size as i64 - old_window;
+        for stream in self.streams.values_mut() {
+            stream.send_window += delta;
+            if stream.send_window > MAX_WINDOW_SIZE {
+                return Err(ErrorCode::FlowControl);
+            }
+        }
+        self.outgoing.push(Frame::new(SETTINGS, ACK, 0, vec![]));
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), ErrorCode> {
+        let increment = match (frame.payload.len(), frame.u32_at(0)) {
+            (4, Some(increment)) => increment as i64,
+            _ => return Err(ErrorCode::FrameSize),
+        };
+        if frame.stream_id == 0 {
+            self.send_window += increment;
+            return match increment == 0 || self.send_window > MAX_WINDOW_SIZE {
+                true => Err(ErrorCode::FlowControl),
+                false => Ok(()),
+            };
+        }
+        if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+            stream.send_window += increment;
+            if increment == 0 {
+                self.reset(frame.stream_id, ErrorCode::Protocol);
+            } else if stream.send_window > MAX_WINDOW_SIZE {
+                self.reset(frame.stream_id, ErrorCode::FlowControl);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), ErrorCode> {
+        if frame.stream_id == 0 {
+            return Err(ErrorCode::Protocol);
+        }
+        let fragment = frame.data().ok_or(ErrorCode::Protocol)?.to_vec();
+        if frame.has(END_HEADERS) {
+            self.on_header_block(frame.stream_id, frame.flags, &fragment)
+        } else {
+            self.header_block = Some((frame.stream_id, frame.flags, fragment));
+            Ok(())
+        }
+    }
+
+    fn on_continuation(&mut self, frame: Frame) -> Result<(), ErrorCode> {
+        let (stream_id, flags, mut block) = self.header_block.take().ok_or(ErrorCode::Protocol)?;
+        block.extend_from_slice(&frame.payload);
+        // A compressed block several times the header limit is not worth decoding.
+        if block.len() > 4 * self.context.limits.max_header_bytes {
+            return Err(ErrorCode::EnhanceYourCalm);
+        }
+        match frame.has(END_HEADERS) {
+            true => self.on_header_block(stream_id, flags, &block),
+            false => {
+                self.header_block = Some((stream_id, flags, block));
+                Ok(())
+            },
+        }
+    }
+
+    fn on_header_block(&mut self, stream_id: u32, flags: u8, block: &[u8]) -> Result<(), ErrorCode> {
+        // Decode even blocks of refused streams to keep the dynamic table in sync.
+        let fields = self.decoder.decode(block, self.context.limits.max_header_bytes).map_err(|e| match e {
+            HpackError::TooLarge(_) => ErrorCode::EnhanceYourCalm,
+            _ => ErrorCode::Compression,
+        })?;
+        let end_stream = flags & END_STREAM != 0;
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            // Trailers, which must end the stream and are otherwise ignored.
+            if stream.remote_closed {
+                self.reset(stream_id, ErrorCode::StreamClosed);
+            } else if !end_stream {
+                self.reset(stream_id, ErrorCode::Protocol);
+            } else {
+                stream.remote_closed = true;
+                self.complete(stream_id);
+            }
+            return Ok(());
+        }
+        if stream_id.is_multiple_of(2) || stream_id <= self.last_stream_id {
+            return Err(ErrorCode::Protocol);
+        }
+        self.last_stream_id = stream_id;
+        let reset_in_flight = self.in_flight.keys().filter(|stream_id| !self.streams.contains_key(stream_id)).count();
+        if self.going_away || self.streams.len() + reset_in_flight >= self.context.http2.max_concurrent_streams as usize {
+            self.outgoing.push(Frame::rst_stream(stream_id, ErrorCode::RefusedStream));
+            return Ok(());
+        }
+
+        let mut stream = Stream {
+            request: None,
+            body: vec![],
+            max_body: self.context.limits.max_body,
+            recv_window: self.context.http2.initial_window_size as i64,
+            send_window: self.peer_settings.initial_window_size as i64,
+            remote_closed: end_stream,
+            pending: None,
+            body_stream: None,
+        };
+        let parsed = build_request(fields, &self.context.limits).and_then(|mut request| {
+            let endpoint = FastWebServer::route(self.context, &mut request);
+            stream.max_body = endpoint.max_body.unwrap_or(self.context.limits.max_body);
+            let content_length = request.content_length()?;
+            if content_length > stream.max_body {
+                return Err(HttpRequestError::BodyTooLarge(content_length, stream.max_body));
+            }
+            body_decoding::content_codings(&request)?;
+            let expects_continue = FastWebServer::expects_continue(&request, stream.max_body)?;
+            Ok((request, endpoint, expects_continue))
+        });
+        self.streams.insert(stream_id, stream);
+        match parsed {
+            Ok((request, endpoint, expects_continue)) => {
+                self.streams.get_mut(&stream_id).unwrap().request = Some((request, endpoint));
+                if end_stream {
+                    self.complete(stream_id);
+                } else if expects_continue {
+                    // An informational response: a HEADERS frame that does not end the stream.
+                    let block = hpack::encode([(":status", "100")]);
+                    self.outgoing.push(Frame::new(HEADERS, END_HEADERS, stream_id, block));
+                }
+            },
+            Err(e) => self.reject(stream_id, e),
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), ErrorCode> {
+        if frame.stream_id == 0 {
+            return Err(ErrorCode::Protocol);
+        }
+        let len = frame.payload.len() as i64;
+        self.recv_window -= len;
+        if self.recv_window < 0 {
+            return Err(ErrorCode::FlowControl);
+        }
+        if len > 0 {
+            self.outgoing.push(Frame::window_update(0, len as u32));
+            self.recv_window += len;
+        }
+        let data = frame.data().ok_or(ErrorCode::Protocol)?;
+        let stream = match self.streams.get_mut(&frame.stream_id) {
+            Some(stream) if !stream.remote_closed => stream,
+            Some(_) => {
+                self.reset(frame.stream_id, ErrorCode::StreamClosed);
+                return Ok(());
+            },
+            None if frame.stream_id > self.last_stream_id => return Err(ErrorCode::Protocol),
+            None => {
+                self.outgoing.push(Frame::rst_stream(frame.stream_id, ErrorCode::StreamClosed));
+                return Ok(());
+            },
+        };
+        stream.recv_window -= len;
+        if stream.recv_window < 0 {
+            self.reset(frame.stream_id, ErrorCode::FlowControl);
+            return Ok(());
+        }
+        let mut too_large = None;
+        if stream.request.is_some() {
+            if stream.body.len() + data.len() > stream.max_body {
+                stream.request = None;
+                too_large = Some(HttpRequestError::BodyTooLarge(stream.body.len() + data.len(), stream.max_body));
+            } else if len > 0 {
+                stream.body.extend_from_slice(data);
+                stream.recv_window += len;
+                self.outgoing.push(Frame::window_update(frame.stream_id, len as u32));
+            }
+        }
+        stream.remote_closed = frame.has(END_STREAM);
+        if let Some(e) = too_large {
+            self.reject(frame.stream_id, e);
+        } else if frame.has(END_STREAM) {
+            self.complete(frame.stream_id);
+        }
+        Ok(())
+    }
+
+    /// Turns the HTTP/1.1 request of an h2c upgrade into stream 1. Its body
+    /// was already read and decoded along with the request.
+    fn upgrade(&mut self, request: HttpRequest, endpoint: Endpoint) {
+        self.last_stream_id = 1;
+        self.streams.insert(1, Stream {
+            request: None,
+            body: vec![],
+            max_body: 0,
+            recv_window: 0,
+            send_window: self.peer_settings.initial_window_size as i64,
+            remote_closed: true,
+            pending: None,
+            body_stream: None,
+        });
+        self.dispatch(1, request, endpoint);
+    }
+
+    /// Decodes the body of a fully received request and dispatches it.
+    fn complete(&mut self, stream_id: u32) {
+        let stream = self.streams.get_mut(&stream_id).unwrap();
+        let (mut request, endpoint) = match stream.request.take() {
+            Some(request) => request,
+            None => return,
+        };
+        let body = std::mem::take(&mut stream.body);
+        match body_decoding::decode_body(&mut request, body, stream.max_body) {
+            Ok(()) => self.dispatch(stream_id, request, endpoint),
+            Err(e) => self.reject(stream_id, e),
+        }
+    }
+
+    /// Runs the handler of a request on the handler pool. The connection
+    /// loop enforces the handler deadline, so a job still queued when it
+    /// passes is dropped without running.
+    fn dispatch(&mut self, stream_id: u32, request: HttpRequest, endpoint: Endpoint) {
+        let deadline = self.context.timeouts.handler.map(|timeout| Instant::now() + timeout);
+        self.in_flight.insert(stream_id, deadline);
+        let (context, sender, peer, span) = (self.context.clone(), self.sender.clone(), self.peer, Span::current());
+        self.context.spawn_handler(move || span.in_scope(|| {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return;
+            }
+            let response = FastWebServer::dispatch_with(&context, &endpoint, request, peer, FastWebServer::run_handler);
+            let _ = sender.send(Wake::Response(stream_id, response));
+        }));
+    }
+
+    /// Answers a request that could not be read with an error status.
+    fn reject(&mut self, stream_id: u32, error: HttpRequestError) {
+        self.context.metrics.parse_error(error.kind());
+        let status_code = error.status_code().unwrap_or(StatusCode::Code400);
+        self.respond(stream_id, FastWebServer::error_response(status_code, &error));
+    }
+
+    fn reset(&mut self, stream_id: u32, code: ErrorCode) {
+        self.streams.remove(&stream_id);
+        self.outgoing.push(Frame::rst_stream(stream_id, code));
+    }
+
+    /// Queues the response headers and body of a stream.
+    fn respond(&mut self, stream_id: u32, mut response: HttpResponse) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            // The client reset the stream while the handler was running.
+            None => return,
+        };
+        let status = response.status_line.status_code.as_u16().to_string();
+        let names: Vec<(String, &str)> = response.headers.iter()
+            .filter(|(name, _)| !is_connection_header(name))
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.as_str()))
+            .collect();
+        let fields = [(":status", status.as_str())].into_iter()
+            .chain(names.iter().map(|(name, value)| (name.as_str(), *value)));
+        let block = hpack::encode(fields);
+
+        let max_frame_size = self.peer_settings.max_frame_size as usize;
+        // Files are read in chunks, like streamed bodies.
+        let body_stream = response.stream.take().or_else(|| response.file.take().map(StreamingBody::new));
+        let end_stream = if response.body.is_empty() && body_stream.is_none() { END_STREAM } else { 0 };
+        let mut chunks = block.chunks(max_frame_size).peekable();
+        let mut kind = HEADERS;
+        while let Some(chunk) = chunks.next() {
+            let mut flags = if kind == HEADERS { end_stream } else { 0 };
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.outgoing.push(Frame::new(kind, flags, stream_id, chunk.to_vec()));
+            kind = CONTINUATION;
+        }
+        match end_stream {
+            END_STREAM => self.finish(stream_id),
+            _ => {
+                stream.pending = Some((response.body, 0));
+                stream.body_stream = body_stream;
+            },
+        }
+    }
+
+    /// Forgets a stream once its response is sent, resetting it if the
+    /// client is still sending a request body nobody will read.
+    fn finish(&mut self, stream_id: u32) {
+        if let Some(stream) = self.streams.remove(&stream_id) {
+            if !stream.remote_closed {
+                self.outgoing.push(Frame::rst_stream(stream_id, ErrorCode::NoError));
+            }
+        }
+    }
+
+    /// Sends queued frames and as much response data as the flow-control
+    /// windows allow.
+    fn flush<S: Write>(&mut self, stream: &mut S) -> io::Result<()> {
+        let max_frame_size = self.peer_settings.max_frame_size as i64;
+        let mut finished = vec![];
+        for (&stream_id, state) in self.streams.iter_mut() {
+            let (body, sent) = match &mut state.pending {
+                Some(pending) => pending,
+                None => continue,
+            };
+            // A streamed body is polled again while the windows have room.
+            loop {
+                if let (true, Some(body_stream)) = (*sent == body.len(), &mut state.body_stream) {
+                    match body_stream.poll_chunk() {
+                        Chunk::Data(data) => (*body, *sent) = (data, 0),
+                        Chunk::Pending => break,
+                        Chunk::End => {
+                            state.body_stream = None;
+                            self.outgoing.push(Frame::new(DATA, END_STREAM, stream_id, vec![]));
+                            finished.push(stream_id);
+                            break;
+                        },
+                    }
+                }
+                let streaming = state.body_stream.is_some();
+                while *sent < body.len() && state.send_window > 0 && self.send_window > 0 {
+                    let len = ((body.len() - *sent) as i64).min(state.send_window).min(self.send_window).min(max_frame_size);
+                    let end = *sent + len as usize;
+                    let flags = if end == body.len() && !streaming { END_STREAM } else { 0 };
+                    self.outgoing.push(Frame::new(DATA, flags, stream_id, body[*sent..end].to_vec()));
+                    *sent = end;
+                    state.send_window -= len;
+                    self.send_window -= len;
+                }
+                if *sent == body.len() && !streaming {
+                    finished.push(stream_id);
+                }
+                if !streaming || *sent < body.len() || state.send_window <= 0 || self.send_window <= 0 {
+                    break;
+                }
+            }
+        }
+        for stream_id in finished {
+            self.finish(stream_id);
+        }
+        if self.outgoing.is_empty() {
+            return Ok(());
+        }
+        let frames = std::mem::take(&mut self.outgoing);
+        let bytes_sent = write_frames(stream, &frames)?;
+        self.context.metrics.add_bytes_sent(bytes_sent);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use fast_web_server_types::RequestType;
+
+    use super::*;
+
+    /// A minimal HTTP/2 client speaking raw frames.
+    struct Client {
+        socket: TcpStream,
+        reader: FrameReader,
+        decoder: Decoder,
+        /// Streams in the order their responses ended.
+        finished: Vec<u32>,
+    }
+
+    impl Client {
+        fn connect(context: Arc<ServerContext>, settings: &[(u16, u32)]) -> Self {
+            let mut client = Self::connect_raw(context);
+            client.socket.write_all(PREFACE).unwrap();
+            client.send(Frame::new(SETTINGS, 0, 0, encode_settings(settings)));
+            client
+        }
+
+        fn connect_raw(context: Arc<ServerContext>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = FastWebServer::handle_client(&context, &mut stream, None, false);
+            });
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            Self { socket, reader: FrameReader::new(vec![]), decoder: Decoder::default(), finished: vec![] }
+        }
+
+        fn send(&mut self, frame: Frame) {
+            write_frames(&mut self.socket, &[frame]).unwrap();
+        }
+
+        fn request(&mut self, stream_id: u32, method: &str, path: &str, body: Option<&[u8]>) {
+            let block = hpack::encode([(":method", method), (":scheme", "http"), (":path", path), (":authority", "localhost")]);
+            let flags = if body.is_none() { END_HEADERS | END_STREAM } else { END_HEADERS };
+            self.send(Frame::new(HEADERS, flags, stream_id, block));
+            if let Some(body) = body {
+                self.send(Frame::new(DATA, END_STREAM, stream_id, body.to_vec()));
+            }
+        }
+
+        fn frame(&mut self) -> Frame {
+            loop {
+                if let Some(frame) = self.reader.poll(&mut self.socket).unwrap() {
+                    return frame;
+                }
+            }
+        }
+
+        /// Reads frames until the given streams ended, returning status and body of each.
+        fn responses(&mut self, mut stream_ids: Vec<u32>) -> BTreeMap<u32, (String, Vec<u8>)> {
+            let mut responses = BTreeMap::new();
+            while !stream_ids.is_empty() {
+                let frame = self.frame();
+                let response: &mut (String, Vec<u8>) = responses.entry(frame.stream_id).or_default();
+                match frame.kind {
+                    HEADERS => {
+                        let fields = self.decoder.decode(frame.data().unwrap(), usize::MAX).unwrap();
+                        response.0 = String::from_utf8(fields[0].1.clone()).unwrap();
+                    },
+                    DATA => response.1.extend_from_slice(&frame.payload),
+                    _ => continue,
+                }
+                if frame.has(END_STREAM) {
+                    self.finished.push(frame.stream_id);
+                    stream_ids.retain(|&id| id != frame.stream_id);
+                }
+            }
+            responses
+        }
+    }
+
+    fn context(settings: crate::Http2Settings) -> Arc<ServerContext> {
+        let mut context = ServerContext::default();
+        context.http2 = settings;
+        // Enough handler threads to run the test requests side by side.
+        context.handler_threads = 4;
+        let slow = |_request: HttpRequest| {
+            thread::sleep(Duration::from_millis(100));
+            "slow"
+        };
+        context.routes.insert(RequestType::GET, "/slow", Endpoint::new(Arc::new(slow))).unwrap();
+        context.routes.insert(RequestType::GET, "/fast", Endpoint::new(Arc::new(|_request: HttpRequest| "fast"))).unwrap();
+        context.routes.insert(RequestType::GET, "/large", Endpoint::new(Arc::new(|_request: HttpRequest| vec![b'x'; 100]))).unwrap();
+        context.routes.insert(RequestType::POST, "/echo", Endpoint::new(Arc::new(|request: HttpRequest| request.body))).unwrap();
+        let events = |_request: HttpRequest| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            thread::spawn(move || for i in 0..3 {
+                thread::sleep(Duration::from_millis(20));
+                sender.send(crate::Event::data(i.to_string())).unwrap();
+            });
+            crate::Sse::from_channel(receiver)
+        };
+        context.routes.insert(RequestType::GET, "/events", Endpoint::new(Arc::new(events))).unwrap();
+        Arc::new(context)
+    }
+
+    #[test]
+    fn test_multiplexing() {
+        let mut client = Client::connect(context(Default::default()), &[]);
+        client.request(1, "GET", "/slow", None);
+        client.request(3, "GET", "/fast", None);
+        client.request(5, "POST", "/echo", Some(b"hello"));
+
+        let responses = client.responses(vec![1, 3, 5]);
+        assert_eq!(responses[&1], ("200".to_string(), b"slow".to_vec()));
+        assert_eq!(responses[&3], ("200".to_string(), b"fast".to_vec()));
+        assert_eq!(responses[&5], ("200".to_string(), b"hello".to_vec()));
+        assert_eq!(client.finished.last(), Some(&1));
+    }
+
+    #[test]
+    fn test_streaming_body() {
+        let mut client = Client::connect(context(Default::default()), &[]);
+        client.request(1, "GET", "/events", None);
+        client.request(3, "GET", "/fast", None);
+        let responses = client.responses(vec![1, 3]);
+        assert_eq!(responses[&1], ("200".to_string(), b"data: 0\n\ndata: 1\n\ndata: 2\n\n".to_vec()));
+        assert_eq!(client.finished, vec![3, 1]);
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let mut client = Client::connect(context(Default::default()), &[(SETTINGS_INITIAL_WINDOW_SIZE, 10)]);
+        client.request(1, "GET", "/large", None);
+        let mut received = 0;
+        while received < 10 {
+            let frame = client.frame();
+            if frame.kind == DATA {
+                received += frame.payload.len();
+                assert!(!frame.has(END_STREAM));
+            }
+        }
+        assert_eq!(received, 10);
+        client.send(Frame::window_update(1, 90));
+        let responses = client.responses(vec![1]);
+        assert_eq!(responses[&1].1.len(), 90);
+    }
+
+    #[test]
+    fn test_ping_and_errors() {
+        let mut client = Client::connect(context(Default::default()), &[]);
+        client.send(Frame::new(PING, 0, 0, b"12345678".to_vec()));
+        let pong = loop {
+            let frame = client.frame();
+            if frame.kind == PING {
+                break frame;
+            }
+        };
+        assert_eq!((pong.flags, pong.payload), (ACK, b"12345678".to_vec()));
+
+        client.request(1, "GET", "/missing", None);
+        assert_eq!(client.responses(vec![1])[&1].0, "404");
+
+        // Stream ids must increase.
+        client.request(1, "GET", "/fast", None);
+        let goaway = loop {
+            let frame = client.frame();
+            if frame.kind == GOAWAY {
+                break frame;
+            }
+        };
+        assert_eq!(goaway.u32_at(4), Some(ErrorCode::Protocol as u32));
+    }
+
+    #[test]
+    fn test_reset_streams_count_until_handled() {
+        let settings = crate::Http2Settings::default().max_concurrent_streams(2);
+        let mut client = Client::connect(context(settings), &[]);
+        for stream_id in [1, 3] {
+            client.request(stream_id, "GET", "/slow", None);
+            client.send(Frame::rst_stream(stream_id, ErrorCode::NoError));
+        }
+        client.request(5, "GET", "/fast", None);
+        let refused = loop {
+            let frame = client.frame();
+            if frame.kind == RST_STREAM {
+                break frame;
+            }
+        };
+        assert_eq!((refused.stream_id, refused.u32_at(0)), (5, Some(ErrorCode::RefusedStream as u32)));
+
+        // Once the handlers of the reset streams finished, new streams are served.
+        thread::sleep(Duration::from_millis(300));
+        client.request(7, "GET", "/fast", None);
+        assert_eq!(client.responses(vec![7])[&7], ("200".to_string(), b"fast".to_vec()));
+    }
+
+    #[test]
+    fn test_handler_deadline() {
+        let mut context = context(Default::default());
+        Arc::get_mut(&mut context).unwrap().timeouts = crate::Timeouts::new().handler(Duration::from_millis(50));
+        let mut client = Client::connect(context, &[]);
+        client.request(1, "GET", "/slow", None);
+        client.request(3, "GET", "/fast", None);
+        let responses = client.responses(vec![1, 3]);
+        assert_eq!(responses[&1].0, "503");
+        assert_eq!(responses[&3], ("200".to_string(), b"fast".to_vec()));
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls() {
+        use crate::test_util::TempDir;
+        use crate::tls::{tests, TlsAcceptor};
+
+        let dir = TempDir::new("h2-tls");
+        let localhost = tests::self_signed(&dir, "localhost");
+        let acceptor = TlsAcceptor::new(crate::TlsConfig::new(&localhost.cert, &localhost.key).alpn(&["h2"])).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let context = context(Default::default());
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(stream, Duration::from_secs(5)).unwrap();
+            let _ = serve(&context, &mut stream, None, vec![], None);
+        });
+
+        // Every write is a TLS record of its own, likely read all at once.
+        let socket = TcpStream::connect(addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut socket = rustls::StreamOwned::new(tests::client(&localhost.der, "localhost", &["h2"]), socket);
+        socket.write_all(PREFACE).unwrap();
+        write_frames(&mut socket, &[Frame::new(SETTINGS, 0, 0, vec![])]).unwrap();
+        for (stream_id, path) in [(1, "/fast"), (3, "/large")] {
+            let block = hpack::encode([(":method", "GET"), (":scheme", "https"), (":path", path), (":authority", "localhost")]);
+            write_frames(&mut socket, &[Frame::new(HEADERS, END_HEADERS | END_STREAM, stream_id, block)]).unwrap();
+        }
+
+        let (mut reader, mut decoder, mut ended) = (FrameReader::new(vec![]), Decoder::default(), vec![]);
+        while ended.len() < 2 {
+            let frame = match reader.poll(&mut socket).unwrap() {
+                Some(frame) => frame,
+                None => continue,
+            };
+            if frame.kind == HEADERS {
+                let fields = decoder.decode(frame.data().unwrap(), usize::MAX).unwrap();
+                assert_eq!(fields[0].1, b"200");
+            }
+            if matches!(frame.kind, HEADERS | DATA) && frame.has(END_STREAM) {
+                ended.push(frame.stream_id);
+            }
+        }
+        ended.sort();
+        assert_eq!(ended, [1, 3]);
+    }
+
+    #[test]
+    fn test_header_list_limit() {
+        // A 100 byte literal added to the dynamic table, then referenced a
+        // thousand times: a small block that decodes to over 16 KiB.
+        let mut block = hpack::encode([(":method", "GET"), (":scheme", "http"), (":path", "/fast")]);
+        block.extend([0x40, 0x01, b'x', 0x64]);
+        block.extend([b'a'; 100]);
+        block.extend([0xbe; 1000]);
+        let mut client = Client::connect(context(Default::default()), &[]);
+        client.send(Frame::new(HEADERS, END_HEADERS | END_STREAM, 1, block));
+        let goaway = loop {
+            let frame = client.frame();
+            if frame.kind == GOAWAY {
+                break frame;
+            }
+        };
+        assert_eq!(goaway.u32_at(4), Some(ErrorCode::EnhanceYourCalm as u32));
+    }
+
+    #[test]
+    fn test_body_limit() {
+        let mut client = Client::connect(context(Default::default()), &[]);
+        let block = hpack::encode([(":method", "POST"), (":scheme", "http"), (":path", "/echo"), ("content-length", "2000000")]);
+        client.send(Frame::new(HEADERS, END_HEADERS, 1, block));
+        assert_eq!(client.responses(vec![1])[&1].0, "413");
+        let reset = loop {
+            let frame = client.frame();
+            if frame.kind == RST_STREAM {
+                break frame;
+            }
+        };
+        assert_eq!((reset.stream_id, reset.u32_at(0)), (1, Some(ErrorCode::NoError as u32)));
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let mut client = Client::connect(context(Default::default()), &[]);
+        let headers = |expect: &str, content_length: &str| hpack::encode([
+            (":method", "POST"), (":scheme", "http"), (":path", "/echo"), ("expect", expect), ("content-length", content_length),
+        ]);
+        client.send(Frame::new(HEADERS, END_HEADERS, 1, headers("100-continue", "2")));
+        let interim = loop {
+            let frame = client.frame();
+            if frame.stream_id == 1 {
+                break frame;
+            }
+        };
+        assert_eq!((interim.kind, interim.stream_id, interim.has(END_STREAM)), (HEADERS, 1, false));
+        assert_eq!(client.decoder.decode(interim.data().unwrap(), usize::MAX).unwrap()[0].1, b"100");
+        client.send(Frame::new(DATA, END_STREAM, 1, b"hi".to_vec()));
+        assert_eq!(client.responses(vec![1])[&1], ("200".to_string(), b"hi".to_vec()));
+
+        client.send(Frame::new(HEADERS, END_HEADERS, 3, headers("something-else", "2")));
+        assert_eq!(client.responses(vec![3])[&3].0, "417");
+    }
+
+    #[test]
+    fn test_h2c_upgrade() {
+        let mut client = Client::connect_raw(context(Default::default()));
+        client.socket.write_all(b"GET /fast HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\n\r\n").unwrap();
+        let mut head = [0u8; 71];
+        client.socket.read_exact(&mut head).unwrap();
+        assert_eq!(&head[..], b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n");
+        client.socket.write_all(PREFACE).unwrap();
+        client.send(Frame::new(SETTINGS, 0, 0, vec![]));
+        assert_eq!(client.responses(vec![1])[&1], ("200".to_string(), b"fast".to_vec()));
+
+        client.request(3, "GET", "/slow", None);
+        assert_eq!(client.responses(vec![3])[&3], ("200".to_string(), b"slow".to_vec()));
+
+        // The body of the upgraded request reaches the handler of stream 1.
+        let mut client = Client::connect_raw(context(Default::default()));
+        client.socket.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        client.socket.read_exact(&mut head).unwrap();
+        client.socket.write_all(PREFACE).unwrap();
+        client.send(Frame::new(SETTINGS, 0, 0, vec![]));
+        assert_eq!(client.responses(vec![1])[&1], ("200".to_string(), b"hello".to_vec()));
+    }
+}
@@ -0,0 +1,265 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+
+/// The connection preface every HTTP/2 client sends first.
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+pub(crate) const DATA: u8 = 0x0;
+pub(crate) const HEADERS: u8 = 0x1;
+pub(crate) const PRIORITY: u8 = 0x2;
+pub(crate) const RST_STREAM: u8 = 0x3;
+pub(crate) const SETTINGS: u8 = 0x4;
+pub(crate) const PUSH_PROMISE: u8 = 0x5;
+pub(crate) const PING: u8 = 0x6;
+pub(crate) const GOAWAY: u8 = 0x7;
+pub(crate) const WINDOW_UPDATE: u8 = 0x8;
+pub(crate) const CONTINUATION: u8 = 0x9;
+
+pub(crate) const END_STREAM: u8 = 0x1;
+pub(crate) const ACK: u8 = 0x1;
+pub(crate) const END_HEADERS: u8 = 0x4;
+pub(crate) const PADDED: u8 = 0x8;
+pub(crate) const PRIORITY_FLAG: u8 = 0x20;
+
+/// Error codes of `RST_STREAM` and `GOAWAY`, RFC 9113 section 7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorCode {
+    NoError = 0x0,
+    Protocol = 0x1,
+    FlowControl = 0x3,
+    StreamClosed = 0x5,
+    FrameSize = 0x6,
+    RefusedStream = 0x7,
+    Compression = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+pub(crate) const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+pub(crate) const DEFAULT_WINDOW_SIZE: u32 = 65_535;
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame {
+    pub(crate) kind: u8,
+    pub(crate) flags: u8,
+    pub(crate) stream_id: u32,
+    pub(crate) payload: Vec<u8>,
+}
+
+impl Frame {
+    pub(crate) fn new(kind: u8, flags: u8, stream_id: u32, payload: Vec<u8>) -> Self {
+        Self { kind, flags, stream_id, payload }
+    }
+
+    pub(crate) fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub(crate) fn rst_stream(stream_id: u32, code: ErrorCode) -> Self {
+        Self::new(RST_STREAM, 0, stream_id, (code as u32).to_be_bytes().to_vec())
+    }
+
+    pub(crate) fn goaway(last_stream_id: u32, code: ErrorCode) -> Self {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend((code as u32).to_be_bytes());
+        Self::new(GOAWAY, 0, 0, payload)
+    }
+
+    pub(crate) fn window_update(stream_id: u32, increment: u32) -> Self {
+        Self::new(WINDOW_UPDATE, 0, stream_id, increment.to_be_bytes().to_vec())
+    }
+
+    /// The payload without padding, and without the priority fields of `HEADERS`.
+    pub(crate) fn data(&self) -> Option<&[u8]> {
+        let mut payload = &self.payload[..];
+        let mut padding = 0;
+        if self.has(PADDED) {
+            padding = *payload.first()? as usize;
+            payload = &payload[1..];
+        }
+        if self.kind == HEADERS && self.has(PRIORITY_FLAG) {
+            payload = payload.get(5..)?;
+        }
+        payload.get(..payload.len().checked_sub(padding)?)
+    }
+
+    pub(crate) fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.payload.get(offset..offset + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()) & 0x7fff_ffff)
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend(&(self.payload.len() as u32).to_be_bytes()[1..]);
+        buf.push(self.kind);
+        buf.push(self.flags);
+        buf.extend(self.stream_id.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+    }
+}
+
+/// Reads frames from a stream whose reads may time out, keeping partial
+/// frames buffered so reading can be resumed.
+pub(crate) struct FrameReader {
+    buf: Vec<u8>,
+    pub(crate) max_frame_size: u32,
+    pub(crate) bytes_read: u64,
+}
+
+impl FrameReader {
+    /// `buffered` holds bytes already read from the connection.
+    pub(crate) fn new(buffered: Vec<u8>) -> Self {
+        Self { buf: buffered, max_frame_size: DEFAULT_MAX_FRAME_SIZE, bytes_read: 0 }
+    }
+
+    /// Reads more input once unless a whole frame is buffered. Returns
+    /// `None` if the read timed out before a frame was complete.
+    pub(crate) fn poll(&mut self, stream: &mut impl Read) -> io::Result<Option<Frame>> {
+        if let Some(frame) = self.take()? {
+            return Ok(Some(frame));
+        }
+        match self.fill(stream) {
+            Ok(()) => self.take(),
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Consumes the client connection preface.
+    pub(crate) fn read_preface(&mut self, stream: &mut impl Read) -> io::Result<()> {
+        while self.buf.len() < PREFACE.len() && PREFACE.starts_with(&self.buf) {
+            self.fill(stream)?;
+        }
+        if !self.buf.starts_with(PREFACE) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid HTTP/2 connection preface"));
+        }
+        self.buf.drain(..PREFACE.len());
+        Ok(())
+    }
+
+    fn fill(&mut self, stream: &mut impl Read) -> io::Result<()> {
+        let mut chunk = [0u8; 16 * 1024];
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.bytes_read += len as u64;
+        self.buf.extend_from_slice(&chunk[..len]);
+        Ok(())
+    }
+
+    /// Returns the next frame if it is buffered whole, without reading.
+    pub(crate) fn take(&mut self) -> io::Result<Option<Frame>> {
+        if self.buf.len() < 9 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([0, self.buf[0], self.buf[1], self.buf[2]]);
+        if len > self.max_frame_size {
+            return Err(io::Error::new(ErrorKind::InvalidData, "frame exceeds SETTINGS_MAX_FRAME_SIZE"));
+        }
+        if self.buf.len() < 9 + len as usize {
+            return Ok(None);
+        }
+        let stream_id = u32::from_be_bytes(self.buf[5..9].try_into().unwrap()) & 0x7fff_ffff;
+        let frame = Frame::new(self.buf[3], self.buf[4], stream_id, self.buf[9..9 + len as usize].to_vec());
+        self.buf.drain(..9 + len as usize);
+        Ok(Some(frame))
+    }
+}
+
+/// Writes frames and counts the bytes sent.
+pub(crate) fn write_frames(stream: &mut impl Write, frames: &[Frame]) -> io::Result<u64> {
+    let mut buf = vec![];
+    for frame in frames {
+        frame.encode(&mut buf);
+    }
+    stream.write_all(&buf)?;
+    stream.flush()?;
+    Ok(buf.len() as u64)
+}
+
+/// The parameters one endpoint announces to the other in `SETTINGS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PeerSettings {
+    pub(crate) initial_window_size: u32,
+    pub(crate) max_frame_size: u32,
+}
+
+impl Default for PeerSettings {
+    fn default() -> Self {
+        Self { initial_window_size: DEFAULT_WINDOW_SIZE, max_frame_size: DEFAULT_MAX_FRAME_SIZE }
+    }
+}
+
+pub(crate) const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub(crate) const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub(crate) const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub(crate) const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub(crate) const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub(crate) const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+impl PeerSettings {
+    /// Applies a `SETTINGS` payload, ignoring unknown parameters.
+    pub(crate) fn apply(&mut self, payload: &[u8]) -> Result<(), ErrorCode> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(ErrorCode::FrameSize);
+        }
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes(setting[2..6].try_into().unwrap());
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(ErrorCode::Protocol),
+                SETTINGS_INITIAL_WINDOW_SIZE if value as i64 > MAX_WINDOW_SIZE => return Err(ErrorCode::FlowControl),
+                SETTINGS_INITIAL_WINDOW_SIZE => self.initial_window_size = value,
+                SETTINGS_MAX_FRAME_SIZE if !(DEFAULT_MAX_FRAME_SIZE..=(1 << 24) - 1).contains(&value) => return Err(ErrorCode::Protocol),
+                SETTINGS_MAX_FRAME_SIZE => self.max_frame_size = value,
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn encode_settings(settings: &[(u16, u32)]) -> Vec<u8> {
+    settings.iter()
+        .flat_map(|&(id, value)| id.to_be_bytes().into_iter().chain(value.to_be_bytes()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut buf = PREFACE.to_vec();
+        Frame::new(HEADERS, END_HEADERS, 1, b"abc".to_vec()).encode(&mut buf);
+        Frame::goaway(1, ErrorCode::NoError).encode(&mut buf);
+
+        let mut reader = FrameReader::new(vec![]);
+        let mut input = Cursor::new(buf);
+        reader.read_preface(&mut input).unwrap();
+        assert_eq!(reader.poll(&mut input).unwrap(), Some(Frame::new(HEADERS, END_HEADERS, 1, b"abc".to_vec())));
+        let goaway = reader.poll(&mut input).unwrap().unwrap();
+        assert_eq!((goaway.kind, goaway.u32_at(0), goaway.u32_at(4)), (GOAWAY, Some(1), Some(0)));
+        assert_eq!(reader.poll(&mut input).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_padding_and_priority() {
+        let frame = Frame::new(HEADERS, PADDED | PRIORITY_FLAG, 1, vec![2, 0, 0, 0, 3, 16, b'h', b'i', 0, 0]);
+        assert_eq!(frame.data(), Some(&b"hi"[..]));
+        assert_eq!(Frame::new(DATA, PADDED, 1, vec![5, b'x']).data(), None);
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut settings = PeerSettings::default();
+        settings.apply(&encode_settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 10), (SETTINGS_MAX_FRAME_SIZE, 32_768), (0xff, 1)])).unwrap();
+        assert_eq!(settings, PeerSettings { initial_window_size: 10, max_frame_size: 32_768 });
+        assert_eq!(settings.apply(&encode_settings(&[(SETTINGS_MAX_FRAME_SIZE, 100)])), Err(ErrorCode::Protocol));
+        assert_eq!(settings.apply(&encode_settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 1 << 31)])), Err(ErrorCode::FlowControl));
+        assert_eq!(settings.apply(&[0, 1, 0]), Err(ErrorCode::FrameSize));
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use thiserror::Error;
+
+
+/// A decoded header field as raw name and value bytes.
+pub(crate) type Field = (Vec<u8>, Vec<u8>);
+
+/// Size of the decoder's dynamic table, as advertised in
+/// `SETTINGS_HEADER_TABLE_SIZE`.
+pub(crate) const DEFAULT_TABLE_SIZE: usize = 4096;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum HpackError {
+    #[error("header block ends in the middle of a field")]
+    Truncated,
+    #[error("integer overflows")]
+    IntegerOverflow,
+    #[error("invalid table index {0}")]
+    InvalidIndex(usize),
+    #[error("invalid Huffman code")]
+    Huffman,
+    #[error("dynamic table size update to {0} not allowed here")]
+    TableSizeUpdate(usize),
+    #[error("header list larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+/// Decodes header blocks, keeping the dynamic table that spans a connection.
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    dynamic: VecDeque<Field>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self { dynamic: VecDeque::new(), size: 0, max_size: DEFAULT_TABLE_SIZE }
+    }
+}
+
+impl Decoder {
+    /// Decodes a header block, giving up as soon as the decoded list grows
+    /// past `max_list_size` bytes as counted by `SETTINGS_MAX_HEADER_LIST_SIZE`.
+    /// A few bytes of indexed fields can otherwise expand to many times the
+    /// block's size. The dynamic table is left unusable after an error, so
+    /// every error must close the connection.
+    pub(crate) fn decode(&mut self, mut block: &[u8], max_list_size: usize) -> Result<Vec<Field>, HpackError> {
+        let mut fields = vec![];
+        let mut list_size = 0usize;
+        while let Some(&first) = block.first() {
+            let field = if first & 0x80 != 0 {
+                let index = decode_integer(&mut block, 7)?;
+                self.get(index)?
+            } else if first & 0x40 != 0 {
+                let field = self.decode_literal(&mut block, 6)?;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                let size = decode_integer(&mut block, 5)?;
+                if !fields.is_empty() || size > DEFAULT_TABLE_SIZE {
+                    return Err(HpackError::TableSizeUpdate(size));
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                self.decode_literal(&mut block, 4)?
+            };
+            list_size = list_size.saturating_add(entry_size(&field));
+            if list_size > max_list_size {
+                return Err(HpackError::TooLarge(max_list_size));
+            }
+            fields.push(field);
+        }
+        Ok(fields)
+    }
+
+    fn decode_literal(&self, block: &mut &[u8], prefix: u8) -> Result<Field, HpackError> {
+        let name = match decode_integer(block, prefix)? {
+            0 => decode_string(block)?,
+            index => self.get(index)?.0,
+        };
+        Ok((name, decode_string(block)?))
+    }
+
+    fn get(&self, index: usize) -> Result<Field, HpackError> {
+        match index {
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            },
+            _ => index.checked_sub(62)
+                .and_then(|index| self.dynamic.get(index))
+                .cloned()
+                .ok_or(HpackError::InvalidIndex(index)),
+        }
+    }
+
+    fn insert(&mut self, field: Field) {
+        let size = entry_size(&field);
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.dynamic.push_front(field);
+        }
+    }
+
+    /// Drops the oldest entries until `incoming` more bytes fit.
+    fn evict(&mut self, incoming: usize) {
+        while self.size + incoming > self.max_size {
+            match self.dynamic.pop_back() {
+                Some(field) => self.size -= entry_size(&field),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encodes a header block. Fields are never added to the dynamic table, so
+/// the peer's table size setting never needs to be tracked.
+pub(crate) fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut block = vec![];
+    for (name, value) in fields {
+        let exact = STATIC_TABLE.iter().position(|&field| field == (name, value));
+        let by_name = STATIC_TABLE.iter().position(|&(static_name, _)| static_name == name);
+        match (exact, by_name) {
+            (Some(index), _) => encode_integer(&mut block, 0x80, 7, index + 1),
+            (None, Some(index)) => {
+                encode_integer(&mut block, 0x00, 4, index + 1);
+                encode_string(&mut block, value.as_bytes());
+            },
+            (None, None) => {
+                block.push(0x00);
+                encode_string(&mut block, name.as_bytes());
+                encode_string(&mut block, value.as_bytes());
+            },
+        }
+    }
+    block
+}
+
+fn entry_size((name, value): &Field) -> usize {
+    name.len() + value.len() + 32
+}
+
+fn decode_integer(block: &mut &[u8], prefix: u8) -> Result<usize, HpackError> {
+    let (&first, rest) = block.split_first().ok_or(HpackError::Truncated)?;
+    *block = rest;
+    let mask = (1usize << prefix) - 1;
+    let mut value = first as usize & mask;
+    if value < mask {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(HpackError::Truncated)?;
+        *block = rest;
+        if shift > 28 {
+            return Err(HpackError::IntegerOverflow);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<Vec<u8>, HpackError> {
+    let huffman = block.first().ok_or(HpackError::Truncated)? & 0x80 != 0;
+    let len = decode_integer(block, 7)?;
+    if len > block.len() {
+        return Err(HpackError::Truncated);
+    }
+    let (string, rest) = block.split_at(len);
+    *block = rest;
+    match huffman {
+        true => huffman_decode(string),
+        false => Ok(string.to_vec()),
+    }
+}
+
+/// Writes a string literal, Huffman coded when that is shorter.
+fn encode_string(block: &mut Vec<u8>, string: &[u8]) {
+    let encoded_len = string.iter().map(|&b| HUFFMAN_CODES[b as usize].1 as usize).sum::<usize>().div_ceil(8);
+    if encoded_len < string.len() {
+        encode_integer(block, 0x80, 7, encoded_len);
+        huffman_encode(block, string);
+    } else {
+        encode_integer(block, 0x00, 7, string.len());
+        block.extend_from_slice(string);
+    }
+}
+
+fn huffman_encode(block: &mut Vec<u8>, string: &[u8]) {
+    let (mut bits, mut len) = (0u64, 0u32);
+    for &byte in string {
+        let (code, code_len) = HUFFMAN_CODES[byte as usize];
+        bits = (bits << code_len) | code as u64;
+        len += code_len as u32;
+        while len >= 8 {
+            len -= 8;
+            block.push((bits >> len) as u8);
+        }
+    }
+    if len > 0 {
+        // Pad with the most significant bits of EOS, which are all ones.
+        block.push(((bits << (8 - len)) | (0xff >> len)) as u8);
+    }
+}
+
+/// A node of the Huffman decoding tree: children for bit 0 and 1, where a
+/// negative value `-(symbol + 1)` marks a leaf.
+type Node = [i32; 2];
+
+fn huffman_tree() -> &'static [Node] {
+    static TREE: OnceLock<Vec<Node>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0, 0]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..len).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                if i == 0 {
+                    tree[node][bit] = -(symbol as i32 + 1);
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0, 0]);
+                        tree[node][bit] = tree.len() as i32 - 1;
+                    }
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+        tree
+    })
+}
+
+fn huffman_decode(string: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(string.len() * 8 / 5);
+    // Bits consumed since the last symbol, and whether all were ones.
+    let (mut node, mut pending, mut all_ones) = (0, 0, true);
+    for &byte in string {
+        for i in (0..8).rev() {
+            let bit = ((byte >> i) & 1) as usize;
+            pending += 1;
+            all_ones &= bit == 1;
+            match tree[node][bit] {
+                0 => return Err(HpackError::Huffman),
+                next if next > 0 => node = next as usize,
+                leaf => {
+                    let symbol = -leaf - 1;
+                    if symbol == 256 {
+                        return Err(HpackError::Huffman);
+                    }
+                    decoded.push(symbol as u8);
+                    (node, pending, all_ones) = (0, 0, true);
+                },
+            }
+        }
+    }
+    // Padding must be a prefix of EOS shorter than a byte.
+    if pending > 7 || !all_ones {
+        return Err(HpackError::Huffman);
+    }
+    Ok(decoded)
+}
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Code and bit length of each symbol, from RFC 7541 Appendix B. Symbol 256 is EOS.
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<Field> {
+        pairs.iter().map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+    }
+
+    /// The request examples with Huffman coding from RFC 7541 C.4.
+    #[test]
+    fn test_decode_rfc_examples() {
+        let mut decoder = Decoder::default();
+        let first = decoder.decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"), usize::MAX).unwrap();
+        assert_eq!(first, fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]));
+        let second = decoder.decode(&hex("8286 84be 5886 a8eb 1064 9cbf"), usize::MAX).unwrap();
+        assert_eq!(second, fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"), ("cache-control", "no-cache")]));
+        let third = decoder.decode(&hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"), usize::MAX).unwrap();
+        assert_eq!(third, fields(&[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"), (":authority", "www.example.com"), ("custom-key", "custom-value")]));
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let headers = [(":status", "200"), ("content-length", "1000000"), ("x-request-id", "abc"), ("content-type", "application/json")];
+        let block = encode(headers);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::default().decode(&block, usize::MAX).unwrap(), fields(&headers));
+
+        let long = "a".repeat(300);
+        let block = encode([("x-long", long.as_str())]);
+        assert_eq!(Decoder::default().decode(&block, usize::MAX).unwrap(), fields(&[("x-long", &long)]));
+    }
+
+    #[test]
+    fn test_integers() {
+        let mut block = vec![];
+        encode_integer(&mut block, 0, 5, 1337);
+        assert_eq!(block, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_integer(&mut &block[..], 5), Ok(1337));
+        assert_eq!(decode_integer(&mut &[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f][..], 5), Err(HpackError::IntegerOverflow));
+        assert_eq!(decode_integer(&mut &[0x1f, 0x9a][..], 5), Err(HpackError::Truncated));
+    }
+
+    #[test]
+    fn test_invalid_blocks() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode(&[0x80], usize::MAX), Err(HpackError::InvalidIndex(0)));
+        assert_eq!(decoder.decode(&[0xbe], usize::MAX), Err(HpackError::InvalidIndex(62)));
+        assert_eq!(decoder.decode(&[0x82, 0x20], usize::MAX), Err(HpackError::TableSizeUpdate(0)));
+        let mut block = vec![];
+        encode_integer(&mut block, 0x20, 5, DEFAULT_TABLE_SIZE + 1);
+        assert_eq!(decoder.decode(&block, usize::MAX), Err(HpackError::TableSizeUpdate(DEFAULT_TABLE_SIZE + 1)));
+        // "a" followed by eleven bits of padding.
+        assert_eq!(decoder.decode(&hex("00 82 1fff 00"), usize::MAX), Err(HpackError::Huffman));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut decoder = Decoder::default();
+        let mut block = vec![];
+        encode_integer(&mut block, 0x20, 5, 64);
+        block.extend([0x40, 0x01, b'a', 0x01, b'1', 0x40, 0x01, b'b', 0x01, b'2']);
+        assert_eq!(decoder.decode(&block, usize::MAX).unwrap(), fields(&[("a", "1"), ("b", "2")]));
+        assert_eq!(decoder.dynamic, fields(&[("b", "2")]));
+        assert_eq!(decoder.size, 34);
+    }
+
+    #[test]
+    fn test_list_size_limit() {
+        // ":method: GET" and ":path: /" count 42 and 38 bytes.
+        let block = hex("8284");
+        assert_eq!(Decoder::default().decode(&block, 80).unwrap(), fields(&[(":method", "GET"), (":path", "/")]));
+        assert_eq!(Decoder::default().decode(&block, 79), Err(HpackError::TooLarge(79)));
+
+        // One large literal repeated by index stops at the first repeat over the limit.
+        let mut decoder = Decoder::default();
+        let long = "a".repeat(1000);
+        let mut block = vec![0x40];
+        encode_string(&mut block, b"x-long");
+        encode_string(&mut block, long.as_bytes());
+        block.extend([0xbe; 10_000]);
+        assert_eq!(decoder.decode(&block, 4096), Err(HpackError::TooLarge(4096)));
+    }
+}
@@ -0,0 +1,198 @@
+mod connection;
+mod frame;
+mod hpack;
+
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use fast_web_server_types::{HttpHeaders, HttpRequest, HttpRequestError, HttpVersion, RequestLimits, RequestType};
+
+pub(crate) use crate::http2::connection::{serve, Socket, Upgrade};
+use crate::http2::frame::PREFACE;
+
+
+/// HTTP/2 parameters. HTTP/2 is negotiated with ALPN on TLS connections;
+/// on plain connections clients can upgrade with `Upgrade: h2c` or send
+/// the connection preface right away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Http2Settings {
+    pub max_concurrent_streams: u32,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    pub idle_timeout: Duration,
+    pub h2c: bool,
+}
+
+impl Default for Http2Settings {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: 100,
+            initial_window_size: 65_535,
+            max_frame_size: 16_384,
+            idle_timeout: Duration::from_secs(60),
+            h2c: true,
+        }
+    }
+}
+
+impl Http2Settings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Streams a client may have open at once, counting reset streams whose
+    /// handler is still running. Handlers run on the server's handler pool.
+    pub fn max_concurrent_streams(mut self, max_concurrent_streams: u32) -> Self {
+        self.max_concurrent_streams = max_concurrent_streams;
+        self
+    }
+
+    /// Bytes of request body a client may send on a stream before the
+    /// server grants more with `WINDOW_UPDATE`.
+    pub fn initial_window_size(mut self, initial_window_size: u32) -> Self {
+        self.initial_window_size = initial_window_size.min(frame::MAX_WINDOW_SIZE as u32);
+        self
+    }
+
+    /// Largest frame payload the server accepts, between 16 KiB and 16 MiB.
+    pub fn max_frame_size(mut self, max_frame_size: u32) -> Self {
+        self.max_frame_size = max_frame_size.clamp(frame::DEFAULT_MAX_FRAME_SIZE, (1 << 24) - 1);
+        self
+    }
+
+    /// Time a connection may stay without frames while no request is running.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Whether plain HTTP connections may switch to HTTP/2.
+    pub fn h2c(mut self, h2c: bool) -> Self {
+        self.h2c = h2c;
+        self
+    }
+}
+
+/// Whether the start of a connection looks like the HTTP/2 preface.
+pub(crate) fn is_preface(buffered: &[u8]) -> bool {
+    let len = buffered.len().min(PREFACE.len());
+    len >= 4 && buffered[..len] == PREFACE[..len]
+}
+
+/// The decoded `HTTP2-Settings` of an `Upgrade: h2c` request.
+pub(crate) fn h2c_upgrade(request: &HttpRequest) -> Option<Vec<u8>> {
+    let has_token = |header: &str, token: &str| request.headers.get(header)
+        .is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+    if !has_token("Upgrade", "h2c") || !has_token("Connection", "upgrade") {
+        return None;
+    }
+    let settings = request.headers.get("HTTP2-Settings")?;
+    URL_SAFE_NO_PAD.decode(settings.trim_end_matches('=')).ok()
+}
+
+/// Header fields that only apply to a single HTTP/1.1 connection.
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+pub(crate) fn is_connection_header(name: &str) -> bool {
+    CONNECTION_HEADERS.iter().any(|header| header.eq_ignore_ascii_case(name))
+}
+
+/// Builds a request from the decoded header fields of a stream.
+fn build_request(fields: Vec<hpack::Field>, limits: &RequestLimits) -> Result<HttpRequest, HttpRequestError> {
+    let (mut method, mut path, mut scheme, mut authority) = (None, None, None, None);
+    let mut headers = HttpHeaders::new();
+    let (mut size, mut regular) = (0, false);
+    for (name, value) in fields {
+        size += name.len() + value.len() + 32;
+        if size > limits.max_header_bytes {
+            return Err(HttpRequestError::HeadersTooLarge);
+        }
+        let name = String::from_utf8(name).map_err(|_| HttpRequestError::Header)?;
+        let value = String::from_utf8(value).map_err(|_| HttpRequestError::Header)?;
+        if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err(HttpRequestError::Header);
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            let field = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                _ => return Err(HttpRequestError::Header),
+            };
+            // Pseudo-header fields come first and only once.
+            if regular || field.replace(value).is_some() {
+                return Err(HttpRequestError::Header);
+            }
+            continue;
+        }
+        regular = true;
+        if is_connection_header(&name) {
+            return Err(HttpRequestError::Header);
+        }
+        match headers.get(&name) {
+            Some(existing) => {
+                let separator = if name == "cookie" { "; " } else { ", " };
+                let joined = format!("{}{}{}", existing, separator, value);
+                headers.insert(name, joined);
+            },
+            None if headers.len() == limits.max_headers => return Err(HttpRequestError::HeadersTooLarge),
+            None => headers.insert(name, value),
+        }
+    }
+    let (method, path) = match (method, path) {
+        (Some(method), Some(path)) if !path.is_empty() && scheme.is_some() => (method, path),
+        _ => return Err(HttpRequestError::StartLine("missing :method, :scheme or :path".to_string())),
+    };
+    if let Some(authority) = authority {
+        if headers.get("host").is_none() {
+            headers.insert("host".to_string(), authority);
+        }
+    }
+    let request_type = RequestType::from_string(&method)
+        .map_err(|_| HttpRequestError::StartLine(format!("unsupported method {}", method)))?;
+    HttpRequest::from_parts(request_type, &path, HttpVersion::HTTP2, headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<hpack::Field> {
+        pairs.iter().map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn test_build_request() {
+        let limits = RequestLimits::default();
+        let request = build_request(fields(&[(":method", "POST"), (":scheme", "https"), (":path", "/a?b=c"), (":authority", "example.test"),
+            ("cookie", "a=1"), ("cookie", "b=2"), ("accept", "text/html")]), &limits).unwrap();
+        assert_eq!(request.start_line.request_type, RequestType::POST);
+        assert_eq!(request.start_line.request_target.raw, "/a?b=c");
+        assert_eq!(request.start_line.http_version, HttpVersion::HTTP2);
+        assert_eq!(request.headers.get("Host").unwrap(), "example.test");
+        assert_eq!(request.headers.get("Cookie").unwrap(), "a=1; b=2");
+
+        let invalid = |pairs: &[(&str, &str)]| build_request(fields(pairs), &limits).unwrap_err().kind();
+        assert_eq!(invalid(&[(":method", "GET"), (":scheme", "https")]), "start_line");
+        assert_eq!(invalid(&[(":method", "GET"), (":scheme", "https"), (":path", "/"), ("Accept", "*/*")]), "header");
+        assert_eq!(invalid(&[(":method", "GET"), (":scheme", "https"), (":path", "/"), ("connection", "close")]), "header");
+        assert_eq!(invalid(&[(":method", "GET"), (":method", "GET"), (":scheme", "https"), (":path", "/")]), "header");
+        assert_eq!(invalid(&[(":method", "GET"), (":scheme", "https"), ("accept", "*/*"), (":path", "/")]), "header");
+        assert_eq!(invalid(&[(":method", "GET"), (":scheme", "https"), (":path", "/"), ("a", &"a".repeat(20_000))]), "headers_too_large");
+    }
+
+    #[test]
+    fn test_h2c_upgrade() {
+        let request = |raw: &str| HttpRequest::new(&mut Cursor::new(raw.as_bytes())).unwrap();
+        let upgrade = request("GET / HTTP/1.1\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n");
+        assert_eq!(h2c_upgrade(&upgrade), Some(vec![0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 255, 255]));
+        assert_eq!(h2c_upgrade(&request("GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n")), None);
+        assert_eq!(h2c_upgrade(&request("GET / HTTP/1.1\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\n\r\n")), None);
+        assert!(is_preface(b"PRI * HTTP/2.0\r\n"));
+        assert!(!is_preface(b"PRI"));
+        assert!(!is_preface(b"POST / HTTP/1.1\r\n"));
+    }
+}
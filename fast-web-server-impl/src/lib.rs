@@ -1,20 +1,56 @@
 mod fast_web_server;
-use fast_web_server_types::RequestType;
+mod router;
+mod response_writer;
+mod route_group;
+mod metrics;
+mod connection_limits;
+mod timeouts;
+mod http2;
+mod websocket;
+mod streaming;
+mod sendfile;
+mod body_decoding;
+mod sse;
+mod static_files;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod middleware;
+#[cfg(test)]
+mod test_util;
+use std::sync::Arc;
+use fast_web_server_types::{Handler, RequestType};
 
-pub use crate::fast_web_server::FastWebServer;
+pub use crate::connection_limits::{ConnectionLimits, Overload};
+pub use crate::fast_web_server::{FastWebServer, PeerAddr};
+pub use crate::http2::Http2Settings;
+pub use crate::metrics::{Histogram, Metrics, LATENCY_BUCKETS, UNMATCHED_ROUTE};
+pub use crate::middleware::{Middleware, Next};
+pub use crate::route_group::RouteGroup;
+pub use crate::router::MatchedRoute;
+pub use crate::sse::{Event, LastEventId, Sse};
+pub use crate::static_files::StaticFiles;
+pub use crate::timeouts::{SetTimeout, Timeouts};
+pub use crate::websocket::{CloseCode, CloseFrame, Message, WebSocket, WebSocketConfig, WebSocketError, WebSocketUpgrade};
+#[cfg(feature = "tls")]
+pub use crate::tls::{TlsConfig, TlsError, TlsStream};
 
 
 #[macro_export]
 macro_rules! bind {
     ( $server:expr, $( $x:expr ),* ) => {
         $(
-            $x.register(&mut $server);
+            $crate::RegisterEndpoint::register(&$x, &mut $server);
         )*
     };
 }
 
+/// Something handlers can be bound to: the server itself or a `RouteGroup`.
+pub trait Routes {
+    fn bind_arc(&mut self, request_type: RequestType, route: &str, handler: Arc<dyn Handler>);
+}
+
 pub trait RegisterEndpoint {
-    fn register(&self, server: &mut FastWebServer);
-    fn request_type() -> RequestType;
+    fn register(&self, server: &mut dyn Routes);
+    fn request_types() -> Vec<RequestType>;
     fn route() -> String;
 }
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use fast_web_server_types::RequestType;
+
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 14] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Cumulative count of observations below each of `LATENCY_BUCKETS`.
+    pub buckets: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Route label of requests that matched no route.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Registry of server metrics, shared by the server and its handlers.
+/// Handlers can reach it through the `State<Metrics>` extractor.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    handler_latency: Mutex<HashMap<(RequestType, String), Histogram>>,
+    request_latency: Mutex<HashMap<(RequestType, String, u16), Histogram>>,
+    parse_errors: Mutex<HashMap<&'static str, u64>>,
+    queued_connections: AtomicUsize,
+    active_connections: AtomicUsize,
+    rejected_connections: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a request that went through the middleware pipeline,
+    /// labelled with its route pattern, or `UNMATCHED_ROUTE`, and status.
+    pub fn observe_request(&self, request_type: &RequestType, route: &str, status: u16, elapsed: Duration) {
+        let mut request_latency = self.request_latency.lock().unwrap();
+        request_latency.entry((request_type.to_owned(), route.to_string(), status))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn request_latency(&self, request_type: &RequestType, route: &str, status: u16) -> Option<Histogram> {
+        let request_latency = self.request_latency.lock().unwrap();
+        request_latency.get(&(request_type.to_owned(), route.to_string(), status)).cloned()
+    }
+
+    pub fn parse_error(&self, kind: &'static str) {
+        *self.parse_errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn parse_errors(&self, kind: &str) -> u64 {
+        self.parse_errors.lock().unwrap().get(kind).copied().unwrap_or(0)
+    }
+
+    /// An accepted connection waits for a free worker.
+    pub(crate) fn connection_queued(&self) {
+        self.queued_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A worker picked up a queued connection.
+    pub(crate) fn connection_started(&self) {
+        self.queued_connections.fetch_sub(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A worker handed its connection to the streamer. It stays active
+    /// until finished a second time, when its stream ends.
+    pub(crate) fn connection_streaming(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_finished(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A connection was turned away because the server was at capacity.
+    pub(crate) fn connection_rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Connections accepted but not yet picked up by the thread pool.
+    pub fn queued_connections(&self) -> usize {
+        self.queued_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn add_bytes_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let request_latency = self.request_latency.lock().unwrap();
+        let mut requests: Vec<_> = request_latency.iter()
+            .map(|((request_type, route, status), histogram)| {
+                let labels = vec![("method", request_type.to_string()), ("route", route.clone()), ("status", status.to_string())];
+                (labels, histogram.clone())
+            })
+            .collect();
+        requests.sort_by(|a, b| a.0.cmp(&b.0));
+        drop(request_latency);
+        header(&mut out, "http_requests_total", "counter", "Requests handled, by method, route and status.");
+        for (labels, histogram) in &requests {
+            writeln!(out, "http_requests_total{} {}", format_labels(labels), histogram.count).unwrap();
+        }
+        header(&mut out, "http_request_duration_seconds", "histogram", "Time spent in middlewares and handler, by method, route and status.");
+        for (labels, histogram) in &requests {
+            write_histogram(&mut out, "http_request_duration_seconds", labels, histogram);
+        }
+
+        let handler_latency = self.handler_latency.lock().unwrap();
+        let mut handlers: Vec<_> = handler_latency.iter()
+            .map(|((request_type, route), histogram)| (vec![("method", request_type.to_string()), ("route", route.clone())], histogram.clone()))
+            .collect();
+        handlers.sort_by(|a, b| a.0.cmp(&b.0));
+        drop(handler_latency);
+        header(&mut out, "http_handler_duration_seconds", "histogram", "Time spent in #[instrument]ed handlers, by method and route.");
+        for (labels, histogram) in &handlers {
+            write_histogram(&mut out, "http_handler_duration_seconds", labels, histogram);
+        }
+
+        header(&mut out, "http_parse_errors_total", "counter", "Requests that could not be parsed, by kind.");
+        let mut parse_errors: Vec<_> = self.parse_errors.lock().unwrap().iter().map(|(kind, count)| (*kind, *count)).collect();
+        parse_errors.sort();
+        for (kind, count) in parse_errors {
+            writeln!(out, "http_parse_errors_total{} {}", format_labels(&[("kind", kind.to_string())]), count).unwrap();
+        }
+
+        header(&mut out, "http_active_connections", "gauge", "Connections being served by a worker.");
+        writeln!(out, "http_active_connections {}", self.active_connections()).unwrap();
+        header(&mut out, "http_queued_connections", "gauge", "Accepted connections waiting in the thread pool queue.");
+        writeln!(out, "http_queued_connections {}", self.queued_connections()).unwrap();
+        header(&mut out, "http_rejected_connections_total", "counter", "Connections answered with 503 because the server was at capacity.");
+        writeln!(out, "http_rejected_connections_total {}", self.rejected_connections()).unwrap();
+        header(&mut out, "http_received_bytes_total", "counter", "Bytes read from clients.");
+        writeln!(out, "http_received_bytes_total {}", self.bytes_received()).unwrap();
+        header(&mut out, "http_sent_bytes_total", "counter", "Bytes written to clients.");
+        writeln!(out, "http_sent_bytes_total {}", self.bytes_sent()).unwrap();
+        out
+    }
+
+    /// Records the time a handler took, labelled with the request method
+    /// and the route pattern it was bound to.
+    pub fn observe_handler(&self, request_type: &RequestType, route: &str, elapsed: Duration) {
+        let mut handler_latency = self.handler_latency.lock().unwrap();
+        handler_latency.entry((request_type.to_owned(), route.to_string()))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn handler_latency(&self, request_type: &RequestType, route: &str) -> Option<Histogram> {
+        let handler_latency = self.handler_latency.lock().unwrap();
+        handler_latency.get(&(request_type.to_owned(), route.to_string())).cloned()
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn format_labels(labels: &[(&str, String)]) -> String {
+    let labels: Vec<String> = labels.iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &[(&str, String)], histogram: &Histogram) {
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+        let mut labels = labels.to_vec();
+        labels.push(("le", bound.to_string()));
+        writeln!(out, "{}_bucket{} {}", name, format_labels(&labels), count).unwrap();
+    }
+    let mut labels = labels.to_vec();
+    labels.push(("le", "+Inf".to_string()));
+    writeln!(out, "{}_bucket{} {}", name, format_labels(&labels), histogram.count).unwrap();
+    labels.pop();
+    writeln!(out, "{}_sum{} {}", name, format_labels(&labels), histogram.sum).unwrap();
+    writeln!(out, "{}_count{} {}", name, format_labels(&labels), histogram.count).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.2);
+        assert_eq!(histogram.count, 2);
+        assert_eq!(histogram.buckets[2], 0);
+        assert_eq!(histogram.buckets[3], 1);
+        assert_eq!(histogram.buckets[8], 2);
+        assert!((histogram.sum - 0.203).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_observe_handler() {
+        let metrics = Metrics::default();
+        metrics.observe_handler(&RequestType::GET, "/users/{id}", Duration::from_millis(3));
+        metrics.observe_handler(&RequestType::GET, "/users/{id}", Duration::from_millis(30));
+        let histogram = metrics.handler_latency(&RequestType::GET, "/users/{id}").unwrap();
+        assert_eq!(histogram.count, 2);
+        assert_eq!(metrics.handler_latency(&RequestType::POST, "/users/{id}"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.observe_request(&RequestType::GET, "/users/{id}", 200, Duration::from_millis(3));
+        metrics.observe_request(&RequestType::GET, UNMATCHED_ROUTE, 404, Duration::from_millis(1));
+        metrics.parse_error("header");
+        metrics.connection_queued();
+        metrics.connection_queued();
+        metrics.connection_started();
+        metrics.connection_rejected();
+        metrics.add_bytes_received(120);
+        metrics.add_bytes_sent(2048);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE http_requests_total counter\n"));
+        assert!(text.contains("http_requests_total{method=\"GET\",route=\"/users/{id}\",status=\"200\"} 1\n"));
+        assert!(text.contains("http_requests_total{method=\"GET\",route=\"unmatched\",status=\"404\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{method=\"GET\",route=\"/users/{id}\",status=\"200\",le=\"0.0025\"} 0\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{method=\"GET\",route=\"/users/{id}\",status=\"200\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("http_parse_errors_total{kind=\"header\"} 1\n"));
+        assert!(text.contains("http_active_connections 1\n"));
+        assert!(text.contains("http_queued_connections 1\n"));
+        assert!(text.contains("http_rejected_connections_total 1\n"));
+        assert!(text.contains("http_received_bytes_total 120\n"));
+        assert!(text.contains("http_sent_bytes_total 2048\n"));
+    }
+
+    #[test]
+    fn test_label_escaping() {
+        assert_eq!(format_labels(&[("route", "/a\"b\\".to_string())]), "{route=\"/a\\\"b\\\\\"}");
+    }
+}
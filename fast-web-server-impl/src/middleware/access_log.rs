@@ -0,0 +1,358 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use fast_web_server_types::{HttpRequest, HttpResponse};
+use tracing::warn;
+
+use super::{Middleware, Next};
+use crate::PeerAddr;
+
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `host - - [time] "request" status size`
+    Common,
+    /// Common Log Format followed by the quoted referer and user agent.
+    #[default]
+    Combined,
+    /// One JSON object per line with every recorded field, including the duration.
+    Json,
+}
+
+/// Destination of access log lines.
+pub trait LogSink: Send + Sync {
+    fn write_line(&self, line: &str);
+}
+
+impl<F> LogSink for F
+where
+    F: Fn(&str) + Send + Sync,
+{
+    fn write_line(&self, line: &str) {
+        self(line)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Stdout;
+
+impl LogSink for Stdout {
+    fn write_line(&self, line: &str) {
+        let mut stdout = io::stdout().lock();
+        if let Err(e) = writeln!(stdout, "{}", line) {
+            warn!(error = %e, "could not write access log");
+        }
+    }
+}
+
+/// Appends to a file, which is renamed to `<path>.1` once it would grow
+/// past `max_bytes`. Older files shift up to `<path>.<max_files>`.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: Mutex<(File, u64)>,
+}
+
+impl RotatingFile {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let path = path.into();
+        let file = Self::open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_bytes, max_files: 5, file: Mutex::new((file, size)) })
+    }
+
+    /// Number of rotated files to keep, 5 by default.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    fn open(path: &PathBuf) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&self) -> io::Result<File> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        Self::open(&self.path)
+    }
+
+    fn write(&self, line: &str) -> io::Result<()> {
+        let mut guard = self.file.lock().unwrap();
+        let (file, size) = &mut *guard;
+        let len = line.len() as u64 + 1;
+        if *size > 0 && *size + len > self.max_bytes {
+            *file = self.rotate()?;
+            *size = 0;
+        }
+        writeln!(file, "{}", line)?;
+        *size += len;
+        Ok(())
+    }
+}
+
+impl LogSink for RotatingFile {
+    fn write_line(&self, line: &str) {
+        if let Err(e) = self.write(line) {
+            warn!(error = %e, path = %self.path.display(), "could not write access log");
+        }
+    }
+}
+
+/// Writes one line per request with the remote address, method, target,
+/// version, status, response size, referer, user agent and duration.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: LogFormat,
+    sink: Arc<dyn LogSink>,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new(LogFormat::default())
+    }
+}
+
+impl AccessLog {
+    /// Logs in `format` to stdout.
+    pub fn new(format: LogFormat) -> Self {
+        Self { format, sink: Arc::new(Stdout) }
+    }
+
+    pub fn sink<S: LogSink + 'static>(mut self, sink: S) -> Self {
+        self.sink = Arc::new(sink);
+        self
+    }
+}
+
+struct Entry {
+    remote_addr: Option<String>,
+    time: SystemTime,
+    method: String,
+    target: String,
+    version: String,
+    status: u16,
+    size: u64,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    duration: Duration,
+}
+
+impl Entry {
+    fn common(&self) -> String {
+        let size = match self.size {
+            0 => "-".to_string(),
+            size => size.to_string(),
+        };
+        format!("{} - - [{}] \"{} {} {}\" {} {}",
+            self.remote_addr.as_deref().unwrap_or("-"), clf_time(self.time),
+            self.method, escape_quoted(&self.target), self.version, self.status, size)
+    }
+
+    fn combined(&self) -> String {
+        format!("{} \"{}\" \"{}\"", self.common(),
+            escape_quoted(self.referer.as_deref().unwrap_or("-")),
+            escape_quoted(self.user_agent.as_deref().unwrap_or("-")))
+    }
+
+    fn json(&self) -> String {
+        serde_json::json!({
+            "remote_addr": self.remote_addr,
+            "time": rfc3339_time(self.time),
+            "method": self.method,
+            "target": self.target,
+            "version": self.version,
+            "status": self.status,
+            "size": self.size,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "duration_ms": self.duration.as_secs_f64() * 1000.0,
+        }).to_string()
+    }
+
+    fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common => self.common(),
+            LogFormat::Combined => self.combined(),
+            LogFormat::Json => self.json(),
+        }
+    }
+}
+
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Splits a UNIX timestamp into UTC (year, month, day, hour, minute, second).
+fn civil_time(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let (hour, minute, second) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, hour, minute, second)
+}
+
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hour, minute, second) = civil_time(time);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, hour, minute, second)
+}
+
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = civil_time(time);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+/// The size of the response's content: its `Content-Length`, which covers
+/// file bodies, or else the in-memory body. Streamed bodies of unknown
+/// length are logged as empty.
+fn response_size(response: &HttpResponse) -> u64 {
+    let content_length = response.headers.get("Content-Length").and_then(|length| length.parse().ok());
+    content_length.unwrap_or_else(|| response.body.len() as u64 + response.file.as_ref().map_or(0, |file| file.len()))
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: HttpRequest, next: Next) -> HttpResponse {
+        let time = SystemTime::now();
+        let start = Instant::now();
+        let header = |name: &str| request.headers.get(name).cloned();
+        let mut entry = Entry {
+            remote_addr: request.extensions.get::<PeerAddr>().map(|addr| addr.0.ip().to_string()),
+            time,
+            method: request.start_line.request_type.to_string(),
+            target: request.start_line.request_target.raw.clone(),
+            version: request.start_line.http_version.to_string(),
+            status: 0,
+            size: 0,
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+            duration: Duration::ZERO,
+        };
+
+        let response = next.run(request);
+        entry.status = response.status_line.status_code.as_u16();
+        entry.size = response_size(&response);
+        entry.duration = start.elapsed();
+        self.sink.write_line(&entry.format(self.format));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use fast_web_server_types::{FileBody, Handler, StatusCode};
+
+    use super::*;
+    use crate::middleware::tests::{request, run};
+    use crate::test_util::TempDir;
+
+    fn handler(_request: HttpRequest) -> &'static str {
+        "hello"
+    }
+
+    fn log(format: LogFormat, raw: &str) -> String {
+        log_response(format, raw, handler)
+    }
+
+    fn log_response(format: LogFormat, raw: &str, handler: impl Handler) -> String {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let sink = {
+            let lines = lines.clone();
+            move |line: &str| lines.lock().unwrap().push(line.to_string())
+        };
+        let mut request = request(raw);
+        request.extensions.insert(PeerAddr("127.0.0.1:4000".parse::<SocketAddr>().unwrap()));
+        run(AccessLog::new(format).sink(sink), request, handler);
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        lines[0].clone()
+    }
+
+    #[test]
+    fn test_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_185_536);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:45:36 +0000");
+        assert_eq!(rfc3339_time(time), "2000-10-10T13:45:36Z");
+        assert_eq!(rfc3339_time(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn test_common_and_combined() {
+        let raw = "GET /users?id=1 HTTP/1.1\r\nReferer: https://example.com/\r\nUser-Agent: curl/8.0 \"quoted\"\r\n\r\n";
+
+        let line = log(LogFormat::Common, raw);
+        assert!(line.starts_with("127.0.0.1 - - ["));
+        assert!(line.ends_with("] \"GET /users?id=1 HTTP/1.1\" 200 5"));
+
+        let line = log(LogFormat::Combined, raw);
+        assert!(line.ends_with("\"GET /users?id=1 HTTP/1.1\" 200 5 \"https://example.com/\" \"curl/8.0 \\\"quoted\\\"\""));
+    }
+
+    #[test]
+    fn test_json() {
+        let line = log(LogFormat::Json, "POST /greet HTTP/1.0\r\n\r\n");
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["remote_addr"], "127.0.0.1");
+        assert_eq!(value["method"], "POST");
+        assert_eq!(value["target"], "/greet");
+        assert_eq!(value["version"], "HTTP/1.0");
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["size"], 5);
+        assert!(value["referer"].is_null());
+        assert!(value["duration_ms"].is_number());
+    }
+
+    #[test]
+    fn test_body_sizes() {
+        let dir = TempDir::new("access-log-size");
+        let path = dir.file("file", "0123456789");
+        let file = move |_request: HttpRequest| HttpResponse::from_file(FileBody::open(&path).unwrap());
+        assert!(log_response(LogFormat::Common, "GET / HTTP/1.1\r\n\r\n", file).ends_with("\" 200 10"));
+        let empty = |_request: HttpRequest| HttpResponse::with_status(StatusCode::Code204, "");
+        assert!(log_response(LogFormat::Common, "GET / HTTP/1.1\r\n\r\n", empty).ends_with("\" 204 -"));
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = TempDir::new("access-log");
+        let path = dir.join("access.log");
+        let sink = RotatingFile::new(&path, 10).unwrap().max_files(2);
+        for line in ["first", "second", "third", "fourth"] {
+            sink.write_line(line);
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(sink.rotated(1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(sink.rotated(2)).unwrap(), "second\n");
+        assert!(!sink.rotated(3).exists());
+    }
+}
@@ -0,0 +1,484 @@
+use std::io::{self, Write};
+#[cfg(any(feature = "gzip", feature = "deflate"))]
+use flate2::Compression as Level;
+#[cfg(feature = "deflate")]
+use flate2::write::ZlibEncoder;
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+use fast_web_server_types::{BodyStream, Chunk, ETag, HttpRequest, HttpResponse, RequestType, StreamingBody};
+
+use super::{Middleware, Next};
+
+
+/// A content coding the server can compress responses and decode request
+/// bodies with. Each is behind the cargo feature of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[cfg(feature = "br")]
+    Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// The zlib format, which HTTP calls `deflate`, RFC 9110 section 8.4.1.2.
+    #[cfg(feature = "deflate")]
+    Deflate,
+}
+
+impl Encoding {
+    /// The enabled codings, in the server's default order of preference.
+    pub const ALL: &'static [Encoding] = &[
+        #[cfg(feature = "br")]
+        Encoding::Brotli,
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd,
+        #[cfg(feature = "gzip")]
+        Encoding::Gzip,
+        #[cfg(feature = "deflate")]
+        Encoding::Deflate,
+    ];
+
+    /// The coding's name in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "br")]
+            Encoding::Brotli => "br",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => "gzip",
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Parses a coding name, including the `x-gzip` alias.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim();
+        #[cfg(feature = "gzip")]
+        if name.eq_ignore_ascii_case("x-gzip") {
+            return Some(Encoding::Gzip);
+        }
+        Self::ALL.iter().copied().find(|encoding| encoding.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Compresses response bodies with the best coding the client accepts.
+///
+/// The coding is negotiated from `Accept-Encoding`, honouring q-values and
+/// `*`; among equally preferred codings the first of `encodings` wins.
+/// Compressed responses get `Content-Encoding` and their strong `ETag`
+/// weakened, and every response that could have been compressed gets
+/// `Vary: Accept-Encoding`, so caches keep the variants apart.
+///
+/// In-memory bodies are compressed if they are at least `min_size` bytes
+/// and get smaller. Streamed bodies are compressed chunk by chunk, each
+/// flushed so events reach the client without delay. File bodies, partial
+/// content, `HEAD` requests and responses marked `Cache-Control:
+/// no-transform` are sent as they are.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: usize,
+    encodings: Vec<Encoding>,
+    content_types: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            min_size: 1024,
+            encodings: Encoding::ALL.to_vec(),
+            content_types: [
+                "text/", "application/json", "application/javascript", "application/xml",
+                "application/wasm", "image/svg+xml", "+json", "+xml",
+            ].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bodies smaller than `min_size` bytes are sent uncompressed.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// The codings to offer, most preferred first.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// The content types worth compressing. Entries ending in `/` match a
+    /// whole top-level type, entries starting with `+` a structured syntax
+    /// suffix like `application/ld+json`. Responses without a
+    /// `Content-Type` are always eligible.
+    pub fn content_types(mut self, content_types: &[&str]) -> Self {
+        self.content_types = content_types.iter().map(|content_type| content_type.to_ascii_lowercase()).collect();
+        self
+    }
+
+    fn is_eligible(&self, response: &HttpResponse) -> bool {
+        let status = response.status_line.status_code.as_u16();
+        let no_transform = response.headers.get("Cache-Control")
+            .is_some_and(|cache_control| cache_control.split(',').any(|directive| directive.trim().eq_ignore_ascii_case("no-transform")));
+        let content_type = response.headers.get("Content-Type").map(|content_type| {
+            content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+        });
+        let compressible = content_type.is_none_or(|content_type| self.content_types.iter().any(|eligible| {
+            match (eligible.ends_with('/'), eligible.starts_with('+')) {
+                (true, _) => content_type.starts_with(eligible.as_str()),
+                (_, true) => content_type.ends_with(eligible.as_str()),
+                _ => content_type == *eligible,
+            }
+        }));
+        // 206 bodies are slices of the uncompressed representation.
+        !matches!(status, 100..=199 | 204 | 206 | 304)
+            && response.file.is_none()
+            && response.headers.get("Content-Encoding").is_none()
+            && response.headers.get("Content-Range").is_none()
+            && !no_transform
+            && compressible
+            && (response.stream.is_some() || response.body.len() >= self.min_size)
+    }
+
+    /// The coding with the highest q-value in `Accept-Encoding`, RFC 9110
+    /// section 12.5.3. Without the header the body is sent as it is.
+    fn negotiate(&self, accept: &str) -> Option<Encoding> {
+        let codings: Vec<(&str, f32)> = accept.split(',').filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim();
+            let q = params.find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                name.trim().eq_ignore_ascii_case("q").then(|| value.trim().parse::<f32>().unwrap_or(0.0))
+            });
+            (!coding.is_empty()).then_some((coding, q.unwrap_or(1.0)))
+        }).collect();
+        let q = |encoding: Encoding| {
+            let listed = codings.iter().find(|(coding, _)| Encoding::from_name(coding) == Some(encoding));
+            listed.or_else(|| codings.iter().find(|(coding, _)| *coding == "*")).map_or(0.0, |&(_, q)| q)
+        };
+        let mut best: Option<(Encoding, f32)> = None;
+        for &encoding in &self.encodings {
+            let q = q(encoding);
+            if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: HttpRequest, next: Next) -> HttpResponse {
+        let head = request.start_line.request_type == RequestType::HEAD;
+        let accept = request.headers.get("Accept-Encoding").cloned();
+        let mut response = next.run(request);
+        if head || !self.is_eligible(&response) {
+            return response;
+        }
+        response.add_vary("Accept-Encoding");
+        let encoding = match accept.and_then(|accept| self.negotiate(&accept)) {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+
+        if let Some(stream) = response.stream.take() {
+            match Encoder::new(encoding) {
+                Ok(encoder) => {
+                    response.stream = Some(StreamingBody::new(CompressedStream { stream, encoder: Some(encoder) }));
+                    response.headers.remove("Content-Length");
+                },
+                Err(_) => {
+                    response.stream = Some(stream);
+                    return response;
+                },
+            }
+        } else {
+            let compressed = match Encoder::new(encoding).and_then(|mut encoder| {
+                encoder.write_all(&response.body)?;
+                encoder.finish()
+            }) {
+                Ok(compressed) if compressed.len() < response.body.len() => compressed,
+                _ => return response,
+            };
+            response.set_body(compressed);
+        }
+        response.headers.insert("Content-Encoding".to_string(), encoding.as_str().to_string());
+        // The compressed bytes differ from the ones the tag was computed for.
+        if let Some(etag) = response.etag().filter(|etag| !etag.is_weak()) {
+            response.set_etag(&ETag::weak(etag.tag()));
+        }
+        response
+    }
+}
+
+/// A compressor writing into a buffer that is drained as output is produced.
+enum Encoder {
+    #[cfg(feature = "br")]
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<Vec<u8>>),
+    #[cfg(feature = "deflate")]
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    /// Levels trade ratio for speed, as bodies are compressed per response.
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        match encoding {
+            #[cfg(feature = "br")]
+            Encoding::Brotli => Ok(Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22)))),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Ok(Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?)),
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => Ok(Encoder::Gzip(GzEncoder::new(Vec::new(), Level::default()))),
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => Ok(Encoder::Deflate(ZlibEncoder::new(Vec::new(), Level::default()))),
+        }
+    }
+
+    /// Takes the compressed output produced so far.
+    fn take_output(&mut self) -> Vec<u8> {
+        match *self {
+            #[cfg(feature = "br")]
+            Encoder::Brotli(ref mut encoder) => std::mem::take(encoder.get_mut()),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(ref mut encoder) => std::mem::take(encoder.get_mut()),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(ref mut encoder) => std::mem::take(encoder.get_mut()),
+            #[cfg(feature = "deflate")]
+            Encoder::Deflate(ref mut encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    /// Ends the compressed stream, returning the remaining output.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "br")]
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.finish(),
+            #[cfg(feature = "deflate")]
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            #[cfg(feature = "br")]
+            Encoder::Brotli(ref mut encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(ref mut encoder) => encoder.write(buf),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(ref mut encoder) => encoder.write(buf),
+            #[cfg(feature = "deflate")]
+            Encoder::Deflate(ref mut encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            #[cfg(feature = "br")]
+            Encoder::Brotli(ref mut encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(ref mut encoder) => encoder.flush(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(ref mut encoder) => encoder.flush(),
+            #[cfg(feature = "deflate")]
+            Encoder::Deflate(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// A streamed body compressed as it is polled. `None` once the compressed
+/// stream has ended.
+struct CompressedStream {
+    stream: StreamingBody,
+    encoder: Option<Encoder>,
+}
+
+impl BodyStream for CompressedStream {
+    fn poll_chunk(&mut self) -> Chunk {
+        loop {
+            let Some(encoder) = &mut self.encoder else {
+                return Chunk::End;
+            };
+            match self.stream.poll_chunk() {
+                Chunk::Data(data) => {
+                    // A sync flush per chunk, so the client can decode it on arrival.
+                    if encoder.write_all(&data).and_then(|_| encoder.flush()).is_err() {
+                        self.encoder = None;
+                        return Chunk::End;
+                    }
+                    let output = encoder.take_output();
+                    if !output.is_empty() {
+                        return Chunk::Data(output);
+                    }
+                },
+                Chunk::Pending => return Chunk::Pending,
+                Chunk::End => {
+                    return match self.encoder.take().map(Encoder::finish) {
+                        Some(Ok(output)) if !output.is_empty() => Chunk::Data(output),
+                        _ => Chunk::End,
+                    };
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::tests::{request, run};
+
+    fn handler(_request: HttpRequest) -> Vec<u8> {
+        vec![b'a'; 4096]
+    }
+
+    fn decompress(encoding: &str, body: &[u8]) -> Vec<u8> {
+        let mut decompressed = vec![];
+        match encoding {
+            #[cfg(feature = "br")]
+            "br" => io::copy(&mut brotli::Decompressor::new(body, 4096), &mut decompressed).unwrap(),
+            #[cfg(feature = "zstd")]
+            "zstd" => io::copy(&mut zstd::stream::read::Decoder::new(body).unwrap(), &mut decompressed).unwrap(),
+            #[cfg(feature = "gzip")]
+            "gzip" => io::copy(&mut flate2::read::GzDecoder::new(body), &mut decompressed).unwrap(),
+            #[cfg(feature = "deflate")]
+            "deflate" => io::copy(&mut flate2::read::ZlibDecoder::new(body), &mut decompressed).unwrap(),
+            encoding => panic!("unexpected encoding {}", encoding),
+        };
+        decompressed
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compresses() {
+        let response = run(Compression::new(), request("GET / HTTP/1.1\r\nAccept-Encoding: deflate;q=0.5, gzip;q=0.8\r\n\r\n"), handler);
+        assert_eq!(response.headers.get("Content-Encoding"), Some(&"gzip".to_string()));
+        assert_eq!(response.headers.get("Content-Length"), Some(&response.body.len().to_string()));
+        assert_eq!(response.headers.get("Vary"), Some(&"Accept-Encoding".to_string()));
+        assert_eq!(decompress("gzip", &response.body), vec![b'a'; 4096]);
+    }
+
+    #[test]
+    fn test_every_encoding() {
+        for encoding in Encoding::ALL {
+            let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", encoding.as_str());
+            let response = run(Compression::new(), request(&raw), handler);
+            assert_eq!(response.headers.get("Content-Encoding").map(String::as_str), Some(encoding.as_str()));
+            assert_eq!(decompress(encoding.as_str(), &response.body), vec![b'a'; 4096]);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "br", feature = "zstd", feature = "gzip", feature = "deflate"))]
+    fn test_negotiate() {
+        let compression = Compression::new();
+        assert_eq!(compression.negotiate("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(compression.negotiate("gzip, br;q=0.9"), Some(Encoding::Gzip));
+        assert_eq!(compression.negotiate("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(compression.negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(compression.negotiate("*;q=0.5, br;q=0, zstd;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(compression.negotiate("identity, gzip;q=0"), None);
+        assert_eq!(compression.negotiate("compress"), None);
+        assert_eq!(compression.negotiate(""), None);
+        assert_eq!(compression.encodings(&[Encoding::Zstd, Encoding::Gzip]).negotiate("gzip, br, zstd"), Some(Encoding::Zstd));
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_skips() {
+        let response = run(Compression::new(), request("GET / HTTP/1.1\r\n\r\n"), handler);
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.headers.get("Vary"), Some(&"Accept-Encoding".to_string()));
+
+        let response = run(Compression::new().min_size(8192), request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"), handler);
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.body.len(), 4096);
+
+        let with_header = |name: &'static str, value: &'static str| move |_request: HttpRequest| {
+            let mut response = HttpResponse::from_body(vec![b'a'; 4096]);
+            response.headers.insert(name.to_string(), value.to_string());
+            response
+        };
+        let gzip = || request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        for (name, value) in [("Content-Type", "image/png"), ("Cache-Control", "public, no-transform"), ("Content-Range", "bytes 0-4095/8192")] {
+            let response = run(Compression::new(), gzip(), with_header(name, value));
+            assert_eq!(response.headers.get("Content-Encoding"), None, "{}: {}", name, value);
+            assert_eq!(response.body.len(), 4096);
+        }
+        for content_type in ["text/html; charset=utf-8", "application/problem+json", "Application/JSON"] {
+            let response = run(Compression::new(), gzip(), with_header("Content-Type", content_type));
+            assert_eq!(response.headers.get("Content-Encoding"), Some(&"gzip".to_string()), "{}", content_type);
+        }
+        let response = run(Compression::new(), request("HEAD / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"), handler);
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_weakens_etag() {
+        let handler = |_request: HttpRequest| {
+            let mut response = HttpResponse::from_body(vec![b'a'; 4096]);
+            response.set_etag(&ETag::strong("v1"));
+            response.add_vary("Origin");
+            response
+        };
+        let response = run(Compression::new(), request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"), handler);
+        assert_eq!(response.etag(), Some(ETag::weak("v1")));
+        assert_eq!(response.headers.get("Vary"), Some(&"Origin, Accept-Encoding".to_string()));
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_streaming() {
+        struct Lines(Vec<&'static str>);
+        impl BodyStream for Lines {
+            fn poll_chunk(&mut self) -> Chunk {
+                match self.0.pop() {
+                    Some("") => Chunk::Pending,
+                    Some(line) => Chunk::Data(line.as_bytes().to_vec()),
+                    None => Chunk::End,
+                }
+            }
+        }
+        let handler = |_request: HttpRequest| {
+            let mut response = HttpResponse::streaming(StreamingBody::new(Lines(vec!["data: 3\n\n", "", "data: 2\n\n", "data: 1\n\n"])));
+            response.headers.insert("Content-Type".to_string(), "text/event-stream".to_string());
+            response
+        };
+        let mut response = run(Compression::new(), request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"), handler);
+        assert_eq!(response.headers.get("Content-Encoding"), Some(&"gzip".to_string()));
+        let mut stream = response.stream.take().unwrap();
+
+        // Each chunk decodes on its own, without waiting for the end of the stream.
+        let mut compressed = vec![];
+        let mut decoder = flate2::write::GzDecoder::new(vec![]);
+        for expected in ["data: 1\n\n", "data: 2\n\n"] {
+            let Chunk::Data(chunk) = stream.poll_chunk() else { panic!("expected data") };
+            decoder.write_all(&chunk).unwrap();
+            decoder.flush().unwrap();
+            assert_eq!(std::mem::take(decoder.get_mut()), expected.as_bytes());
+            compressed.extend(chunk);
+        }
+        assert_eq!(stream.poll_chunk(), Chunk::Pending);
+        while let Chunk::Data(chunk) = stream.poll_chunk() {
+            compressed.extend(chunk);
+        }
+        assert_eq!(stream.poll_chunk(), Chunk::End);
+        assert_eq!(decompress("gzip", &compressed), b"data: 1\n\ndata: 2\n\ndata: 3\n\n");
+    }
+}
@@ -0,0 +1,146 @@
+use fast_web_server_types::{ETag, HttpRequest, HttpResponse, Precondition, Preconditions, RequestType, StatusCode};
+
+use super::{Middleware, Next};
+
+
+/// Evaluates `If-Match`, `If-None-Match`, `If-Modified-Since` and
+/// `If-Unmodified-Since` of `GET` and `HEAD` requests against the `ETag`
+/// and `Last-Modified` of the handler's response, answering
+/// `304 Not Modified` or `412 Precondition Failed` instead of sending it.
+///
+/// `200 OK` responses to `GET` with an in-memory body and no `ETag` get one
+/// computed from the body. File and streamed bodies are never read, so a
+/// `304` or `412` drops them unsent. Handlers that can tell the validators
+/// up front should evaluate `Preconditions` themselves to skip producing
+/// the body entirely, as `StaticFiles` does; state-changing requests are
+/// left to them, since the change would already be applied here.
+#[derive(Debug, Clone)]
+pub struct Conditional {
+    compute_etags: bool,
+    weak_etags: bool,
+}
+
+impl Default for Conditional {
+    fn default() -> Self {
+        Self { compute_etags: true, weak_etags: false }
+    }
+}
+
+impl Conditional {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to compute an `ETag` for responses without one.
+    pub fn compute_etags(mut self, compute_etags: bool) -> Self {
+        self.compute_etags = compute_etags;
+        self
+    }
+
+    /// Computes weak `ETag`s, for bodies that middlewares closer to the
+    /// client may still transform, like `Compression`.
+    pub fn weak_etags(mut self, weak_etags: bool) -> Self {
+        self.weak_etags = weak_etags;
+        self
+    }
+}
+
+impl Middleware for Conditional {
+    fn handle(&self, request: HttpRequest, next: Next) -> HttpResponse {
+        let request_type = request.start_line.request_type.clone();
+        if !matches!(request_type, RequestType::GET | RequestType::HEAD) {
+            return next.run(request);
+        }
+        let preconditions = Preconditions::for_request(&request);
+        let mut response = next.run(request);
+        let status = response.status_line.status_code.as_u16();
+        // HEAD responses carry no body to compute a tag from.
+        let computable = request_type == RequestType::GET && status == 200
+            && response.stream.is_none() && response.file.is_none();
+        if self.compute_etags && computable && response.headers.get("ETag").is_none() {
+            let etag = ETag::from_body(&response.body);
+            response.set_etag(&match self.weak_etags {
+                true => ETag::weak(etag.tag()),
+                false => etag,
+            });
+        }
+        // Preconditions only apply to successful responses, RFC 9110 section 13.2.1.
+        if preconditions.is_empty() || !(200..300).contains(&status) {
+            return response;
+        }
+        match preconditions.evaluate(response.etag().as_ref(), response.last_modified()) {
+            Precondition::Passed => response,
+            Precondition::NotModified => response.into_not_modified(),
+            Precondition::Failed => HttpResponse::json_error(StatusCode::Code412, "precondition_failed", None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::middleware::tests::{request, run};
+
+    fn handler(_request: HttpRequest) -> &'static str {
+        "hello"
+    }
+
+    #[test]
+    fn test_computed_etag() {
+        let response = run(Conditional::new(), request("GET / HTTP/1.1\r\n\r\n"), handler);
+        let etag = response.etag().unwrap();
+        assert_eq!(etag, ETag::from_body(b"hello"));
+
+        let response = run(Conditional::new(), request(&format!("GET / HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag)), handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code304);
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers.get("Content-Length"), None);
+        assert_eq!(response.etag(), Some(etag.clone()));
+
+        let response = run(Conditional::new(), request(&format!("GET / HTTP/1.1\r\nIf-Match: {}\r\n\r\n", etag)), handler);
+        assert_eq!(response.body, b"hello");
+        let response = run(Conditional::new(), request("GET / HTTP/1.1\r\nIf-Match: \"other\"\r\n\r\n"), handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code412);
+
+        // A weak tag never matches If-Match.
+        let response = run(Conditional::new().weak_etags(true), request(&format!("GET / HTTP/1.1\r\nIf-Match: {}\r\n\r\n", etag)), handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code412);
+        let response = run(Conditional::new().compute_etags(false), request("GET / HTTP/1.1\r\n\r\n"), handler);
+        assert_eq!(response.headers.get("ETag"), None);
+    }
+
+    #[test]
+    fn test_handler_validators() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let handler = move |_request: HttpRequest| {
+            let mut response = HttpResponse::from_body("hello");
+            response.set_last_modified(modified);
+            response.set_etag(&ETag::strong("v1"));
+            response
+        };
+        let since = |header: &str, time| format!("GET / HTTP/1.1\r\n{}: {}\r\n\r\n", header, httpdate::fmt_http_date(time));
+
+        let response = run(Conditional::new(), request(&since("If-Modified-Since", modified)), handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code304);
+        assert_eq!(response.headers.get("Last-Modified"), Some(&httpdate::fmt_http_date(modified)));
+        let response = run(Conditional::new(), request(&since("If-Modified-Since", modified - Duration::from_secs(1))), handler);
+        assert_eq!(response.body, b"hello");
+        let response = run(Conditional::new(), request(&since("If-Unmodified-Since", modified - Duration::from_secs(1))), handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code412);
+    }
+
+    #[test]
+    fn test_skips() {
+        // Errors and state-changing requests are passed through.
+        let not_found = |_request: HttpRequest| HttpResponse::json_error(StatusCode::Code404, "not_found", None);
+        let response = run(Conditional::new(), request("GET / HTTP/1.1\r\nIf-Match: \"v1\"\r\n\r\n"), not_found);
+        assert_eq!(response.status_line.status_code, StatusCode::Code404);
+        assert_eq!(response.headers.get("ETag"), None);
+
+        let response = run(Conditional::new(), request("PUT / HTTP/1.1\r\nIf-Match: \"v1\"\r\nContent-Length: 0\r\n\r\n"), handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code200);
+        assert_eq!(response.headers.get("ETag"), None);
+    }
+}
@@ -0,0 +1,190 @@
+use fast_web_server_types::{HttpRequest, HttpResponse, RequestType, StatusCode};
+
+use super::{Middleware, Next};
+
+
+/// Cross-Origin Resource Sharing. Answers preflight `OPTIONS` requests
+/// itself and adds `Access-Control-Allow-Origin` to allowed responses.
+/// Register it on the server rather than on a group, so preflights for
+/// routes without an `OPTIONS` handler are answered too.
+#[derive(Debug, Clone)]
+pub struct Cors {
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<RequestType>,
+    allowed_headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<u32>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: vec![RequestType::GET, RequestType::HEAD, RequestType::POST],
+            allowed_headers: vec![],
+            exposed_headers: vec![],
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    /// Allows any origin with `GET`, `HEAD` and `POST`, and no request
+    /// headers beyond the CORS-safelisted ones.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restricts requests to the given origin; can be called several times.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.allowed_origins.push(origin.to_string());
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: &[RequestType]) -> Self {
+        self.allowed_methods = methods.to_vec();
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        self.allowed_headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.exposed_headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Lets browsers send cookies and credentials. Only origins added with
+    /// `allow_origin` are then allowed: without any, every origin is refused
+    /// rather than allowing credentialed reads from any site.
+    pub fn allow_credentials(mut self, allow: bool) -> Self {
+        self.allow_credentials = allow;
+        self
+    }
+
+    pub fn max_age(mut self, seconds: u32) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    fn is_allowed(&self, origin: &str) -> bool {
+        match self.allowed_origins.is_empty() {
+            true => !self.allow_credentials,
+            false => self.allowed_origins.iter().any(|o| o == origin),
+        }
+    }
+
+    fn allow_origin_header(&self, response: &mut HttpResponse, origin: &str) {
+        if self.allowed_origins.is_empty() && !self.allow_credentials {
+            response.headers.insert("Access-Control-Allow-Origin".to_string(), "*".to_string());
+        } else {
+            response.headers.insert("Access-Control-Allow-Origin".to_string(), origin.to_string());
+            response.add_vary("Origin");
+        }
+        if self.allow_credentials {
+            response.headers.insert("Access-Control-Allow-Credentials".to_string(), "true".to_string());
+        }
+    }
+
+    /// Answers a preflight for `method`. One for a method that is not
+    /// allowed gets no CORS headers, so the browser fails it.
+    fn preflight(&self, method: &str, origin: &str) -> HttpResponse {
+        let mut response = HttpResponse::with_status(StatusCode::Code204, "");
+        if !self.allowed_methods.iter().any(|allowed| allowed.as_str() == method) {
+            return response;
+        }
+        self.allow_origin_header(&mut response, origin);
+        let methods: Vec<&str> = self.allowed_methods.iter().map(RequestType::as_str).collect();
+        response.headers.insert("Access-Control-Allow-Methods".to_string(), methods.join(", "));
+        if !self.allowed_headers.is_empty() {
+            response.headers.insert("Access-Control-Allow-Headers".to_string(), self.allowed_headers.join(", "));
+        }
+        if let Some(max_age) = self.max_age {
+            response.headers.insert("Access-Control-Max-Age".to_string(), max_age.to_string());
+        }
+        response
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: HttpRequest, next: Next) -> HttpResponse {
+        let origin = match request.headers.get("Origin") {
+            Some(origin) if self.is_allowed(origin) => origin.to_string(),
+            _ => return next.run(request),
+        };
+        if request.start_line.request_type == RequestType::OPTIONS {
+            if let Some(method) = request.headers.get("Access-Control-Request-Method") {
+                return self.preflight(method, &origin);
+            }
+        }
+
+        let mut response = next.run(request);
+        self.allow_origin_header(&mut response, &origin);
+        if !self.exposed_headers.is_empty() {
+            response.headers.insert("Access-Control-Expose-Headers".to_string(), self.exposed_headers.join(", "));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::tests::{request, run};
+
+    fn handler(_request: HttpRequest) -> &'static str {
+        "ok"
+    }
+
+    fn preflight(method: &str) -> HttpRequest {
+        let raw = format!("OPTIONS /users HTTP/1.1\r\nOrigin: https://example.com\r\nAccess-Control-Request-Method: {}\r\nAccess-Control-Request-Headers: content-type\r\n\r\n", method);
+        request(&raw)
+    }
+
+    #[test]
+    fn test_preflight() {
+        let cors = || Cors::new().allow_methods(&[RequestType::GET, RequestType::PUT]).max_age(600);
+        let response = run(cors().allow_headers(&["content-type"]), preflight("PUT"), handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code204);
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some(&"*".to_string()));
+        assert_eq!(response.headers.get("Access-Control-Allow-Methods"), Some(&"GET, PUT".to_string()));
+        assert_eq!(response.headers.get("Access-Control-Allow-Headers"), Some(&"content-type".to_string()));
+        assert_eq!(response.headers.get("Access-Control-Max-Age"), Some(&"600".to_string()));
+
+        // Requested headers are not echoed without an allow-list.
+        let response = run(cors(), preflight("PUT"), handler);
+        assert_eq!(response.headers.get("Access-Control-Allow-Headers"), None);
+
+        let response = run(cors(), preflight("DELETE"), handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code204);
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.headers.get("Access-Control-Allow-Methods"), None);
+    }
+
+    #[test]
+    fn test_credentials_need_origins() {
+        let response = run(Cors::new().allow_credentials(true), request("GET / HTTP/1.1\r\nOrigin: https://evil.com\r\n\r\n"), handler);
+        assert_eq!(response.body, b"ok");
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.headers.get("Access-Control-Allow-Credentials"), None);
+    }
+
+    #[test]
+    fn test_simple_request() {
+        let cors = || Cors::new().allow_origin("https://example.com").allow_credentials(true);
+
+        let response = run(cors(), request("GET / HTTP/1.1\r\nOrigin: https://example.com\r\n\r\n"), handler);
+        assert_eq!(response.body, b"ok");
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some(&"https://example.com".to_string()));
+        assert_eq!(response.headers.get("Access-Control-Allow-Credentials"), Some(&"true".to_string()));
+        assert_eq!(response.headers.get("Vary"), Some(&"Origin".to_string()));
+
+        let response = run(cors(), request("GET / HTTP/1.1\r\nOrigin: https://evil.com\r\n\r\n"), handler);
+        assert_eq!(response.body, b"ok");
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), None);
+    }
+}
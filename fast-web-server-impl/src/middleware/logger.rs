@@ -0,0 +1,23 @@
+use std::time::Instant;
+use fast_web_server_types::{HttpRequest, HttpResponse};
+use tracing::info;
+
+use super::{Middleware, Next};
+
+
+/// Emits an `info` event per request with its method, target, status and
+/// handling time.
+#[derive(Debug, Default, Clone)]
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle(&self, request: HttpRequest, next: Next) -> HttpResponse {
+        let start = Instant::now();
+        let request_type = request.start_line.request_type.clone();
+        let uri = request.start_line.request_target.uri.clone();
+        let response = next.run(request);
+        let status = response.status_line.status_code.as_u16();
+        info!(method = %request_type, %uri, status, elapsed = ?start.elapsed(), "request");
+        response
+    }
+}
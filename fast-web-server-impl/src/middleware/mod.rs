@@ -0,0 +1,115 @@
+mod access_log;
+mod auth;
+mod compression;
+mod conditional;
+mod cors;
+mod logger;
+mod ranges;
+
+use std::sync::Arc;
+use fast_web_server_types::{Handler, HttpRequest, HttpResponse};
+
+pub use self::access_log::{AccessLog, LogFormat, LogSink, RotatingFile, Stdout};
+pub use self::auth::Auth;
+pub use self::compression::{Compression, Encoding};
+pub use self::conditional::Conditional;
+pub use self::cors::Cors;
+pub use self::logger::Logger;
+pub use self::ranges::Ranges;
+
+
+/// Code that runs around route handlers. A middleware can inspect or modify
+/// the request, short-circuit by returning a response without calling
+/// `next.run`, and transform the response returned by `next.run`.
+///
+/// Middlewares registered on the server run first, in registration order,
+/// followed by the middlewares of the route group the handler was bound in.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: HttpRequest, next: Next) -> HttpResponse;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(HttpRequest, Next) -> HttpResponse + Send + Sync,
+{
+    fn handle(&self, request: HttpRequest, next: Next) -> HttpResponse {
+        self(request, next)
+    }
+}
+
+/// The rest of the pipeline: the remaining server and group middlewares,
+/// followed by the route handler.
+pub struct Next<'a> {
+    global: &'a [Arc<dyn Middleware>],
+    group: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(global: &'a [Arc<dyn Middleware>], group: &'a [Arc<dyn Middleware>], handler: &'a dyn Handler) -> Self {
+        Self { global, group, handler }
+    }
+
+    pub fn run(self, request: HttpRequest) -> HttpResponse {
+        if let Some((middleware, global)) = self.global.split_first() {
+            middleware.handle(request, Next { global, ..self })
+        } else if let Some((middleware, group)) = self.group.split_first() {
+            middleware.handle(request, Next { group, ..self })
+        } else {
+            self.handler.call(request)
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    pub(crate) fn request(raw: &str) -> HttpRequest {
+        HttpRequest::new(&mut Cursor::new(raw.as_bytes())).unwrap()
+    }
+
+    pub(crate) fn run(middleware: impl Middleware + 'static, request: HttpRequest, handler: impl Handler) -> HttpResponse {
+        let middlewares: Vec<Arc<dyn Middleware>> = vec![Arc::new(middleware)];
+        Next::new(&middlewares, &[], &handler).run(request)
+    }
+
+    #[test]
+    fn test_ordering() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let tracker = |name: &'static str| {
+            let calls = calls.clone();
+            Arc::new(move |request: HttpRequest, next: Next| {
+                calls.lock().unwrap().push(format!("before {}", name));
+                let response = next.run(request);
+                calls.lock().unwrap().push(format!("after {}", name));
+                response
+            }) as Arc<dyn Middleware>
+        };
+        let global = vec![tracker("global 1"), tracker("global 2")];
+        let group = vec![tracker("group")];
+        let handler_calls = calls.clone();
+        let handler = move |_request: HttpRequest| {
+            handler_calls.lock().unwrap().push("handler".to_string());
+            "ok"
+        };
+
+        let response = Next::new(&global, &group, &handler).run(request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.body, b"ok");
+        assert_eq!(*calls.lock().unwrap(), vec![
+            "before global 1", "before global 2", "before group", "handler", "after group", "after global 2", "after global 1",
+        ]);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let middleware = |_request: HttpRequest, _next: Next| HttpResponse::from_body("blocked");
+        let response = run(middleware, request("GET / HTTP/1.1\r\n\r\n"), |_request: HttpRequest| -> &'static str {
+            panic!("handler must not run")
+        });
+        assert_eq!(response.body, b"blocked");
+    }
+}
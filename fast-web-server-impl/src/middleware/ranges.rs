@@ -0,0 +1,315 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use fast_web_server_types::{ETag, FileBody, HttpRequest, HttpResponse, RequestType, StatusCode};
+use tracing::debug;
+
+use super::{Middleware, Next};
+
+
+/// Distinguishes the boundaries of responses generated in the same instant.
+static BOUNDARY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Most bytes of a file read into memory for a `multipart/byteranges`
+/// body. Requests for more get the whole file, which is sent from disk.
+const MAX_BUFFERED_PARTS: u64 = 256 * 1024;
+
+/// Answers `GET` requests with a `Range` header with `206 Partial Content`,
+/// for in-memory and file bodies of `200 OK` responses. Several ranges are
+/// sent as `multipart/byteranges`, unless they would buffer more than 256
+/// KiB of a file body; ranges that all lie past the end of the body are
+/// answered with `416 Range Not Satisfiable`.
+///
+/// Responses it can serve ranges of are marked with `Accept-Ranges: bytes`.
+/// With an `If-Range` header, the range is only served if the response's
+/// strong `ETag` or `Last-Modified` date still matches; otherwise the whole
+/// body is sent. Streamed bodies are left alone.
+#[derive(Debug, Clone)]
+pub struct Ranges {
+    max_ranges: usize,
+}
+
+impl Default for Ranges {
+    fn default() -> Self {
+        Self { max_ranges: 16 }
+    }
+}
+
+impl Ranges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests for more ranges than this, after overlapping ones are
+    /// merged, get the whole body instead.
+    pub fn max_ranges(mut self, max_ranges: usize) -> Self {
+        self.max_ranges = max_ranges;
+        self
+    }
+}
+
+impl Middleware for Ranges {
+    fn handle(&self, request: HttpRequest, next: Next) -> HttpResponse {
+        // Range requests are only defined for GET, RFC 9110 section 14.2.
+        let range = match request.start_line.request_type {
+            RequestType::GET => request.headers.get("Range").cloned(),
+            _ => None,
+        };
+        let if_range = request.headers.get("If-Range").cloned();
+        let mut response = next.run(request);
+        if response.status_line.status_code != StatusCode::Code200 || response.stream.is_some() {
+            return response;
+        }
+        response.headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
+        let range = match range {
+            Some(range) if if_range.as_deref().is_none_or(|if_range| if_range_matches(&response, if_range)) => range,
+            _ => return response,
+        };
+        let len = match &response.file {
+            Some(file) => file.len(),
+            None => response.body.len() as u64,
+        };
+        // A malformed header or a unit other than bytes is ignored.
+        let ranges = match parse_ranges(&range, len) {
+            Some(ranges) => ranges,
+            None => return response,
+        };
+        match ranges.len() {
+            0 => not_satisfiable(len),
+            1 => single_range(response, ranges[0], len),
+            count if count > self.max_ranges => response,
+            _ if response.file.is_some() && ranges.iter().map(|(first, last)| last - first + 1).sum::<u64>() > MAX_BUFFERED_PARTS => response,
+            _ => {
+                if let Err(e) = multiple_ranges(&mut response, &ranges, len) {
+                    debug!(error = %e, "could not read ranges, sending the whole body");
+                }
+                response
+            },
+        }
+    }
+}
+
+/// Whether an `If-Range` validator matches the response, RFC 9110 section
+/// 13.1.5. Entity tags must match strongly and dates exactly.
+fn if_range_matches(response: &HttpResponse, if_range: &str) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return match (response.etag(), ETag::parse(if_range)) {
+            (Some(etag), Some(if_range)) => etag.strong_eq(&if_range),
+            _ => false,
+        };
+    }
+    match (response.last_modified(), httpdate::parse_http_date(if_range)) {
+        (Some(modified), Ok(date)) => modified == date,
+        _ => false,
+    }
+}
+
+/// The satisfiable ranges of a `Range: bytes=...` header as inclusive
+/// `(first, last)` offsets, sorted with overlapping and adjacent ranges
+/// merged. `None` if the header is malformed.
+fn parse_ranges(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let mut ranges = vec![];
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first.trim(), last.trim()) {
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+            },
+            (first, "") => {
+                let first: u64 = first.parse().ok()?;
+                (first < len).then(|| (first, len - 1))
+            },
+            (first, last) => {
+                let (first, last): (u64, u64) = (first.parse().ok()?, last.parse().ok()?);
+                if last < first {
+                    return None;
+                }
+                (first < len).then(|| (first, last.min(len - 1)))
+            },
+        };
+        ranges.extend(range);
+    }
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(previous) if first <= previous.1.saturating_add(1) => previous.1 = previous.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    Some(merged)
+}
+
+fn not_satisfiable(len: u64) -> HttpResponse {
+    let mut response = HttpResponse::json_error(StatusCode::Code416, "range_not_satisfiable", None);
+    response.headers.insert("Content-Range".to_string(), format!("bytes */{}", len));
+    response.headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
+    response
+}
+
+fn single_range(mut response: HttpResponse, (first, last): (u64, u64), len: u64) -> HttpResponse {
+    let range_len = last - first + 1;
+    match response.file.take() {
+        Some(file) => response.file = Some(file.slice(first, range_len)),
+        None => {
+            response.body.truncate(last as usize + 1);
+            response.body.drain(..first as usize);
+        },
+    }
+    response.status_line.status_code = StatusCode::Code206;
+    response.headers.insert("Content-Length".to_string(), range_len.to_string());
+    response.headers.insert("Content-Range".to_string(), format!("bytes {}-{}/{}", first, last, len));
+    response
+}
+
+/// Replaces the body with a `multipart/byteranges` body with one part per
+/// range. File ranges are read into memory, at most `MAX_BUFFERED_PARTS`
+/// bytes; should that fail, the response is left untouched.
+fn multiple_ranges(response: &mut HttpResponse, ranges: &[(u64, u64)], len: u64) -> io::Result<()> {
+    let boundary = boundary();
+    let content_type = response.headers.get("Content-Type").cloned();
+    let mut body = Vec::new();
+    for &(first, last) in ranges {
+        body.extend_from_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+        if let Some(content_type) = &content_type {
+            body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+        }
+        body.extend_from_slice(format!("Content-Range: bytes {}-{}/{}\r\n\r\n", first, last, len).as_bytes());
+        match &response.file {
+            Some(file) => read_range(file, first, last - first + 1, &mut body)?,
+            None => body.extend_from_slice(&response.body[first as usize..=last as usize]),
+        }
+    }
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    response.file = None;
+    response.set_body(body);
+    response.status_line.status_code = StatusCode::Code206;
+    response.headers.insert("Content-Type".to_string(), format!("multipart/byteranges; boundary={}", boundary));
+    Ok(())
+}
+
+fn read_range(file: &FileBody, start: u64, len: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+    let mut reader = file.file();
+    reader.seek(SeekFrom::Start(file.offset() + start))?;
+    match reader.take(len).read_to_end(buf)? as u64 == len {
+        true => Ok(()),
+        false => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while being read")),
+    }
+}
+
+/// A boundary unlikely to occur in the body.
+fn boundary() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    format!("{:016x}{:08x}", nanos, BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::middleware::tests::{request, run};
+    use crate::test_util::TempDir;
+
+    fn handler(_request: HttpRequest) -> HttpResponse {
+        let mut response = HttpResponse::from_body("0123456789");
+        response.headers.insert("Content-Type".to_string(), "text/plain".to_string());
+        response.headers.insert("ETag".to_string(), "\"v1\"".to_string());
+        response.headers.insert("Last-Modified".to_string(), "Sat, 17 Oct 2026 10:00:00 GMT".to_string());
+        response
+    }
+
+    fn get(headers: &str) -> HttpResponse {
+        run(Ranges::new(), request(&format!("GET / HTTP/1.1\r\n{}\r\n", headers)), handler)
+    }
+
+    #[test]
+    fn test_single_range() {
+        let response = get("Range: bytes=2-4\r\n");
+        assert_eq!(response.status_line.status_code, StatusCode::Code206);
+        assert_eq!(response.body, b"234");
+        assert_eq!(response.headers.get("Content-Range").unwrap(), "bytes 2-4/10");
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "3");
+        assert_eq!(get("Range: bytes=7-\r\n").body, b"789");
+        assert_eq!(get("Range: bytes=-3\r\n").body, b"789");
+        assert_eq!(get("Range: bytes=8-100\r\n").headers.get("Content-Range").unwrap(), "bytes 8-9/10");
+        // Overlapping ranges are merged into one.
+        assert_eq!(get("Range: bytes=1-3, 2-5\r\n").body, b"12345");
+    }
+
+    #[test]
+    fn test_multiple_ranges() {
+        let response = get("Range: bytes=0-1, 20-30, 8-\r\n");
+        assert_eq!(response.status_line.status_code, StatusCode::Code206);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let expected = format!("\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+            \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--{b}--\r\n", b = boundary);
+        assert_eq!(String::from_utf8(response.body.clone()).unwrap(), expected);
+        assert_eq!(response.headers.get("Content-Length").unwrap(), &expected.len().to_string());
+
+        // Five disjoint ranges exceed a limit of four, so the whole body is sent.
+        let response = run(Ranges::new().max_ranges(4), request("GET / HTTP/1.1\r\nRange: bytes=0-0,2-2,4-4,6-6,8-8\r\n\r\n"), handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code200);
+        // Adjacent single bytes merge into one range.
+        let adjacent = (0..10).map(|i| format!("{}-{}", i, i)).collect::<Vec<_>>().join(",");
+        assert_eq!(get(&format!("Range: bytes={}\r\n", adjacent)).headers.get("Content-Range").unwrap(), "bytes 0-9/10");
+    }
+
+    #[test]
+    fn test_not_satisfiable_and_ignored() {
+        let response = get("Range: bytes=10-\r\n");
+        assert_eq!(response.status_line.status_code, StatusCode::Code416);
+        assert_eq!(response.headers.get("Content-Range").unwrap(), "bytes */10");
+        for ignored in ["Range: bytes=5-2\r\n", "Range: items=0-1\r\n", "Range: bytes=x-\r\n"] {
+            let response = get(ignored);
+            assert_eq!(response.status_line.status_code, StatusCode::Code200, "{}", ignored);
+            assert_eq!(response.headers.get("Accept-Ranges").unwrap(), "bytes");
+        }
+        let head = run(Ranges::new(), request("HEAD / HTTP/1.1\r\nRange: bytes=0-1\r\n\r\n"), handler);
+        assert_eq!(head.status_line.status_code, StatusCode::Code200);
+        assert_eq!(head.headers.get("Accept-Ranges").unwrap(), "bytes");
+    }
+
+    #[test]
+    fn test_if_range() {
+        assert_eq!(get("Range: bytes=0-1\r\nIf-Range: \"v1\"\r\n").status_line.status_code, StatusCode::Code206);
+        assert_eq!(get("Range: bytes=0-1\r\nIf-Range: \"v0\"\r\n").status_line.status_code, StatusCode::Code200);
+        assert_eq!(get("Range: bytes=0-1\r\nIf-Range: W/\"v1\"\r\n").status_line.status_code, StatusCode::Code200);
+        assert_eq!(get("Range: bytes=0-1\r\nIf-Range: Sat, 17 Oct 2026 10:00:00 GMT\r\n").status_line.status_code, StatusCode::Code206);
+        assert_eq!(get("Range: bytes=0-1\r\nIf-Range: Sat, 17 Oct 2026 09:00:00 GMT\r\n").status_line.status_code, StatusCode::Code200);
+    }
+
+    #[test]
+    fn test_file_ranges() {
+        let dir = TempDir::new("ranges");
+        let path = dir.file("file", "abcdefghij");
+        let file_handler = |_request: HttpRequest| HttpResponse::from_file(FileBody::new(File::open(&path).unwrap()).unwrap());
+
+        let mut response = run(Ranges::new(), request("GET / HTTP/1.1\r\nRange: bytes=3-5\r\n\r\n"), file_handler);
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "3");
+        let file = response.file.take().unwrap();
+        assert_eq!((file.offset(), file.len()), (3, 3));
+
+        let response = run(Ranges::new(), request("GET / HTTP/1.1\r\nRange: bytes=0-0,-2\r\n\r\n"), file_handler);
+        assert!(response.file.is_none());
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("bytes 0-0/10\r\n\r\na\r\n") && body.contains("bytes 8-9/10\r\n\r\nij\r\n"));
+
+        // Too much to buffer: the whole file is sent from disk instead.
+        let large = dir.file("large", vec![b'a'; 2 * MAX_BUFFERED_PARTS as usize]);
+        let large_handler = |_request: HttpRequest| HttpResponse::from_file(FileBody::open(&large).unwrap());
+        let range = format!("GET / HTTP/1.1\r\nRange: bytes=0-0,2-{}\r\n\r\n", MAX_BUFFERED_PARTS + 1);
+        let response = run(Ranges::new(), request(&range), large_handler);
+        assert_eq!(response.status_line.status_code, StatusCode::Code200);
+        assert_eq!(response.file.unwrap().len(), 2 * MAX_BUFFERED_PARTS);
+        let range = format!("GET / HTTP/1.1\r\nRange: bytes=0-0,2-{}\r\n\r\n", MAX_BUFFERED_PARTS);
+        assert_eq!(run(Ranges::new(), request(&range), large_handler).status_line.status_code, StatusCode::Code206);
+    }
+}
@@ -0,0 +1,111 @@
+use std::cell::RefCell;
+use std::io::{self, ErrorKind, IoSlice, Write};
+use fast_web_server_types::HttpResponse;
+
+
+/// Initial capacity of a worker's head buffer, enough for typical heads.
+const HEAD_CAPACITY: usize = 1024;
+
+/// Head buffers that grew past this are not kept, so one response with
+/// huge headers does not pin the memory for the worker's lifetime.
+const MAX_RETAINED_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    /// The serialized head of the response a worker is writing, reused
+    /// across its responses.
+    static HEAD: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(HEAD_CAPACITY));
+}
+
+/// Writes a response's head and in-memory body with vectored writes, so the
+/// body is handed to the connection without being copied. Returns the
+/// number of bytes written.
+pub(crate) fn write_response(stream: &mut impl Write, response: &HttpResponse) -> io::Result<u64> {
+    HEAD.with(|head| {
+        let mut head = head.borrow_mut();
+        head.clear();
+        response.write_head(&mut head);
+        let written = (head.len() + response.body.len()) as u64;
+        let result = write_all_vectored(stream, &mut [IoSlice::new(&head), IoSlice::new(&response.body)])
+            .and_then(|_| stream.flush());
+        if head.capacity() > MAX_RETAINED_CAPACITY {
+            *head = Vec::with_capacity(HEAD_CAPACITY);
+        }
+        result.map(|_| written)
+    })
+}
+
+/// `Write::write_all` for several buffers, which std only offers unstably.
+fn write_all_vectored(stream: &mut impl Write, mut bufs: &mut [IoSlice]) -> io::Result<()> {
+    while !bufs.is_empty() {
+        match stream.write_vectored(bufs) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "failed to write whole response")),
+            Ok(len) => IoSlice::advance_slices(&mut bufs, len),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accepts at most `limit` bytes per call, like a socket with a full buffer.
+    struct Trickle {
+        output: Vec<u8>,
+        limit: usize,
+        calls: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.calls += 1;
+            let mut written = 0;
+            for buf in bufs {
+                let len = buf.len().min(self.limit - written);
+                self.output.extend_from_slice(&buf[..len]);
+                written += len;
+            }
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_response() {
+        let response = HttpResponse::from_body(vec![b'x'; 1000]);
+        let expected: Vec<u8> = HttpResponse::from_body(vec![b'x'; 1000]).into();
+
+        let mut stream = Trickle { output: vec![], limit: usize::MAX, calls: 0 };
+        assert_eq!(write_response(&mut stream, &response).unwrap(), expected.len() as u64);
+        assert_eq!(stream.output, expected);
+        assert_eq!(stream.calls, 1);
+
+        let mut stream = Trickle { output: vec![], limit: 300, calls: 0 };
+        write_response(&mut stream, &response).unwrap();
+        assert_eq!(stream.output, expected);
+        assert_eq!(stream.calls, expected.len().div_ceil(300));
+    }
+
+    #[test]
+    fn test_head_buffer_is_reused() {
+        let response = HttpResponse::from_body("ok");
+        write_response(&mut Vec::new(), &response).unwrap();
+        let capacity = HEAD.with(|head| head.borrow().capacity());
+        write_response(&mut Vec::new(), &response).unwrap();
+        assert_eq!(HEAD.with(|head| head.borrow().capacity()), capacity);
+
+        let mut huge = HttpResponse::from_body("");
+        huge.headers.insert("X-Large".to_string(), "a".repeat(MAX_RETAINED_CAPACITY));
+        write_response(&mut Vec::new(), &huge).unwrap();
+        assert_eq!(HEAD.with(|head| head.borrow().capacity()), HEAD_CAPACITY);
+    }
+}
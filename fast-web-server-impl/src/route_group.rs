@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use fast_web_server_types::{Handler, RequestType};
+
+use crate::middleware::Middleware;
+use crate::Routes;
+
+
+/// Routes sharing a path prefix and a set of middlewares, added to the
+/// server with `FastWebServer::mount`.
+pub struct RouteGroup {
+    pub(crate) prefix: String,
+    pub(crate) middlewares: Vec<Arc<dyn Middleware>>,
+    pub(crate) routes: Vec<(RequestType, String, Arc<dyn Handler>)>,
+    pub(crate) max_body: Option<usize>,
+}
+
+impl RouteGroup {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            middlewares: vec![],
+            routes: vec![],
+            max_body: None,
+        }
+    }
+
+    /// Adds a middleware that runs, in registration order, after the
+    /// server-wide middlewares for every route of this group.
+    pub fn wrap<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Overrides the server's body size limit for every route of this group.
+    pub fn body_limit(&mut self, max_body: usize) -> &mut Self {
+        self.max_body = Some(max_body);
+        self
+    }
+
+    pub fn bind<H: Handler + 'static>(&mut self, request_type: RequestType, route: &str, handler: H) {
+        self.bind_arc(request_type, route, Arc::new(handler));
+    }
+
+    pub fn bind_boxed(&mut self, request_type: RequestType, route: &str, handler: Box<dyn Handler>) {
+        self.bind_arc(request_type, route, Arc::from(handler));
+    }
+
+    /// The full path of `route` inside this group.
+    pub(crate) fn path(&self, route: &str) -> String {
+        match route {
+            "/" if !self.prefix.is_empty() => self.prefix.clone(),
+            route => format!("{}{}", self.prefix, route),
+        }
+    }
+}
+
+impl Routes for RouteGroup {
+    fn bind_arc(&mut self, request_type: RequestType, route: &str, handler: Arc<dyn Handler>) {
+        self.routes.push((request_type, route.to_string(), handler));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path() {
+        let group = RouteGroup::new("/api/");
+        assert_eq!(group.path("/users"), "/api/users");
+        assert_eq!(group.path("/"), "/api");
+        assert_eq!(RouteGroup::new("").path("/"), "/");
+    }
+}
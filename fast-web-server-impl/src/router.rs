@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use fast_web_server_types::{Handler, RequestType, RoutePattern, RoutePatternError};
+
+use crate::middleware::Middleware;
+
+
+/// The route pattern a request was matched against, e.g. `/users/{id}`,
+/// stored in the request extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedRoute(pub Arc<str>);
+
+/// A bound handler together with the middlewares of the group it was bound in.
+#[derive(Clone)]
+pub(crate) struct Endpoint {
+    pub(crate) handler: Arc<dyn Handler>,
+    pub(crate) middlewares: Arc<[Arc<dyn Middleware>]>,
+    pub(crate) route: Option<MatchedRoute>,
+    /// Overrides `RequestLimits::max_body` for this route.
+    pub(crate) max_body: Option<usize>,
+}
+
+impl Endpoint {
+    pub(crate) fn new(handler: Arc<dyn Handler>) -> Self {
+        Self { handler, middlewares: Arc::new([]), route: None, max_body: None }
+    }
+}
+
+/// Route table: static paths are looked up directly, paths with `{param}`
+/// segments are matched in registration order.
+#[derive(Default)]
+pub(crate) struct Router {
+    static_routes: HashMap<(RequestType, String), Endpoint>,
+    dynamic_routes: Vec<(RequestType, RoutePattern, Endpoint)>,
+}
+
+impl Router {
+    pub(crate) fn insert(&mut self, request_type: RequestType, route: &str, mut endpoint: Endpoint) -> Result<(), RoutePatternError> {
+        let pattern = RoutePattern::parse(route)?;
+        endpoint.route = Some(MatchedRoute(route.into()));
+        if pattern.is_static() {
+            self.static_routes.insert((request_type, route.to_string()), endpoint);
+        } else {
+            self.dynamic_routes.retain(|(t, p, _)| !(*t == request_type && *p == pattern));
+            self.dynamic_routes.push((request_type, pattern, endpoint));
+        }
+        Ok(())
+    }
+
+    /// The endpoint bound to exactly `route`, as opposed to a path matching it.
+    pub(crate) fn endpoint_mut(&mut self, request_type: &RequestType, route: &str) -> Option<&mut Endpoint> {
+        if let Some(endpoint) = self.static_routes.get_mut(&(request_type.to_owned(), route.to_string())) {
+            return Some(endpoint);
+        }
+        let pattern = RoutePattern::parse(route).ok()?;
+        self.dynamic_routes.iter_mut()
+            .find(|(t, p, _)| t == request_type && *p == pattern)
+            .map(|(_, _, endpoint)| endpoint)
+    }
+
+    pub(crate) fn lookup(&self, request_type: &RequestType, path: &str) -> Option<(Endpoint, HashMap<String, String>)> {
+        if let Some(endpoint) = self.static_routes.get(&(request_type.to_owned(), path.to_string())) {
+            return Some((endpoint.clone(), HashMap::default()));
+        }
+        self.dynamic_routes.iter()
+            .filter(|(t, _, _)| t == request_type)
+            .find_map(|(_, pattern, endpoint)| pattern.matches(path).map(|params| (endpoint.clone(), params)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use fast_web_server_types::{HttpRequest, HttpResponse};
+
+    use super::*;
+
+    fn first(_request: HttpRequest) -> HttpResponse {
+        HttpResponse::from_body("first")
+    }
+
+    fn second(_request: HttpRequest) -> HttpResponse {
+        HttpResponse::from_body("second")
+    }
+
+    fn endpoint<H: Handler + 'static>(handler: H) -> Endpoint {
+        Endpoint::new(Arc::new(handler))
+    }
+
+    fn call(endpoint: Endpoint) -> Vec<u8> {
+        let request = HttpRequest::new(&mut Cursor::new(b"GET / HTTP/1.1\r\n\r\n".as_ref())).unwrap();
+        endpoint.handler.call(request).body
+    }
+
+    #[test]
+    fn test_static_lookup() {
+        let mut router = Router::default();
+        router.insert(RequestType::GET, "/test", endpoint(first)).unwrap();
+        assert!(router.lookup(&RequestType::GET, "/test").is_some());
+        assert!(router.lookup(&RequestType::POST, "/test").is_none());
+        assert!(router.lookup(&RequestType::GET, "/other").is_none());
+    }
+
+    #[test]
+    fn test_static_routes_take_precedence() {
+        let mut router = Router::default();
+        router.insert(RequestType::GET, "/users/{id}", endpoint(first)).unwrap();
+        router.insert(RequestType::GET, "/users/me", endpoint(second)).unwrap();
+
+        let (endpoint, params) = router.lookup(&RequestType::GET, "/users/me").unwrap();
+        assert_eq!(call(endpoint), b"second");
+        assert!(params.is_empty());
+
+        let (endpoint, params) = router.lookup(&RequestType::GET, "/users/42").unwrap();
+        assert_eq!(endpoint.route, Some(MatchedRoute("/users/{id}".into())));
+        assert_eq!(call(endpoint), b"first");
+        assert_eq!(params.get("id"), Some(&"42".to_string()));
+    }
+
+    #[test]
+    fn test_closure_handler() {
+        let mut router = Router::default();
+        let greeting = String::from("hello");
+        router.insert(RequestType::GET, "/greet", endpoint(move |_request: HttpRequest| greeting.clone())).unwrap();
+        let (endpoint, _) = router.lookup(&RequestType::GET, "/greet").unwrap();
+        assert_eq!(call(endpoint), b"hello");
+    }
+
+    #[test]
+    fn test_endpoint_mut() {
+        let mut router = Router::default();
+        router.insert(RequestType::POST, "/users/{id}/avatar", endpoint(first)).unwrap();
+        router.endpoint_mut(&RequestType::POST, "/users/{id}/avatar").unwrap().max_body = Some(1 << 20);
+        assert!(router.endpoint_mut(&RequestType::POST, "/users/42/avatar").is_none());
+
+        let (endpoint, _) = router.lookup(&RequestType::POST, "/users/42/avatar").unwrap();
+        assert_eq!(endpoint.max_body, Some(1 << 20));
+    }
+
+    #[test]
+    fn test_invalid_route() {
+        let mut router = Router::default();
+        assert_eq!(router.insert(RequestType::GET, "test", endpoint(first)), Err(RoutePatternError::MissingLeadingSlash));
+    }
+}
@@ -0,0 +1,100 @@
+use std::io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use fast_web_server_types::FileBody;
+
+use crate::metrics::Metrics;
+
+
+/// Buffer size of the copy used where the kernel cannot send the file.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Sends `body` over a plain TCP connection with `sendfile(2)`, so the
+/// file's pages go to the socket without passing through user space.
+#[cfg(target_os = "linux")]
+pub(crate) fn send_file(socket: &mut TcpStream, body: &FileBody, metrics: &Metrics) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    /// The most `sendfile` transfers in one call.
+    const MAX_COUNT: u64 = 0x7fff_f000;
+
+    let end = body.offset() + body.len();
+    let mut offset = body.offset() as libc::off_t;
+    while (offset as u64) < end {
+        let count = (end - offset as u64).min(MAX_COUNT) as usize;
+        // SAFETY: both descriptors are open for the duration of the call and
+        // `offset` is a valid pointer the kernel advances by the bytes sent.
+        let sent = unsafe { libc::sendfile(socket.as_raw_fd(), body.file().as_raw_fd(), &mut offset, count) };
+        match sent {
+            -1 => match io::Error::last_os_error() {
+                e if e.kind() == ErrorKind::Interrupted => continue,
+                e => return Err(e),
+            },
+            0 => return Err(io::Error::new(ErrorKind::UnexpectedEof, "file shrank while being sent")),
+            sent => metrics.add_bytes_sent(sent as u64),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn send_file(socket: &mut TcpStream, body: &FileBody, metrics: &Metrics) -> io::Result<()> {
+    copy_file(socket, body, metrics)
+}
+
+/// Sends `body` through a buffer, for connections such as TLS whose bytes
+/// the kernel cannot produce from the file itself.
+pub(crate) fn copy_file(stream: &mut impl Write, body: &FileBody, metrics: &Metrics) -> io::Result<()> {
+    let mut file = body.file();
+    file.seek(SeekFrom::Start(body.offset()))?;
+    let mut writer = BufWriter::with_capacity(COPY_BUFFER_SIZE, stream);
+    let copied = io::copy(&mut file.take(body.len()), &mut writer)?;
+    writer.flush()?;
+    metrics.add_bytes_sent(copied);
+    match copied == body.len() {
+        true => Ok(()),
+        false => Err(io::Error::new(ErrorKind::UnexpectedEof, "file shrank while being sent")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_send_file() {
+        let contents: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+        let dir = TempDir::new("sendfile");
+        let path = dir.file("send", &contents);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let metrics = Metrics::default();
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![];
+            client.read_to_end(&mut received).unwrap();
+            received
+        });
+        let body = FileBody::with_range(File::open(&path).unwrap(), 1000, 200_000);
+        send_file(&mut server, &body, &metrics).unwrap();
+        drop(server);
+        assert_eq!(reader.join().unwrap(), &contents[1000..201_000]);
+        assert_eq!(metrics.bytes_sent(), 200_000);
+    }
+
+    #[test]
+    fn test_copy_file() {
+        let dir = TempDir::new("copy-file");
+        let path = dir.file("copy", b"0123456789");
+        let metrics = Metrics::default();
+        let mut out = vec![];
+        copy_file(&mut out, &FileBody::with_range(File::open(&path).unwrap(), 2, 5), &metrics).unwrap();
+        assert_eq!(out, b"23456");
+        assert_eq!(metrics.bytes_sent(), 5);
+        let error = copy_file(&mut vec![], &FileBody::with_range(File::open(&path).unwrap(), 8, 5), &metrics).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,238 @@
+use std::fmt::Write;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+use fast_web_server_types::{BodyStream, Chunk, ExtractError, FromRequest, HttpRequest, HttpResponse, Responder, StreamingBody};
+
+
+/// Events sent in one chunk at most, so one busy stream cannot starve the others.
+const MAX_BATCH: usize = 64;
+
+/// A server-sent event. Multi-line data is split into several `data` fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn data(data: impl Into<String>) -> Self {
+        Self { data: data.into(), ..Self::default() }
+    }
+
+    /// The event type, dispatched to `addEventListener(name)` in browsers.
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// The id a reconnecting client sends back in `Last-Event-ID`.
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// How long the client waits before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn encode(&self, buf: &mut String) {
+        // Line breaks would end the field early, and NUL is not allowed in ids.
+        let single_line = |value: &str| value.replace(['\r', '\n', '\0'], "");
+        if let Some(event) = &self.event {
+            writeln!(buf, "event: {}", single_line(event)).unwrap();
+        }
+        for line in self.data.split('\n') {
+            writeln!(buf, "data: {}", line.strip_suffix('\r').unwrap_or(line)).unwrap();
+        }
+        if let Some(id) = &self.id {
+            writeln!(buf, "id: {}", single_line(id)).unwrap();
+        }
+        if let Some(retry) = self.retry {
+            writeln!(buf, "retry: {}", retry.as_millis()).unwrap();
+        }
+        buf.push('\n');
+    }
+}
+
+enum Source {
+    Channel(Receiver<Event>),
+    Iter(Box<dyn Iterator<Item = Event> + Send>),
+}
+
+/// A `text/event-stream` response. Events come from a channel, sent as
+/// soon as they arrive until every sender is dropped, or from an iterator,
+/// which should not block. While no events arrive, a comment is sent every
+/// keep-alive interval so proxies keep the connection open.
+///
+/// On HTTP/1 the connection is then served by a single streaming thread
+/// shared by all event streams, so idle clients do not hold a worker.
+pub struct Sse {
+    source: Source,
+    keep_alive: Option<Duration>,
+    retry: Option<Duration>,
+}
+
+impl Sse {
+    pub fn from_channel(receiver: Receiver<Event>) -> Self {
+        Self::new(Source::Channel(receiver))
+    }
+
+    pub fn from_events<I>(events: I) -> Self
+    where I: IntoIterator<Item = Event>, I::IntoIter: Send + 'static {
+        Self::new(Source::Iter(Box::new(events.into_iter())))
+    }
+
+    fn new(source: Source) -> Self {
+        Self { source, keep_alive: Some(Duration::from_secs(15)), retry: None }
+    }
+
+    /// Interval of the keep-alive comments, 15 seconds by default.
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval);
+        self
+    }
+
+    pub fn no_keep_alive(mut self) -> Self {
+        self.keep_alive = None;
+        self
+    }
+
+    /// Sent first, to set how long clients wait before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl Responder for Sse {
+    fn respond(self) -> HttpResponse {
+        let mut buf = String::new();
+        if let Some(retry) = self.retry {
+            writeln!(buf, "retry: {}\n", retry.as_millis()).unwrap();
+        }
+        let stream = EventStream { source: Some(self.source), keep_alive: self.keep_alive, last_sent: Instant::now(), initial: buf };
+        let mut response = HttpResponse::streaming(StreamingBody::new(stream));
+        response.headers.insert("Content-Type".to_string(), "text/event-stream".to_string());
+        response.headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+        response.headers.insert("X-Accel-Buffering".to_string(), "no".to_string());
+        response
+    }
+}
+
+struct EventStream {
+    /// `None` once the source is exhausted.
+    source: Option<Source>,
+    keep_alive: Option<Duration>,
+    last_sent: Instant,
+    initial: String,
+}
+
+impl BodyStream for EventStream {
+    fn poll_chunk(&mut self) -> Chunk {
+        let mut buf = std::mem::take(&mut self.initial);
+        let mut ended = false;
+        for _ in 0..MAX_BATCH {
+            let event = match &mut self.source {
+                Some(Source::Channel(receiver)) => match receiver.try_recv() {
+                    Ok(event) => Some(event),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => None,
+                },
+                Some(Source::Iter(events)) => events.next(),
+                None => None,
+            };
+            match event {
+                Some(event) => event.encode(&mut buf),
+                None => {
+                    ended = true;
+                    break;
+                },
+            }
+        }
+        if ended {
+            self.source = None;
+        }
+        if buf.is_empty() {
+            if ended {
+                return Chunk::End;
+            }
+            match self.keep_alive {
+                Some(interval) if self.last_sent.elapsed() >= interval => buf.push_str(":\n\n"),
+                _ => return Chunk::Pending,
+            }
+        }
+        self.last_sent = Instant::now();
+        Chunk::Data(buf.into_bytes())
+    }
+}
+
+/// The `Last-Event-ID` a reconnecting client sends, to resume after the
+/// last event it received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastEventId(pub Option<String>);
+
+impl FromRequest for LastEventId {
+    fn from_request(request: &HttpRequest) -> Result<Self, ExtractError> {
+        Ok(Self(request.headers.get("Last-Event-ID").cloned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn encode(event: Event) -> String {
+        let mut buf = String::new();
+        event.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(Event::data("hello")), "data: hello\n\n");
+        let event = Event::data("line 1\r\nline 2").event("update").id("7").retry(Duration::from_secs(3));
+        assert_eq!(encode(event), "event: update\ndata: line 1\ndata: line 2\nid: 7\nretry: 3000\n\n");
+        assert_eq!(encode(Event::data("").event("a\nb").id("1\n2")), "event: ab\ndata: \nid: 12\n\n");
+    }
+
+    #[test]
+    fn test_channel() {
+        let (sender, receiver) = mpsc::channel();
+        let mut response = Sse::from_channel(receiver).keep_alive(Duration::from_millis(20)).retry(Duration::from_secs(1)).respond();
+        assert_eq!(response.headers.get("Content-Type").unwrap(), "text/event-stream");
+        assert_eq!(response.headers.get("Content-Length"), None);
+        let mut stream = response.stream.take().unwrap();
+        assert_eq!(stream.poll_chunk(), Chunk::Data(b"retry: 1000\n\n".to_vec()));
+        assert_eq!(stream.poll_chunk(), Chunk::Pending);
+        sender.send(Event::data("a")).unwrap();
+        sender.send(Event::data("b")).unwrap();
+        assert_eq!(stream.poll_chunk(), Chunk::Data(b"data: a\n\ndata: b\n\n".to_vec()));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(stream.poll_chunk(), Chunk::Data(b":\n\n".to_vec()));
+        drop(sender);
+        assert_eq!(stream.poll_chunk(), Chunk::End);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut response = Sse::from_events((1..=3).map(|i| Event::data(i.to_string()).id(i.to_string()))).respond();
+        let mut stream = response.stream.take().unwrap();
+        assert_eq!(stream.poll_chunk(), Chunk::Data(b"data: 1\nid: 1\n\ndata: 2\nid: 2\n\ndata: 3\nid: 3\n\n".to_vec()));
+        assert_eq!(stream.poll_chunk(), Chunk::End);
+    }
+
+    #[test]
+    fn test_last_event_id() {
+        let request = |raw: &str| HttpRequest::new(&mut Cursor::new(raw.as_bytes())).unwrap();
+        let id = LastEventId::from_request(&request("GET /events HTTP/1.1\r\nLast-Event-ID: 42\r\n\r\n")).unwrap();
+        assert_eq!(id, LastEventId(Some("42".to_string())));
+        assert_eq!(LastEventId::from_request(&request("GET /events HTTP/1.1\r\n\r\n")).unwrap(), LastEventId(None));
+    }
+}
@@ -0,0 +1,355 @@
+use std::fs::{self, Metadata};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use fast_web_server_types::{ETag, FileBody, HttpRequest, HttpResponse, Precondition, Preconditions, RequestType, StatusCode};
+use tracing::warn;
+
+use crate::middleware::Ranges;
+use crate::route_group::RouteGroup;
+use crate::Routes;
+
+
+/// Content types by file extension. Text types are sent as UTF-8.
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// The content type of `path`, `application/octet-stream` if unknown.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    MIME_TYPES.iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map_or("application/octet-stream", |(_, content_type)| content_type)
+}
+
+/// Serves the files below a directory, mounted on the server like a route
+/// group:
+///
+/// ```ignore
+/// server.mount(StaticFiles::new("/assets", "./public").spa_fallback(true));
+/// ```
+///
+/// `GET` and `HEAD` requests are answered with the file's `Content-Type`,
+/// `ETag` and `Last-Modified`, or `304 Not Modified` if the client's copy
+/// is current and `412 Precondition Failed` if an `If-Match` or
+/// `If-Unmodified-Since` precondition fails. Range requests are served by
+/// the `Ranges` middleware. Paths with `..` or hidden segments are never
+/// served, nor are files that symlinks lead outside the directory to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticFiles {
+    prefix: String,
+    root: PathBuf,
+    index: Option<String>,
+    spa_fallback: bool,
+    cache_control: Option<String>,
+}
+
+impl StaticFiles {
+    pub fn new(prefix: &str, root: impl Into<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            root: root.into(),
+            index: Some("index.html".to_string()),
+            spa_fallback: false,
+            cache_control: None,
+        }
+    }
+
+    /// The file served for a directory, `index.html` by default.
+    pub fn index_file(mut self, index: &str) -> Self {
+        self.index = Some(index.to_string());
+        self
+    }
+
+    /// Answers requests for directories with `404 Not Found`.
+    pub fn no_index(mut self) -> Self {
+        self.index = None;
+        self
+    }
+
+    /// Serves the root index file for missing paths without a file
+    /// extension, so a single-page app can handle its own routes. Missing
+    /// assets like `/app.js` are still `404 Not Found`.
+    pub fn spa_fallback(mut self, spa_fallback: bool) -> Self {
+        self.spa_fallback = spa_fallback;
+        self
+    }
+
+    /// The `Cache-Control` header of served files.
+    pub fn cache_control(mut self, cache_control: &str) -> Self {
+        self.cache_control = Some(cache_control.to_string());
+        self
+    }
+
+    fn serve(&self, request: &HttpRequest) -> HttpResponse {
+        let not_found = || HttpResponse::json_error(StatusCode::Code404, "not_found", None);
+        let path = request.path_params.get("path").map_or("", String::as_str);
+        let target = match self.resolve(path) {
+            Some(target) => target,
+            None => return not_found(),
+        };
+        let file = match fs::metadata(&target) {
+            Ok(metadata) if metadata.is_dir() && !request.start_line.request_target.uri.ends_with('/') => {
+                return redirect_to_directory(request);
+            },
+            Ok(metadata) if metadata.is_dir() => self.index.as_ref().map(|index| target.join(index)),
+            Ok(_) => Some(target),
+            Err(_) => None,
+        };
+        let file = file.and_then(|file| fs::metadata(&file).ok().filter(Metadata::is_file).map(|metadata| (file, metadata)));
+        let (file, metadata) = match file.or_else(|| self.fallback(path)) {
+            Some(file) if self.is_inside_root(&file.0) => file,
+            _ => return not_found(),
+        };
+        self.respond(request, &file, &metadata)
+    }
+
+    /// The file system path of a request path, `None` if it has segments
+    /// that could leave the root or reveal hidden files.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut target = self.root.clone();
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            if segment.starts_with('.') || segment.contains(['\\', '\0']) || Path::new(segment).has_root() {
+                return None;
+            }
+            target.push(segment);
+        }
+        Some(target)
+    }
+
+    fn fallback(&self, path: &str) -> Option<(PathBuf, Metadata)> {
+        let last = path.rsplit('/').next().unwrap_or_default();
+        if !self.spa_fallback || last.contains('.') {
+            return None;
+        }
+        let index = self.root.join(self.index.as_ref()?);
+        fs::metadata(&index).ok().filter(Metadata::is_file).map(|metadata| (index, metadata))
+    }
+
+    /// Whether `file` is below the root once symlinks are resolved.
+    fn is_inside_root(&self, file: &Path) -> bool {
+        match (fs::canonicalize(&self.root), fs::canonicalize(file)) {
+            (Ok(root), Ok(file)) => file.starts_with(root),
+            _ => false,
+        }
+    }
+
+    fn respond(&self, request: &HttpRequest, file: &Path, metadata: &Metadata) -> HttpResponse {
+        let modified = metadata.modified().ok();
+        let etag = entity_tag(metadata.len(), modified);
+        // Evaluated before the file is opened, so 304 and 412 never touch it.
+        let mut response = match Preconditions::for_request(request).evaluate(Some(&etag), modified) {
+            Precondition::Failed => return HttpResponse::json_error(StatusCode::Code412, "precondition_failed", None),
+            Precondition::NotModified => HttpResponse::from_body("").into_not_modified(),
+            Precondition::Passed if request.start_line.request_type == RequestType::HEAD => {
+                let mut response = HttpResponse::from_body("");
+                response.headers.insert("Content-Length".to_string(), metadata.len().to_string());
+                response
+            },
+            Precondition::Passed => match FileBody::open(file) {
+                Ok(body) => HttpResponse::from_file(body),
+                Err(e) if e.kind() == ErrorKind::NotFound => return HttpResponse::json_error(StatusCode::Code404, "not_found", None),
+                Err(e) => {
+                    warn!(error = %e, file = %file.display(), "could not read static file");
+                    return HttpResponse::json_error(StatusCode::Code500, "internal_server_error", None);
+                },
+            },
+        };
+        if response.status_line.status_code != StatusCode::Code304 {
+            response.headers.insert("Content-Type".to_string(), content_type(file).to_string());
+        }
+        response.set_etag(&etag);
+        if let Some(modified) = modified {
+            response.set_last_modified(modified);
+        }
+        if let Some(cache_control) = &self.cache_control {
+            response.headers.insert("Cache-Control".to_string(), cache_control.clone());
+        }
+        response
+    }
+}
+
+/// A strong validator changing whenever the file is modified or resized.
+fn entity_tag(len: u64, modified: Option<SystemTime>) -> ETag {
+    let modified = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+    ETag::strong(&format!("{:x}-{:x}", modified.as_nanos(), len))
+}
+
+/// Redirects `/assets/docs` to `/assets/docs/`, so relative links in the
+/// directory's index resolve below it.
+fn redirect_to_directory(request: &HttpRequest) -> HttpResponse {
+    let target = &request.start_line.request_target;
+    let location = match target.raw.split_once('?') {
+        Some((_, query)) => format!("{}/?{}", target.uri, query),
+        None => format!("{}/", target.uri),
+    };
+    let mut response = HttpResponse::with_status(StatusCode::Code308, "");
+    response.headers.insert("Location".to_string(), location);
+    response
+}
+
+impl From<StaticFiles> for RouteGroup {
+    fn from(files: StaticFiles) -> Self {
+        let mut group = RouteGroup::new(&files.prefix);
+        group.wrap(Ranges::new());
+        let files = Arc::new(files);
+        for request_type in [RequestType::GET, RequestType::HEAD] {
+            for route in ["/", "/{*path}"] {
+                let files = files.clone();
+                group.bind_arc(request_type.clone(), route, Arc::new(move |request: HttpRequest| files.serve(&request)));
+            }
+        }
+        group
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+    use std::time::Duration;
+    use fast_web_server_types::RoutePattern;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// A public directory with an index, a script, a subdirectory and a dotfile.
+    fn public_dir(name: &str) -> TempDir {
+        let root = TempDir::new(&format!("static-{}", name));
+        root.file("index.html", "<h1>home</h1>");
+        root.file("app.js", "run()");
+        root.file("docs/index.html", "<h1>docs</h1>");
+        root.file(".env", "SECRET=1");
+        root
+    }
+
+    fn get(files: &StaticFiles, raw: &str) -> HttpResponse {
+        let mut request = HttpRequest::new(&mut Cursor::new(raw.as_bytes())).unwrap();
+        let pattern = RoutePattern::parse("/assets/{*path}").unwrap();
+        if let Some(params) = pattern.matches(&request.start_line.request_target.uri) {
+            request.path_params = params;
+        }
+        files.serve(&request)
+    }
+
+    fn body(response: &mut HttpResponse) -> Vec<u8> {
+        let mut body = std::mem::take(&mut response.body);
+        if let Some(file) = response.file.take() {
+            let mut reader = file.file();
+            reader.read_to_end(&mut body).unwrap();
+        }
+        body
+    }
+
+    fn status(response: &HttpResponse) -> u16 {
+        response.status_line.status_code.as_u16()
+    }
+
+    #[test]
+    fn test_serves_files() {
+        let root = public_dir("files");
+        let files = StaticFiles::new("/assets", &*root).cache_control("max-age=60");
+        let mut response = get(&files, "GET /assets/app.js HTTP/1.1\r\n\r\n");
+        assert_eq!(body(&mut response), b"run()");
+        assert_eq!(response.headers.get("Content-Type").unwrap(), "text/javascript; charset=utf-8");
+        assert_eq!(response.headers.get("Cache-Control").unwrap(), "max-age=60");
+        assert!(response.headers.get("ETag").unwrap().starts_with('"'));
+        assert!(response.headers.get("Last-Modified").unwrap().ends_with(" GMT"));
+
+        let head = get(&files, "HEAD /assets/app.js HTTP/1.1\r\n\r\n");
+        assert!(head.body.is_empty());
+        assert_eq!(head.headers.get("Content-Length").unwrap(), "5");
+        assert_eq!(head.headers.get("ETag"), response.headers.get("ETag"));
+        assert_eq!(content_type(Path::new("archive.unknown")), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_path_traversal() {
+        let root = public_dir("traversal");
+        let files = StaticFiles::new("/assets", root.join("docs"));
+        for path in ["/assets/../app.js", "/assets/%2e%2e/app.js", "/assets/..%2fapp.js", "/assets/.env", "/assets/a\\..\\b"] {
+            assert_eq!(status(&get(&files, &format!("GET {} HTTP/1.1\r\n\r\n", path))), 404, "{}", path);
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("app.js"), root.join("docs/link.js")).unwrap();
+            assert_eq!(status(&get(&files, "GET /assets/link.js HTTP/1.1\r\n\r\n")), 404);
+        }
+    }
+
+    #[test]
+    fn test_index_and_fallback() {
+        let root = public_dir("index");
+        let files = StaticFiles::new("/assets", &*root);
+        assert_eq!(body(&mut get(&files, "GET /assets/ HTTP/1.1\r\n\r\n")), b"<h1>home</h1>");
+        let mut response = get(&files, "GET /assets/docs/ HTTP/1.1\r\n\r\n");
+        assert_eq!(response.headers.get("Content-Type").unwrap(), "text/html; charset=utf-8");
+        assert_eq!(body(&mut response), b"<h1>docs</h1>");
+        let redirect = get(&files, "GET /assets/docs?v=2 HTTP/1.1\r\n\r\n");
+        assert_eq!(status(&redirect), 308);
+        assert_eq!(redirect.headers.get("Location").unwrap(), "/assets/docs/?v=2");
+        assert_eq!(status(&get(&files, "GET /assets HTTP/1.1\r\n\r\n")), 308);
+        assert_eq!(status(&get(&files, "GET /assets/users/42 HTTP/1.1\r\n\r\n")), 404);
+        assert_eq!(status(&get(&files.clone().no_index(), "GET /assets/docs/ HTTP/1.1\r\n\r\n")), 404);
+
+        let spa = files.spa_fallback(true);
+        assert_eq!(body(&mut get(&spa, "GET /assets/users/42 HTTP/1.1\r\n\r\n")), b"<h1>home</h1>");
+        assert_eq!(status(&get(&spa, "GET /assets/missing.js HTTP/1.1\r\n\r\n")), 404);
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let root = public_dir("conditional");
+        let files = StaticFiles::new("/assets", &*root);
+        let response = get(&files, "GET /assets/app.js HTTP/1.1\r\n\r\n");
+        let etag = response.headers.get("ETag").unwrap();
+        let last_modified = response.headers.get("Last-Modified").unwrap();
+
+        let cached = get(&files, &format!("GET /assets/app.js HTTP/1.1\r\nIf-None-Match: \"x\", W/{}\r\n\r\n", etag));
+        assert_eq!(status(&cached), 304);
+        assert!(cached.body.is_empty() && cached.headers.get("Content-Length").is_none());
+        assert_eq!(cached.headers.get("ETag"), Some(etag));
+        assert_eq!(status(&get(&files, "GET /assets/app.js HTTP/1.1\r\nIf-None-Match: \"x\"\r\n\r\n")), 200);
+
+        let since = get(&files, &format!("GET /assets/app.js HTTP/1.1\r\nIf-Modified-Since: {}\r\n\r\n", last_modified));
+        assert_eq!(status(&since), 304);
+        let earlier = httpdate::fmt_http_date(httpdate::parse_http_date(last_modified).unwrap() - Duration::from_secs(60));
+        assert_eq!(status(&get(&files, &format!("GET /assets/app.js HTTP/1.1\r\nIf-Modified-Since: {}\r\n\r\n", earlier))), 200);
+
+        assert_eq!(status(&get(&files, &format!("GET /assets/app.js HTTP/1.1\r\nIf-Match: {}\r\n\r\n", etag))), 200);
+        assert_eq!(status(&get(&files, "GET /assets/app.js HTTP/1.1\r\nIf-Match: \"x\"\r\n\r\n")), 412);
+    }
+}
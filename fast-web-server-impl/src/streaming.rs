@@ -0,0 +1,263 @@
+use std::io::{self, ErrorKind, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use fast_web_server_types::{Chunk, StreamingBody};
+use tracing::debug;
+
+use crate::metrics::Metrics;
+use crate::timeouts::SetTimeout;
+
+
+/// How often idle streams are polled for new data.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Time a stream with data to send may go without the client accepting
+/// any of it before the client is considered stalled and dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time ending a response may block, when the connection is switched back
+/// to blocking writes.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An HTTP/1 connection whose response head has been sent and whose body
+/// the streamer writes.
+pub(crate) trait StreamConnection: Write + SetTimeout + Send {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// Ends the response, which is delimited by closing the connection.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl StreamConnection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+struct Stream {
+    connection: Box<dyn StreamConnection>,
+    body: StreamingBody,
+    /// Body data the client has not accepted yet. The body is polled again
+    /// only once it is written.
+    pending: Vec<u8>,
+    ended: bool,
+    /// When the client last accepted data, or had nothing left to accept.
+    progress: Instant,
+    /// Called once the stream is dropped, however it ends.
+    on_end: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Stream {
+    /// Writes as much as the connection takes without blocking. Returns
+    /// `None` once the stream is finished, else whether data was written.
+    fn advance(&mut self, metrics: &Metrics) -> io::Result<Option<bool>> {
+        if self.pending.is_empty() && !self.ended {
+            match self.body.poll_chunk() {
+                Chunk::Data(data) => self.pending = data,
+                Chunk::Pending => {},
+                Chunk::End => self.ended = true,
+            }
+        }
+        let mut wrote = false;
+        while !self.pending.is_empty() {
+            match self.connection.write(&self.pending) {
+                // A TLS connection takes nothing while its own buffer is full.
+                Ok(0) => break,
+                Ok(len) => {
+                    self.pending.drain(..len);
+                    metrics.add_bytes_sent(len as u64);
+                    wrote = true;
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let drained = self.pending.is_empty() && match self.connection.flush() {
+            Ok(()) => true,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => return Err(e),
+        };
+        if drained && self.ended {
+            self.connection.set_nonblocking(false)?;
+            self.connection.finish()?;
+            return Ok(None);
+        }
+        if wrote || drained {
+            self.progress = Instant::now();
+        } else if self.progress.elapsed() > STALL_TIMEOUT {
+            return Err(io::Error::new(ErrorKind::TimedOut, "client stopped reading"));
+        }
+        Ok(Some(wrote))
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Some(on_end) = self.on_end.take() {
+            on_end();
+        }
+    }
+}
+
+/// Writes the streamed bodies of all HTTP/1 connections from one thread,
+/// started with the first stream.
+#[derive(Default)]
+pub(crate) struct Streamer {
+    sender: Mutex<Option<Sender<Stream>>>,
+}
+
+impl Streamer {
+    /// Streams `body` to `connection`, calling `on_end` when done.
+    pub(crate) fn push(
+        &self,
+        connection: Box<dyn StreamConnection>,
+        body: StreamingBody,
+        metrics: &Arc<Metrics>,
+        on_end: Box<dyn FnOnce() + Send>) -> io::Result<()> {
+
+        let mut stream = Stream { connection, body, pending: vec![], ended: false, progress: Instant::now(), on_end: Some(on_end) };
+        stream.connection.set_write_timeout(Some(FINISH_TIMEOUT))?;
+        stream.connection.set_nonblocking(true)?;
+        let mut sender = self.sender.lock().unwrap();
+        // The thread is restarted should it ever have exited.
+        if let Some(sender) = sender.as_ref() {
+            match sender.send(stream) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::SendError(returned)) => stream = returned,
+            }
+        }
+        let (new_sender, receiver) = mpsc::channel();
+        new_sender.send(stream).unwrap();
+        let metrics = metrics.clone();
+        thread::Builder::new()
+            .name("fast-web-server-streamer".to_string())
+            .spawn(move || run(receiver, &metrics))?;
+        *sender = Some(new_sender);
+        Ok(())
+    }
+}
+
+fn run(receiver: Receiver<Stream>, metrics: &Metrics) {
+    let mut streams: Vec<Stream> = vec![];
+    loop {
+        if streams.is_empty() {
+            match receiver.recv() {
+                Ok(stream) => streams.push(stream),
+                Err(_) => return,
+            }
+        }
+        loop {
+            match receiver.try_recv() {
+                Ok(stream) => streams.push(stream),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) if streams.is_empty() => return,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        let mut wrote = false;
+        streams.retain_mut(|stream| match stream.advance(metrics) {
+            Ok(Some(progress)) => {
+                wrote |= progress;
+                true
+            },
+            Ok(None) => false,
+            Err(e) => {
+                debug!(error = %e, "streaming client went away");
+                false
+            },
+        });
+        if !wrote {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    use fast_web_server_types::{BodyStream, Responder};
+
+    use super::*;
+    use crate::sse::{Event, Sse};
+
+    #[test]
+    fn test_streams_share_a_thread() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let streamer = Streamer::default();
+        let metrics = Arc::new(Metrics::default());
+        let mut clients = vec![];
+        let mut senders = vec![];
+        for _ in 0..3 {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            let (sender, receiver) = mpsc::channel();
+            let body = Sse::from_channel(receiver).respond().stream.unwrap();
+            streamer.push(Box::new(server), body, &metrics, Box::new(|| {})).unwrap();
+            clients.push(client);
+            senders.push(sender);
+        }
+        for (i, sender) in senders.iter().enumerate() {
+            sender.send(Event::data(i.to_string())).unwrap();
+        }
+        senders.clear();
+        for (i, client) in clients.iter_mut().enumerate() {
+            let mut received = String::new();
+            client.read_to_string(&mut received).unwrap();
+            assert_eq!(received, format!("data: {}\n\n", i));
+        }
+        assert_eq!(metrics.bytes_sent(), 27);
+    }
+
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl BodyStream for Chunks {
+        fn poll_chunk(&mut self) -> Chunk {
+            match self.0.pop() {
+                Some(chunk) => Chunk::Data(chunk),
+                None => Chunk::End,
+            }
+        }
+    }
+
+    #[test]
+    fn test_stalled_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let streamer = Streamer::default();
+        let metrics = Arc::new(Metrics::default());
+        let ended = Arc::new(AtomicUsize::new(0));
+        let stream = |chunks: Vec<Vec<u8>>| {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            let body = StreamingBody::new(Chunks(chunks));
+            let ended = ended.clone();
+            streamer.push(Box::new(server), body, &metrics, Box::new(move || { ended.fetch_add(1, Ordering::Relaxed); })).unwrap();
+            client
+        };
+        // Far more than the socket buffers hold, never read.
+        let _stalled = stream(vec![vec![b'x'; 1024 * 1024]; 64]);
+        let mut client = stream(vec![b"hello".to_vec()]);
+
+        client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "hello");
+        // The stream is dropped right after its connection is finished.
+        let start = Instant::now();
+        while ended.load(Ordering::Relaxed) == 0 && start.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(ended.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,223 @@
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+
+/// Deadlines for the phases of a connection. A client that is too slow to
+/// send its headers or body gets `408 Request Timeout`; a client too slow to
+/// read the response is disconnected. A handler that overruns its deadline
+/// is answered with `503 Service Unavailable` while it finishes in the
+/// background; `408` would blame the client for the server's slowness.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeouts {
+    pub header_read: Duration,
+    pub body_read: Duration,
+    pub handler: Option<Duration>,
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header_read: Duration::from_secs(10),
+            body_read: Duration::from_secs(30),
+            handler: None,
+            write: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time from accepting the connection until the request head is read.
+    pub fn header_read(mut self, timeout: Duration) -> Self {
+        self.header_read = timeout;
+        self
+    }
+
+    /// Time from the end of the head until the body is read.
+    pub fn body_read(mut self, timeout: Duration) -> Self {
+        self.body_read = timeout;
+        self
+    }
+
+    /// Time the middlewares and handler may take. Disabled by default, as
+    /// they then run on a separate pool with as many threads as the server
+    /// has workers. An overrunning handler keeps its thread until it
+    /// returns; requests that wait out their deadline for a free thread get
+    /// the `503` without running.
+    pub fn handler(mut self, timeout: Duration) -> Self {
+        self.handler = Some(timeout);
+        self
+    }
+
+    /// Time to write the whole response. File bodies may take longer, as
+    /// long as every write makes progress within this time.
+    pub fn write(mut self, timeout: Duration) -> Self {
+        self.write = timeout;
+        self
+    }
+}
+
+/// Streams whose blocking reads and writes can be bounded, so a deadline
+/// also interrupts a peer that sends or receives nothing at all.
+pub trait SetTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl SetTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl<S: SetTimeout + ?Sized> SetTimeout for &mut S {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+}
+
+/// Fails reads and writes with `TimedOut` once the deadline has passed,
+/// however steadily the peer trickles data.
+pub(crate) struct DeadlineStream<S> {
+    inner: S,
+    deadline: Instant,
+}
+
+impl<S: SetTimeout> DeadlineStream<S> {
+    pub(crate) fn new(inner: S, timeout: Duration) -> Self {
+        Self { inner, deadline: Instant::now() + timeout }
+    }
+
+    pub(crate) fn into_inner(self) -> S {
+        self.inner
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.deadline = Instant::now() + timeout;
+    }
+
+    fn remaining(&self) -> io::Result<Duration> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => Ok(remaining),
+            _ => Err(io::Error::new(ErrorKind::TimedOut, "deadline expired")),
+        }
+    }
+}
+
+impl<S: Read + SetTimeout> Read for DeadlineStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.set_read_timeout(Some(self.remaining()?))?;
+        let len = self.inner.read(buf)?;
+        self.remaining()?;
+        Ok(len)
+    }
+}
+
+impl<S: Write + SetTimeout> Write for DeadlineStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.set_write_timeout(Some(self.remaining()?))?;
+        self.inner.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.inner.set_write_timeout(Some(self.remaining()?))?;
+        self.inner.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::thread;
+
+    use super::*;
+
+    /// An in-memory connection that delivers its input `chunk` bytes at a
+    /// time, sleeping `delay` before each read, and records what is written.
+    pub(crate) struct SlowStream {
+        input: Vec<u8>,
+        position: usize,
+        chunk: usize,
+        delay: Duration,
+        pub(crate) output: Vec<u8>,
+    }
+
+    impl SlowStream {
+        pub(crate) fn new(input: &[u8], chunk: usize, delay: Duration) -> Self {
+            Self { input: input.to_vec(), position: 0, chunk, delay, output: vec![] }
+        }
+    }
+
+    impl Read for SlowStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(self.delay);
+            let end = (self.position + self.chunk.min(buf.len())).min(self.input.len());
+            let len = end - self.position;
+            buf[..len].copy_from_slice(&self.input[self.position..end]);
+            self.position = end;
+            Ok(len)
+        }
+    }
+
+    impl Write for SlowStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SetTimeout for SlowStream {
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl crate::http2::Socket for SlowStream {
+        fn tcp_stream(&self) -> io::Result<TcpStream> {
+            Err(io::Error::new(ErrorKind::Unsupported, "not a socket"))
+        }
+    }
+
+    #[test]
+    fn test_deadline_stops_dripping_reader() {
+        let slow = SlowStream::new(b"GET / HTTP/1.1\r\n\r\n", 1, Duration::from_millis(5));
+        let mut stream = DeadlineStream::new(slow, Duration::from_millis(30));
+        let mut buf = vec![];
+        let error = stream.read_to_end(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(buf.len() < 10);
+    }
+
+    #[test]
+    fn test_deadline_allows_fast_reader() {
+        let fast = SlowStream::new(b"GET / HTTP/1.1\r\n\r\n", 64, Duration::ZERO);
+        let mut stream = DeadlineStream::new(fast, Duration::from_secs(1));
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"GET / HTTP/1.1\r\n\r\n");
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use fast_web_server_types::{HttpRequest, HttpResponse, StatusCode};
+use thiserror::Error;
+
+use crate::http2;
+use crate::streaming::StreamConnection;
+use crate::timeouts::SetTimeout;
+
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("could not read {0:?}: {1}")]
+    Pem(PathBuf, pem::Error),
+    #[error("no certificates found in {0:?}")]
+    NoCertificates(PathBuf),
+    #[error("invalid certificate or key {0:?}: {1}")]
+    InvalidKey(PathBuf, rustls::Error),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+/// A PEM certificate chain and the PEM private key that goes with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CertificateFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl CertificateFiles {
+    fn load(&self, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>, TlsError> {
+        let chain = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| TlsError::Pem(self.cert.clone(), e))?;
+        if chain.is_empty() {
+            return Err(TlsError::NoCertificates(self.cert.clone()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|e| TlsError::Pem(self.key.clone(), e))?;
+        let certified_key = CertifiedKey::from_der(chain, key, provider)
+            .map_err(|e| TlsError::InvalidKey(self.key.clone(), e))?;
+        Ok(Arc::new(certified_key))
+    }
+}
+
+/// HTTPS settings: a default certificate, optional per-hostname
+/// certificates picked by SNI, and the ALPN protocols to offer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    default: CertificateFiles,
+    sni: Vec<(String, CertificateFiles)>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl TlsConfig {
+    /// Serves the certificate chain in `cert` with the private key in `key`,
+    /// both PEM files, to clients that send no or an unknown server name.
+    pub fn new(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Self {
+        Self {
+            default: CertificateFiles { cert: cert.as_ref().into(), key: key.as_ref().into() },
+            sni: vec![],
+            alpn: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        }
+    }
+
+    /// Serves a different certificate to clients asking for `hostname`.
+    pub fn sni(mut self, hostname: &str, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Self {
+        let files = CertificateFiles { cert: cert.as_ref().into(), key: key.as_ref().into() };
+        self.sni.push((hostname.to_ascii_lowercase(), files));
+        self
+    }
+
+    /// Protocols offered during ALPN, in order of preference. `h2` and
+    /// `http/1.1` by default; leave out `h2` to serve only HTTP/1.1.
+    pub fn alpn(mut self, protocols: &[&str]) -> Self {
+        self.alpn = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Certificates {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// Picks the certificate by SNI and can swap all certificates at runtime.
+#[derive(Debug)]
+struct CertificateResolver {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    certificates: RwLock<Arc<Certificates>>,
+}
+
+impl CertificateResolver {
+    fn new(config: TlsConfig, provider: Arc<CryptoProvider>) -> Result<Self, TlsError> {
+        let certificates = Self::load(&config, &provider)?;
+        Ok(Self { config, provider, certificates: RwLock::new(Arc::new(certificates)) })
+    }
+
+    fn load(config: &TlsConfig, provider: &CryptoProvider) -> Result<Certificates, TlsError> {
+        let default = config.default.load(provider)?;
+        let by_name = config.sni.iter()
+            .map(|(hostname, files)| Ok((hostname.clone(), files.load(provider)?)))
+            .collect::<Result<_, TlsError>>()?;
+        Ok(Certificates { default, by_name })
+    }
+
+    /// Reloads every certificate from disk. On error the old ones stay in use.
+    fn reload(&self) -> Result<(), TlsError> {
+        let certificates = Self::load(&self.config, &self.provider)?;
+        *self.certificates.write().unwrap() = Arc::new(certificates);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read().unwrap().clone();
+        let by_name = client_hello.server_name()
+            .and_then(|name| certificates.by_name.get(&name.to_ascii_lowercase()));
+        Some(by_name.unwrap_or(&certificates.default).clone())
+    }
+}
+
+/// Performs TLS handshakes on accepted connections.
+#[derive(Debug)]
+pub(crate) struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+    resolver: Arc<CertificateResolver>,
+}
+
+impl TlsAcceptor {
+    pub(crate) fn new(config: TlsConfig) -> Result<Self, TlsError> {
+        let provider = Arc::new(ring::default_provider());
+        let alpn = config.alpn.clone();
+        let resolver = Arc::new(CertificateResolver::new(config, provider.clone())?);
+        let mut server_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        server_config.alpn_protocols = alpn;
+        Ok(Self { config: Arc::new(server_config), resolver })
+    }
+
+    pub(crate) fn reload(&self) -> Result<(), TlsError> {
+        self.resolver.reload()
+    }
+
+    /// Completes the handshake within `timeout`.
+    pub(crate) fn accept(&self, stream: TcpStream, timeout: Duration) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        let mut stream = StreamOwned::new(connection, stream);
+        stream.sock.set_read_timeout(Some(timeout))?;
+        stream.sock.set_write_timeout(Some(timeout))?;
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        Ok(TlsStream(stream))
+    }
+}
+
+/// A server-side TLS connection over TCP.
+pub struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl TlsStream {
+    /// The protocol agreed on during ALPN, e.g. `b"http/1.1"`.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.0.conn.alpn_protocol()
+    }
+
+    pub fn server_name(&self) -> Option<&str> {
+        self.0.conn.server_name()
+    }
+
+    /// Sends `close_notify` so the client can tell the response is complete.
+    pub(crate) fn close(&mut self) -> io::Result<()> {
+        self.0.conn.send_close_notify();
+        self.0.flush()
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.0.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl SetTimeout for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_write_timeout(timeout)
+    }
+}
+
+impl http2::Socket for TlsStream {
+    fn tcp_stream(&self) -> io::Result<TcpStream> {
+        self.0.sock.try_clone()
+    }
+
+    fn has_buffered(&mut self) -> bool {
+        // An error is reported by the next read.
+        self.0.conn.process_new_packets().map_or(true, |state| state.plaintext_bytes_to_read() > 0)
+    }
+}
+
+impl StreamConnection for TlsStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.0.sock.set_nonblocking(nonblocking)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.close()?;
+        self.0.sock.shutdown(Shutdown::Write)
+    }
+}
+
+/// Redirects a plain HTTP request to the same host and target on the HTTPS port.
+pub(crate) fn https_redirect(request: &HttpRequest, https_port: u16) -> HttpResponse {
+    let host = match request.headers.get("Host") {
+        Some(host) => host,
+        None => return HttpResponse::json_error(StatusCode::Code400, "bad_request", Some("missing Host header")),
+    };
+    // Keep bracketed IPv6 literals intact while dropping the port.
+    let hostname = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host.as_str(),
+    };
+    let target = &request.start_line.request_target.raw;
+    let target = if target.starts_with('/') { target.as_str() } else { "/" };
+    let location = match https_port {
+        443 => format!("https://{}{}", hostname, target),
+        port => format!("https://{}:{}{}", hostname, port, target),
+    };
+    let mut response = HttpResponse::with_status(StatusCode::Code308, "");
+    response.headers.insert("Location".to_string(), location);
+    response
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use rustls::pki_types::ServerName;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// A self-signed certificate for `hostname`, written as PEM files.
+    pub(crate) struct SelfSigned {
+        pub(crate) cert: PathBuf,
+        pub(crate) key: PathBuf,
+        pub(crate) der: CertificateDer<'static>,
+    }
+
+    pub(crate) fn self_signed(dir: &Path, hostname: &str) -> SelfSigned {
+        let certified = rcgen::generate_simple_self_signed(vec![hostname.to_string()]).unwrap();
+        let (cert, key) = (dir.join(format!("{}.crt", hostname)), dir.join(format!("{}.key", hostname)));
+        fs::write(&cert, certified.cert.pem()).unwrap();
+        fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        SelfSigned { cert, key, der: certified.cert.der().clone() }
+    }
+
+    /// A client connection to `hostname` trusting only `root`.
+    pub(crate) fn client(root: &CertificateDer<'static>, hostname: &str, alpn: &[&str]) -> ClientConnection {
+        let mut roots = RootCertStore::empty();
+        roots.add(root.clone()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        let name = ServerName::try_from(hostname.to_string()).unwrap();
+        ClientConnection::new(Arc::new(config), name).unwrap()
+    }
+
+    /// Connects to `addr` trusting only `root`, and returns the negotiated ALPN protocol.
+    pub(crate) fn handshake(addr: std::net::SocketAddr, root: &CertificateDer<'static>, hostname: &str, alpn: &[&str]) -> Result<Option<Vec<u8>>, io::Error> {
+        let mut connection = client(root, hostname, alpn);
+        let mut socket = TcpStream::connect(addr)?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket)?;
+        }
+        Ok(connection.alpn_protocol().map(<[u8]>::to_vec))
+    }
+
+    /// Accepts `count` connections with `acceptor` on a background thread.
+    fn serve(acceptor: Arc<TlsAcceptor>, count: usize) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let _ = acceptor.accept(stream.unwrap(), Duration::from_secs(5)).map(|mut tls| tls.close());
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_sni_and_alpn() {
+        let dir = TempDir::new("tls-sni");
+        let localhost = self_signed(&dir, "localhost");
+        let example = self_signed(&dir, "example.test");
+        let config = TlsConfig::new(&localhost.cert, &localhost.key)
+            .sni("example.test", &example.cert, &example.key)
+            .alpn(&["h2", "http/1.1"]);
+        let addr = serve(Arc::new(TlsAcceptor::new(config).unwrap()), 3);
+
+        assert_eq!(handshake(addr, &localhost.der, "localhost", &["http/1.1"]).unwrap(), Some(b"http/1.1".to_vec()));
+        assert!(handshake(addr, &example.der, "example.test", &[]).is_ok());
+        assert!(handshake(addr, &localhost.der, "example.test", &[]).is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = TempDir::new("tls-reload");
+        let old = self_signed(&dir, "localhost");
+        let acceptor = Arc::new(TlsAcceptor::new(TlsConfig::new(&old.cert, &old.key)).unwrap());
+        let addr = serve(acceptor.clone(), 2);
+        assert!(handshake(addr, &old.der, "localhost", &[]).is_ok());
+
+        let new = self_signed(&dir, "localhost");
+        acceptor.reload().unwrap();
+        assert!(handshake(addr, &new.der, "localhost", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_https_redirect() {
+        let location = |raw: &str, port: u16| {
+            let request = HttpRequest::new(&mut io::Cursor::new(raw.as_bytes())).unwrap();
+            let response = https_redirect(&request, port);
+            assert_eq!(response.status_line.status_code, StatusCode::Code308);
+            response.headers.get("Location").cloned()
+        };
+        assert_eq!(location("GET /a?b=c HTTP/1.1\r\nHost: example.test:80\r\n\r\n", 443).unwrap(), "https://example.test/a?b=c");
+        assert_eq!(location("GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n", 8443).unwrap(), "https://[::1]:8443/");
+        assert_eq!(location("GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n", 443).unwrap(), "https://[::1]/");
+        let request = HttpRequest::new(&mut io::Cursor::new(&b"GET / HTTP/1.1\r\n\r\n"[..])).unwrap();
+        assert_eq!(https_redirect(&request, 443).status_line.status_code, StatusCode::Code400);
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let error = TlsAcceptor::new(TlsConfig::new("/nonexistent.crt", "/nonexistent.key")).unwrap_err();
+        assert!(matches!(error, TlsError::Pem(path, _) if path == Path::new("/nonexistent.crt")));
+    }
+}
@@ -0,0 +1,190 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+
+/// The end of a sync-flushed deflate block, left off the wire by permessage-deflate.
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The permessage-deflate parameters agreed on in the handshake, RFC 7692.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct DeflateParams {
+    pub(crate) server_no_context_takeover: bool,
+    pub(crate) client_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    /// Accepts the first permessage-deflate offer of a
+    /// `Sec-WebSocket-Extensions` header the server can honour. Offers that
+    /// limit the server's window below 32 KiB are declined.
+    pub(crate) fn negotiate(extensions: &str) -> Option<Self> {
+        extensions.split(',').find_map(|offer| {
+            let mut parts = offer.split(';').map(str::trim);
+            if !parts.next()?.eq_ignore_ascii_case("permessage-deflate") {
+                return None;
+            }
+            let mut params = Self::default();
+            let mut seen = vec![];
+            for part in parts {
+                let (name, value) = match part.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (part, None),
+                };
+                if seen.contains(&name) {
+                    return None;
+                }
+                seen.push(name);
+                match (name, value) {
+                    ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                    ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                    ("server_max_window_bits", Some("15")) => {},
+                    ("client_max_window_bits", None) => {},
+                    ("client_max_window_bits", Some(bits)) if bits.parse().is_ok_and(|bits: u8| (8..=15).contains(&bits)) => {},
+                    _ => return None,
+                }
+            }
+            Some(params)
+        })
+    }
+
+    /// The `Sec-WebSocket-Extensions` value of the handshake response.
+    pub(crate) fn response_header(&self) -> String {
+        let mut header = String::from("permessage-deflate");
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        header
+    }
+}
+
+/// Compresses outgoing and decompresses incoming messages, keeping the
+/// sliding windows between messages unless context takeover was declined.
+pub(crate) struct Deflate {
+    params: DeflateParams,
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl Deflate {
+    pub(crate) fn new(params: DeflateParams) -> Self {
+        Self {
+            params,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        }
+    }
+
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity());
+            }
+            // Compressing into memory cannot fail.
+            self.compress.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync).unwrap();
+            if self.compress.total_in() - start == data.len() as u64 && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&TRAILER) {
+            out.truncate(out.len() - TRAILER.len());
+        }
+        if self.params.server_no_context_takeover {
+            self.compress.reset();
+        }
+        out
+    }
+
+    /// Decompresses a message, failing on invalid data or as soon as the
+    /// output exceeds `limit` bytes.
+    pub(crate) fn decompress(&mut self, data: &[u8], limit: usize) -> Result<Vec<u8>, DecompressError> {
+        let input = [data, &TRAILER].concat();
+        let mut out = Vec::with_capacity((data.len() * 2 + 64).min(limit + 1));
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                if out.len() > limit {
+                    return Err(DecompressError::TooBig);
+                }
+                out.reserve(out.capacity().min(limit + 1 - out.len()));
+            }
+            let before = (self.decompress.total_in(), out.len());
+            let status = self.decompress.decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|_| DecompressError::Invalid)?;
+            let done = self.decompress.total_in() - start == input.len() as u64 && out.len() < out.capacity();
+            if done || status == Status::StreamEnd {
+                break;
+            }
+            if (self.decompress.total_in(), out.len()) == before && out.len() < out.capacity() {
+                return Err(DecompressError::Invalid);
+            }
+        }
+        if out.len() > limit {
+            return Err(DecompressError::TooBig);
+        }
+        if self.params.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DecompressError {
+    Invalid,
+    TooBig,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(DeflateParams::negotiate("permessage-deflate; client_max_window_bits"), Some(DeflateParams::default()));
+        let params = DeflateParams::negotiate("x-webkit-deflate-frame, permessage-deflate; server_no_context_takeover; client_no_context_takeover").unwrap();
+        assert_eq!(params.response_header(), "permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+        // A smaller server window is declined, so the next offer is taken.
+        let params = DeflateParams::negotiate("permessage-deflate; server_max_window_bits=10, permessage-deflate; client_no_context_takeover").unwrap();
+        assert!(params.client_no_context_takeover && !params.server_no_context_takeover);
+        assert_eq!(DeflateParams::negotiate("permessage-deflate; server_max_window_bits=10"), None);
+        assert_eq!(DeflateParams::negotiate("permessage-deflate; unknown"), None);
+        assert_eq!(DeflateParams::negotiate("permessage-deflate; client_no_context_takeover; client_no_context_takeover"), None);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        // The server's compressor feeds a decompressor standing in for the client.
+        let mut server = Deflate::new(DeflateParams::default());
+        let mut client = Deflate::new(DeflateParams::default());
+        let message = b"Hello, Hello, Hello, Hello!".repeat(100);
+        let first = server.compress(&message);
+        assert!(first.len() < message.len() / 10);
+        assert_eq!(client.decompress(&first, 1 << 20).unwrap(), message);
+        // The second message refers back to the first one's window.
+        let second = server.compress(&message);
+        assert!(second.len() < first.len());
+        assert_eq!(client.decompress(&second, 1 << 20).unwrap(), message);
+        assert_eq!(client.decompress(&server.compress(b""), 1 << 20).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_rfc_example() {
+        // RFC 7692 section 7.2.3.1: "Hello" in one compressed message.
+        let mut deflate = Deflate::new(DeflateParams::default());
+        assert_eq!(deflate.decompress(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 100).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut server = Deflate::new(DeflateParams { server_no_context_takeover: true, client_no_context_takeover: false });
+        let bomb = server.compress(&vec![0; 1 << 20]);
+        let mut client = Deflate::new(DeflateParams::default());
+        assert_eq!(client.decompress(&bomb, 1000), Err(DecompressError::TooBig));
+        assert_eq!(Deflate::new(DeflateParams::default()).decompress(&[0xff, 0xff, 0xff], 100), Err(DecompressError::Invalid));
+    }
+}
@@ -0,0 +1,290 @@
+mod deflate;
+mod socket;
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use fast_web_server_types::{HttpRequest, HttpResponse, HttpVersion, StatusCode};
+use tracing::debug;
+
+pub use crate::websocket::socket::{CloseCode, CloseFrame, Message, WebSocket, WebSocketError};
+use crate::fast_web_server::ServerContext;
+use crate::timeouts::SetTimeout;
+use crate::websocket::deflate::{Deflate, DeflateParams};
+
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Settings of the sockets handed to `#[websocket]` handlers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketConfig {
+    pub max_message_size: usize,
+    pub fragment_size: usize,
+    pub permessage_deflate: bool,
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: 16 * 1024 * 1024,
+            fragment_size: 64 * 1024,
+            permessage_deflate: true,
+            idle_timeout: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl WebSocketConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Largest message a client may send, after decompression.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Largest frame payload the server sends; longer messages are fragmented.
+    pub fn fragment_size(mut self, fragment_size: usize) -> Self {
+        self.fragment_size = fragment_size;
+        self
+    }
+
+    /// Whether to accept a client's permessage-deflate offer.
+    pub fn permessage_deflate(mut self, permessage_deflate: bool) -> Self {
+        self.permessage_deflate = permessage_deflate;
+        self
+    }
+
+    /// Time `WebSocket::recv` waits for data before failing with `TimedOut`,
+    /// 60 seconds by default. Since every open socket holds a worker thread,
+    /// this frees the workers of clients that went silent; handlers of
+    /// quieter protocols can raise it, or set the field to `None`.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+}
+
+type Callback = Box<dyn for<'a> FnOnce(WebSocket<'a>) + Send>;
+
+/// A handshake the handler accepted, run once the `101` response is written.
+pub(crate) struct Accepted {
+    callback: Callback,
+    deflate: Option<DeflateParams>,
+}
+
+/// Stored in the extensions of HTTP/1.1 upgrade requests so the handler can
+/// hand the connection's next owner back to the server.
+#[derive(Clone)]
+pub(crate) struct UpgradeSlot {
+    config: WebSocketConfig,
+    accepted: Arc<Mutex<Option<Accepted>>>,
+}
+
+impl UpgradeSlot {
+    /// A slot for `request` if it asks for a WebSocket upgrade.
+    pub(crate) fn for_request(request: &HttpRequest, config: &WebSocketConfig) -> Option<Self> {
+        has_token(request, "Upgrade", "websocket").then(|| Self { config: config.clone(), accepted: Arc::default() })
+    }
+
+    pub(crate) fn take(&self) -> Option<Accepted> {
+        self.accepted.lock().unwrap().take()
+    }
+}
+
+fn has_token(request: &HttpRequest, header: &str, token: &str) -> bool {
+    request.headers.get(header)
+        .is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(sha1.digest().bytes())
+}
+
+/// A validated RFC 6455 opening handshake. `#[websocket]` routes build it
+/// before extracting their other arguments; other handlers can use it
+/// directly:
+///
+/// ```ignore
+/// server.bind(RequestType::GET, "/echo", |request: HttpRequest| {
+///     WebSocketUpgrade::from_request(&request).map(|upgrade| upgrade.on_upgrade(|mut socket| {
+///         while let Ok(Message::Text(text)) = socket.recv() {
+///             let _ = socket.send(text);
+///         }
+///     }))
+/// });
+/// ```
+pub struct WebSocketUpgrade {
+    slot: UpgradeSlot,
+    accept: String,
+    deflate: Option<DeflateParams>,
+}
+
+impl WebSocketUpgrade {
+    /// Validates the handshake headers, answering an invalid handshake with
+    /// `400 Bad Request` or `426 Upgrade Required`.
+    pub fn from_request(request: &HttpRequest) -> Result<Self, HttpResponse> {
+        let bad_request = |message| HttpResponse::json_error(StatusCode::Code400, "bad_request", Some(message));
+        if request.start_line.http_version != HttpVersion::HTTP1_1 {
+            return Err(bad_request("WebSocket connections require HTTP/1.1"));
+        }
+        if !has_token(request, "Upgrade", "websocket") || !has_token(request, "Connection", "upgrade") {
+            let mut response = HttpResponse::json_error(StatusCode::Code426, "upgrade_required", Some("expected a WebSocket handshake"));
+            response.headers.insert("Upgrade".to_string(), "websocket".to_string());
+            return Err(response);
+        }
+        if request.headers.get("Sec-WebSocket-Version").map(|version| version.trim()) != Some("13") {
+            let mut response = HttpResponse::json_error(StatusCode::Code426, "upgrade_required", Some("unsupported WebSocket version"));
+            response.headers.insert("Sec-WebSocket-Version".to_string(), "13".to_string());
+            return Err(response);
+        }
+        let key = request.headers.get("Sec-WebSocket-Key").map(|key| key.trim()).unwrap_or_default();
+        if STANDARD.decode(key).map_or(true, |key| key.len() != 16) {
+            return Err(bad_request("invalid Sec-WebSocket-Key"));
+        }
+        let slot = match request.extensions.get::<UpgradeSlot>() {
+            Some(slot) => slot.clone(),
+            None => return Err(HttpResponse::json_error(StatusCode::Code500, "internal_server_error", None)),
+        };
+        let deflate = request.headers.get("Sec-WebSocket-Extensions")
+            .filter(|_| slot.config.permessage_deflate)
+            .and_then(|extensions| DeflateParams::negotiate(extensions));
+        Ok(Self { slot, accept: accept_key(key), deflate })
+    }
+
+    /// Accepts the handshake. `callback` runs with the socket on the
+    /// connection's worker thread once the `101 Switching Protocols`
+    /// response has been written, and owns that thread until it returns.
+    /// Each open socket therefore takes one worker of the server's pool
+    /// away from HTTP requests; size the pool for the expected number of
+    /// sockets, and keep `WebSocketConfig::idle_timeout` set so abandoned
+    /// ones are closed.
+    pub fn on_upgrade<F>(self, callback: F) -> HttpResponse
+    where F: FnOnce(WebSocket) + Send + 'static {
+        let mut response = HttpResponse::with_status(StatusCode::Code101, "");
+        response.headers.remove("Content-Length");
+        response.headers.insert("Connection".to_string(), "Upgrade".to_string());
+        response.headers.insert("Upgrade".to_string(), "websocket".to_string());
+        response.headers.insert("Sec-WebSocket-Accept".to_string(), self.accept);
+        if let Some(deflate) = &self.deflate {
+            response.headers.insert("Sec-WebSocket-Extensions".to_string(), deflate.response_header());
+        }
+        *self.slot.accepted.lock().unwrap() = Some(Accepted { callback: Box::new(callback), deflate: self.deflate });
+        response
+    }
+}
+
+/// Counts the bytes a socket reads and writes.
+struct Counting<'a, S> {
+    inner: &'a mut S,
+    read: u64,
+    written: u64,
+}
+
+impl<S: Read> Read for Counting<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.read += len as u64;
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for Counting<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: SetTimeout> SetTimeout for Counting<'_, S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+}
+
+/// Runs an accepted handshake's callback over the connection.
+/// `buffered` holds bytes already read from the connection.
+pub(crate) fn serve<S: Read + Write + SetTimeout>(
+    context: &ServerContext,
+    stream: &mut S,
+    buffered: Vec<u8>,
+    accepted: Accepted) -> io::Result<()> {
+
+    let config = &context.websocket;
+    stream.set_read_timeout(config.idle_timeout)?;
+    stream.set_write_timeout(Some(context.timeouts.write))?;
+    let mut stream = Counting { inner: stream, read: 0, written: 0 };
+    (accepted.callback)(WebSocket::new(&mut stream, buffered, config.clone(), accepted.deflate.map(Deflate::new)));
+    context.metrics.add_bytes_received(stream.read);
+    context.metrics.add_bytes_sent(stream.written);
+    debug!(bytes_received = stream.read, bytes_sent = stream.written, "websocket closed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn handshake(extra: &str) -> HttpRequest {
+        let raw = format!("GET /chat HTTP/1.1\r\nHost: example.test\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n", extra);
+        let mut request = HttpRequest::new(&mut Cursor::new(raw.into_bytes())).unwrap();
+        if let Some(slot) = UpgradeSlot::for_request(&request, &WebSocketConfig::default()) {
+            request.extensions.insert(slot);
+        }
+        request
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // RFC 6455 section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_handshake() {
+        let request = handshake("Sec-WebSocket-Version: 13\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n");
+        let slot = request.extensions.get::<UpgradeSlot>().unwrap().clone();
+        let response = WebSocketUpgrade::from_request(&request).unwrap().on_upgrade(|_socket| {});
+        assert_eq!(response.status_line.status_code, StatusCode::Code101);
+        assert_eq!(response.headers.get("Sec-WebSocket-Accept").unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(response.headers.get("Sec-WebSocket-Extensions").unwrap(), "permessage-deflate");
+        assert_eq!(response.headers.get("Connection").unwrap(), "Upgrade");
+        assert_eq!(response.headers.get("Content-Length"), None);
+        assert!(slot.take().is_some_and(|accepted| accepted.deflate.is_some()));
+        assert!(slot.take().is_none());
+    }
+
+    #[test]
+    fn test_invalid_handshakes() {
+        let status = |request: HttpRequest| WebSocketUpgrade::from_request(&request).err().unwrap().status_line.status_code;
+        let response = WebSocketUpgrade::from_request(&handshake("Sec-WebSocket-Version: 8\r\n")).err().unwrap();
+        assert_eq!(response.status_line.status_code, StatusCode::Code426);
+        assert_eq!(response.headers.get("Sec-WebSocket-Version").unwrap(), "13");
+        let plain = HttpRequest::new(&mut Cursor::new(b"GET /chat HTTP/1.1\r\n\r\n".to_vec())).unwrap();
+        assert_eq!(status(plain), StatusCode::Code426);
+        let bad_key = "GET /chat HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n\r\n";
+        assert_eq!(status(HttpRequest::new(&mut Cursor::new(bad_key.as_bytes().to_vec())).unwrap()), StatusCode::Code400);
+    }
+}
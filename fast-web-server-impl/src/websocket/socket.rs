@@ -0,0 +1,611 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::timeouts::SetTimeout;
+use crate::websocket::deflate::{DecompressError, Deflate};
+use crate::websocket::WebSocketConfig;
+
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const MASKED: u8 = 0x80;
+
+/// The largest payload of a control frame.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// A connection a `WebSocket` can take over.
+pub(crate) trait Transport: Read + Write + SetTimeout {}
+
+impl<S: Read + Write + SetTimeout> Transport for S {}
+
+#[derive(Debug, Error)]
+pub enum WebSocketError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("protocol violation: {0}")]
+    Protocol(&'static str),
+    #[error("text message is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("message exceeds {0} bytes")]
+    MessageTooBig(usize),
+    #[error("invalid compressed message")]
+    Compression,
+    #[error("the WebSocket is closed")]
+    Closed,
+}
+
+impl WebSocketError {
+    /// The close code sent to the client when receiving fails with this error.
+    fn close_code(&self) -> Option<CloseCode> {
+        match self {
+            WebSocketError::Protocol(_) => Some(CloseCode::ProtocolError),
+            WebSocketError::InvalidUtf8 | WebSocketError::Compression => Some(CloseCode::InvalidPayload),
+            WebSocketError::MessageTooBig(_) => Some(CloseCode::MessageTooBig),
+            WebSocketError::Io(_) | WebSocketError::Closed => None,
+        }
+    }
+}
+
+/// Status codes of a close frame, RFC 6455 section 7.4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    UnsupportedData,
+    InvalidPayload,
+    PolicyViolation,
+    MessageTooBig,
+    MandatoryExtension,
+    InternalError,
+    /// Registered codes without a variant, and application codes from 3000 to 4999.
+    Other(u16),
+}
+
+impl CloseCode {
+    pub const fn as_u16(&self) -> u16 {
+        match self {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::UnsupportedData => 1003,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::Other(code) => *code,
+        }
+    }
+
+    /// Whether the code may appear in a close frame. 1005 and 1006 only
+    /// describe a missing code or an abnormal closure locally.
+    pub fn is_allowed(&self) -> bool {
+        matches!(self.as_u16(), 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::UnsupportedData,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// A close frame, without a frame if the peer sent no status code.
+    Close(Option<CloseFrame>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
+
+struct Frame {
+    fin: bool,
+    compressed: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// A data message whose frames are still arriving.
+struct Partial {
+    opcode: u8,
+    compressed: bool,
+    data: Vec<u8>,
+}
+
+/// A WebSocket connection, handed to `#[websocket]` handlers once the
+/// handshake completed. Pings are answered automatically and a received
+/// close frame is echoed; a socket dropped without closing sends
+/// `1000 Normal`, or `1011 Internal Error` if the handler panicked.
+pub struct WebSocket<'a> {
+    stream: &'a mut dyn Transport,
+    buf: Vec<u8>,
+    config: WebSocketConfig,
+    deflate: Option<Deflate>,
+    partial: Option<Partial>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    /// `buffered` holds bytes already read from the connection.
+    pub(crate) fn new(stream: &'a mut dyn Transport, buffered: Vec<u8>, config: WebSocketConfig, deflate: Option<Deflate>) -> Self {
+        Self { stream, buf: buffered, config, deflate, partial: None, close_sent: false, close_received: false }
+    }
+
+    /// Whether messages are compressed with permessage-deflate.
+    pub fn is_compressed(&self) -> bool {
+        self.deflate.is_some()
+    }
+
+    /// Time `recv` waits for data before failing with `TimedOut`, `None` to wait indefinitely.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Receives the next message, assembling fragmented ones. Returns
+    /// `Message::Close` once when the peer closes, and `Closed` after that.
+    /// Protocol violations close the connection with the matching code.
+    pub fn recv(&mut self) -> Result<Message, WebSocketError> {
+        if self.close_received {
+            return Err(WebSocketError::Closed);
+        }
+        let result = self.read_message();
+        if let Err(error) = &result {
+            if let Some(code) = error.close_code() {
+                self.close_received = true;
+                let _ = self.send_close(Some(CloseFrame { code, reason: error.to_string() }));
+            }
+        }
+        result
+    }
+
+    /// Sends a message. Data messages longer than the configured fragment
+    /// size are split into several frames.
+    pub fn send(&mut self, message: impl Into<Message>) -> Result<(), WebSocketError> {
+        if self.close_sent {
+            return Err(WebSocketError::Closed);
+        }
+        match message.into() {
+            Message::Text(text) => self.send_data(TEXT, text.into_bytes()),
+            Message::Binary(data) => self.send_data(BINARY, data),
+            Message::Ping(payload) => self.send_control(PING, &payload),
+            Message::Pong(payload) => self.send_control(PONG, &payload),
+            Message::Close(frame) => self.send_close(frame),
+        }
+    }
+
+    /// Starts the closing handshake. `recv` keeps returning the messages
+    /// the peer sent before its close frame.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        self.send(Message::Close(Some(CloseFrame { code, reason: reason.to_string() })))
+    }
+
+    fn send_data(&mut self, opcode: u8, data: Vec<u8>) -> Result<(), WebSocketError> {
+        let (data, compressed) = match &mut self.deflate {
+            Some(deflate) => (deflate.compress(&data), true),
+            None => (data, false),
+        };
+        let mut chunks = data.chunks(self.config.fragment_size.max(1)).peekable();
+        if chunks.peek().is_none() {
+            return self.write_frame(FIN | opcode | if compressed { RSV1 } else { 0 }, &[]);
+        }
+        let mut first = true;
+        while let Some(chunk) = chunks.next() {
+            let mut header = if first { opcode } else { CONTINUATION };
+            if first && compressed {
+                header |= RSV1;
+            }
+            if chunks.peek().is_none() {
+                header |= FIN;
+            }
+            self.write_frame(header, chunk)?;
+            first = false;
+        }
+        Ok(())
+    }
+
+    fn send_control(&mut self, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(WebSocketError::MessageTooBig(MAX_CONTROL_PAYLOAD));
+        }
+        self.write_frame(FIN | opcode, payload)
+    }
+
+    fn send_close(&mut self, frame: Option<CloseFrame>) -> Result<(), WebSocketError> {
+        if self.close_sent {
+            return Err(WebSocketError::Closed);
+        }
+        let mut payload = vec![];
+        if let Some(frame) = frame {
+            if !frame.code.is_allowed() {
+                return Err(WebSocketError::Protocol("close code may not be sent"));
+            }
+            payload.extend(frame.code.as_u16().to_be_bytes());
+            let mut reason = frame.reason.as_str();
+            while reason.len() > MAX_CONTROL_PAYLOAD - 2 {
+                let mut end = MAX_CONTROL_PAYLOAD - 2;
+                while !reason.is_char_boundary(end) {
+                    end -= 1;
+                }
+                reason = &reason[..end];
+            }
+            payload.extend(reason.as_bytes());
+        }
+        self.close_sent = true;
+        self.write_frame(FIN | CLOSE, &payload)
+    }
+
+    fn write_frame(&mut self, header: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(header);
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend((len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend((len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn read_message(&mut self) -> Result<Message, WebSocketError> {
+        loop {
+            let received = self.partial.as_ref().map_or(0, |partial| partial.data.len());
+            let frame = self.read_frame(self.config.max_message_size - received)?;
+            if frame.opcode & 0x8 != 0 {
+                if !frame.fin || frame.payload.len() > MAX_CONTROL_PAYLOAD {
+                    return Err(WebSocketError::Protocol("control frames must be short and unfragmented"));
+                }
+                if frame.compressed {
+                    return Err(WebSocketError::Protocol("control frames cannot be compressed"));
+                }
+            }
+            match frame.opcode {
+                PING => {
+                    if !self.close_sent {
+                        self.write_frame(FIN | PONG, &frame.payload)?;
+                    }
+                    return Ok(Message::Ping(frame.payload));
+                },
+                PONG => return Ok(Message::Pong(frame.payload)),
+                CLOSE => {
+                    let close = parse_close(&frame.payload)?;
+                    self.close_received = true;
+                    if !self.close_sent {
+                        let echo = close.as_ref().map(|close| CloseFrame { code: close.code, reason: String::new() });
+                        self.send_close(echo)?;
+                    }
+                    return Ok(Message::Close(close));
+                },
+                TEXT | BINARY if self.partial.is_some() => return Err(WebSocketError::Protocol("expected a continuation frame")),
+                TEXT | BINARY => {
+                    if frame.compressed && self.deflate.is_none() {
+                        return Err(WebSocketError::Protocol("reserved bit set without an extension"));
+                    }
+                    self.partial = Some(Partial { opcode: frame.opcode, compressed: frame.compressed, data: frame.payload });
+                },
+                CONTINUATION => {
+                    let partial = self.partial.as_mut()
+                        .ok_or(WebSocketError::Protocol("continuation frame without a message"))?;
+                    if frame.compressed {
+                        return Err(WebSocketError::Protocol("reserved bit set on a continuation frame"));
+                    }
+                    partial.data.extend_from_slice(&frame.payload);
+                },
+                _ => return Err(WebSocketError::Protocol("unknown opcode")),
+            }
+            if frame.fin {
+                let partial = self.partial.take().unwrap();
+                return self.finish_message(partial);
+            }
+        }
+    }
+
+    fn finish_message(&mut self, partial: Partial) -> Result<Message, WebSocketError> {
+        let limit = self.config.max_message_size;
+        let data = match (&mut self.deflate, partial.compressed) {
+            (Some(deflate), true) => deflate.decompress(&partial.data, limit).map_err(|e| match e {
+                DecompressError::Invalid => WebSocketError::Compression,
+                DecompressError::TooBig => WebSocketError::MessageTooBig(limit),
+            })?,
+            _ => partial.data,
+        };
+        match partial.opcode {
+            TEXT => String::from_utf8(data).map(Message::Text).map_err(|_| WebSocketError::InvalidUtf8),
+            _ => Ok(Message::Binary(data)),
+        }
+    }
+
+    /// Reads a frame whose payload may be at most `limit` bytes long.
+    fn read_frame(&mut self, limit: usize) -> Result<Frame, WebSocketError> {
+        self.fill(2)?;
+        let (first, second) = (self.buf[0], self.buf[1]);
+        if first & 0x30 != 0 {
+            return Err(WebSocketError::Protocol("reserved bit set without an extension"));
+        }
+        if second & MASKED == 0 {
+            return Err(WebSocketError::Protocol("client frames must be masked"));
+        }
+        let (len, offset) = match second & 0x7f {
+            126 => {
+                self.fill(4)?;
+                (u16::from_be_bytes([self.buf[2], self.buf[3]]) as u64, 4)
+            },
+            127 => {
+                self.fill(10)?;
+                (u64::from_be_bytes(self.buf[2..10].try_into().unwrap()), 10)
+            },
+            len => (len as u64, 2),
+        };
+        if len > limit as u64 {
+            return Err(WebSocketError::MessageTooBig(self.config.max_message_size));
+        }
+        let end = offset + 4 + len as usize;
+        self.fill(end)?;
+        let mask: [u8; 4] = self.buf[offset..offset + 4].try_into().unwrap();
+        let payload = self.buf[offset + 4..end].iter()
+            .zip(mask.iter().cycle())
+            .map(|(byte, mask)| byte ^ mask)
+            .collect();
+        self.buf.drain(..end);
+        Ok(Frame { fin: first & FIN != 0, compressed: first & RSV1 != 0, opcode: first & 0x0f, payload })
+    }
+
+    /// Reads until at least `len` bytes are buffered.
+    fn fill(&mut self, len: usize) -> io::Result<()> {
+        let mut chunk = [0u8; 16 * 1024];
+        while self.buf.len() < len {
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WebSocket<'_> {
+    fn drop(&mut self) {
+        if !self.close_sent {
+            let code = if thread::panicking() { CloseCode::InternalError } else { CloseCode::Normal };
+            let _ = self.send_close(Some(CloseFrame { code, reason: String::new() }));
+        }
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, WebSocketError> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err(WebSocketError::Protocol("close frame with a one byte payload")),
+        [high, low, reason @ ..] => {
+            let code = CloseCode::from(u16::from_be_bytes([*high, *low]));
+            if !code.is_allowed() {
+                return Err(WebSocketError::Protocol("invalid close code"));
+            }
+            let reason = String::from_utf8(reason.to_vec()).map_err(|_| WebSocketError::InvalidUtf8)?;
+            Ok(Some(CloseFrame { code, reason }))
+        },
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+    use crate::websocket::deflate::DeflateParams;
+
+    /// Encodes a masked client frame.
+    pub(crate) fn client_frame(header: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![header];
+        match payload.len() {
+            len @ 0..=125 => frame.push(MASKED | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(MASKED | 126);
+                frame.extend((len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(MASKED | 127);
+                frame.extend((len as u64).to_be_bytes());
+            },
+        }
+        frame.extend(mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(byte, mask)| byte ^ mask));
+        frame
+    }
+
+    /// Reads an unmasked server frame: header byte and payload.
+    pub(crate) fn server_frame(stream: &mut impl Read) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).unwrap();
+        let len = match head[1] {
+            126 => {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            },
+            127 => {
+                let mut len = [0u8; 8];
+                stream.read_exact(&mut len).unwrap();
+                u64::from_be_bytes(len) as usize
+            },
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+
+    /// Runs `handler` on a server socket, returning the client end.
+    fn connect<F>(config: WebSocketConfig, deflate: Option<DeflateParams>, handler: F) -> (TcpStream, thread::JoinHandle<()>)
+    where F: FnOnce(WebSocket) + Send + 'static {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            handler(WebSocket::new(&mut stream, vec![], config, deflate.map(Deflate::new)));
+        });
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_echo_and_fragmentation() {
+        let (mut client, server) = connect(WebSocketConfig::new().fragment_size(4), None, |mut socket| {
+            while let Ok(message) = socket.recv() {
+                match message {
+                    Message::Text(_) | Message::Binary(_) => socket.send(message).unwrap(),
+                    _ => {},
+                }
+            }
+        });
+        client.write_all(&client_frame(FIN | TEXT, "héllo".as_bytes())).unwrap();
+        assert_eq!(server_frame(&mut client), (TEXT, b"h\xc3\xa9l".to_vec()));
+        assert_eq!(server_frame(&mut client), (FIN | CONTINUATION, b"lo".to_vec()));
+
+        // A fragmented binary message with a ping in between.
+        client.write_all(&client_frame(BINARY, &[1, 2])).unwrap();
+        client.write_all(&client_frame(FIN | PING, b"are you there")).unwrap();
+        client.write_all(&client_frame(FIN | CONTINUATION, &[3])).unwrap();
+        assert_eq!(server_frame(&mut client), (FIN | PONG, b"are you there".to_vec()));
+        assert_eq!(server_frame(&mut client), (FIN | BINARY, vec![1, 2, 3]));
+
+        client.write_all(&client_frame(FIN | CLOSE, &[0x03, 0xe8, b'b', b'y', b'e'])).unwrap();
+        assert_eq!(server_frame(&mut client), (FIN | CLOSE, vec![0x03, 0xe8]));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let cases: [(Vec<u8>, u16); 6] = [
+            (vec![FIN | TEXT, 0], 1002),
+            (client_frame(FIN | TEXT, &[0xff, 0xfe]), 1007),
+            (client_frame(FIN | CONTINUATION, b"x"), 1002),
+            (client_frame(PING, b""), 1002),
+            (client_frame(FIN | TEXT | RSV1, b"x"), 1002),
+            (client_frame(FIN | CLOSE, &[0x03, 0xed]), 1002),
+        ];
+        for (input, code) in cases {
+            let (mut client, server) = connect(WebSocketConfig::new(), None, |mut socket| {
+                assert!(socket.recv().is_err());
+                assert!(matches!(socket.recv(), Err(WebSocketError::Closed)));
+            });
+            client.write_all(&input).unwrap();
+            let (header, payload) = server_frame(&mut client);
+            assert_eq!((header, u16::from_be_bytes([payload[0], payload[1]])), (FIN | CLOSE, code));
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_message_size_limit() {
+        let (mut client, server) = connect(WebSocketConfig::new().max_message_size(8), None, |mut socket| {
+            assert_eq!(socket.recv().unwrap(), Message::Text("12345".to_string()));
+            assert!(matches!(socket.recv(), Err(WebSocketError::MessageTooBig(8))));
+        });
+        client.write_all(&client_frame(FIN | TEXT, b"12345")).unwrap();
+        client.write_all(&client_frame(TEXT, b"12345")).unwrap();
+        client.write_all(&client_frame(FIN | CONTINUATION, b"12345")).unwrap();
+        let (_, payload) = server_frame(&mut client);
+        assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), 1009);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_permessage_deflate() {
+        let (mut client, server) = connect(WebSocketConfig::new(), Some(DeflateParams::default()), |mut socket| {
+            assert!(socket.is_compressed());
+            let message = socket.recv().unwrap();
+            socket.send(message).unwrap();
+            socket.close(CloseCode::GoingAway, "shutting down").unwrap();
+            assert!(matches!(socket.send("late"), Err(WebSocketError::Closed)));
+            assert_eq!(socket.recv().unwrap(), Message::Close(None));
+        });
+        let mut deflate = Deflate::new(DeflateParams::default());
+        let text = "compress me ".repeat(50);
+        client.write_all(&client_frame(FIN | RSV1 | TEXT, &deflate.compress(text.as_bytes()))).unwrap();
+        let (header, payload) = server_frame(&mut client);
+        assert_eq!(header, FIN | RSV1 | TEXT);
+        assert!(payload.len() < 100);
+        assert_eq!(deflate.decompress(&payload, 1 << 20).unwrap(), text.as_bytes());
+
+        let (header, payload) = server_frame(&mut client);
+        assert_eq!((header, &payload[..2], &payload[2..]), (FIN | CLOSE, &[0x03, 0xe9][..], &b"shutting down"[..]));
+        client.write_all(&client_frame(FIN | CLOSE, b"")).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_on_drop() {
+        let (mut client, server) = connect(WebSocketConfig::new(), None, |socket| drop(socket));
+        assert_eq!(server_frame(&mut client), (FIN | CLOSE, vec![0x03, 0xe8]));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_codes() {
+        assert_eq!(CloseCode::from(1009), CloseCode::MessageTooBig);
+        assert_eq!(CloseCode::from(4000).as_u16(), 4000);
+        assert!(CloseCode::from(4999).is_allowed());
+        for code in [999, 1004, 1005, 1006, 1015, 2999, 5000] {
+            assert!(!CloseCode::from(code).is_allowed());
+        }
+    }
+}
@@ -1,98 +1,557 @@
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
-use quote::quote;
-use quote::ToTokens;
-use syn::LitStr;
-use syn::{ItemFn, parse_macro_input, parse_quote, Stmt, ItemStruct};
-// use fast_web_server_types::{HttpFn, HttpRequest, HttpResponse, RequestType};
+use quote::{format_ident, quote, quote_spanned};
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::{FnArg, Ident, LitStr, Pat, Token, Type};
+use syn::{Attribute, ItemFn, parse_macro_input, parse_quote};
+use fast_web_server_types::{RequestType, RoutePattern};
 
-// struct Route {
-//     request_type: RequestType,
-//     route: String,
-//     func: HttpFn,
-// }
 
-// struct RouteHandler {
-//     routes: Vec<Route>,
-// }
+/// Arguments of the generic `#[route("/path", method = "GET", ...)]` attribute.
+struct RouteArgs {
+    path: LitStr,
+    methods: Vec<LitStr>,
+}
 
-// static route_handler: RouteHandler = RouteHandler {
-//     routes: vec![],
-// };
+impl Parse for RouteArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let path: LitStr = input.parse()?;
+        let mut methods = vec![];
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let key: Ident = input.parse()?;
+            if key != "method" {
+                return Err(syn::Error::new(key.span(), "expected `method = \"...\"`"));
+            }
+            input.parse::<Token![=]>()?;
+            methods.push(input.parse()?);
+        }
+        if methods.is_empty() {
+            return Err(syn::Error::new(path.span(), "route requires at least one `method = \"...\"`"));
+        }
+        Ok(Self { path, methods })
+    }
+}
 
+fn parse_method(method: &LitStr) -> syn::Result<RequestType> {
+    RequestType::from_str(&method.value())
+        .map_err(|_| syn::Error::new(method.span(), format!("invalid HTTP method `{}`", method.value())))
+}
 
-#[proc_macro_attribute]
-pub fn add_hello_world(_attr: TokenStream, item: TokenStream) -> TokenStream {
+fn parse_path(path: &LitStr) -> syn::Result<RoutePattern> {
+    RoutePattern::parse(&path.value())
+        .map_err(|e| syn::Error::new(path.span(), format!("invalid route path {:?}: {}", path.value(), e)))
+}
 
-    let mut fn_decl = parse_macro_input!(item as ItemFn);
+/// How a single handler argument is produced from the incoming request.
+enum HandlerArg {
+    Request,
+    /// `id: u64` bound to the `{id}` segment of the route.
+    PathParam(String, Type),
+    /// `Path(id): Path<u64>` bound to the `{id}` segment of the route.
+    Path(String, Type),
+    /// `Header(user_agent): Header<String>` reading the `user-agent` header.
+    Header(String, Type),
+    /// `config: &Config`, borrowed from the application state.
+    StateRef(Type),
+    /// Any other argument, built through `FromRequest`; types that do not
+    /// implement it are reported by the compiler at the argument.
+    Extractor(Type),
+    /// `socket: WebSocket`, the connection of a `#[websocket]` route.
+    WebSocket,
+}
 
-    let mut prefix: Vec<Stmt> = parse_quote!(
-        use std::time::Instant;
-        let t0 = Instant::now();
-    );
-    let mut suffix = parse_quote!(
-        let elapsed = t0.elapsed();
-        println!("Elapsed: {:.2?}", elapsed);
-    );
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(type_path) => type_path.path.segments.last()
+            .map(|segment| segment.ident.to_string()),
+        _ => None,
+    }
+}
 
-    prefix.append(&mut fn_decl.block.stmts);
-    prefix.append(&mut suffix);
+/// The variable bound by `name` or by a destructuring `Wrapper(name)` pattern.
+fn binding(pat: &Pat) -> Option<String> {
+    match pat {
+        Pat::Ident(pat_ident) => Some(pat_ident.ident.to_string()),
+        Pat::TupleStruct(tuple_struct) if tuple_struct.elems.len() == 1 => match &tuple_struct.elems[0] {
+            Pat::Ident(pat_ident) => Some(pat_ident.ident.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
 
-    let mut fn_decl2 = fn_decl.clone();
-    fn_decl2.block.stmts = prefix;
-    println!("{:?}", fn_decl2.clone().into_token_stream().to_string());
-    fn_decl2.into_token_stream().into()
+fn state_ref(ty: Type) -> syn::Result<HandlerArg> {
+    match ty {
+        Type::Reference(reference) if reference.mutability.is_none() => Ok(HandlerArg::StateRef(*reference.elem)),
+        ty => Err(syn::Error::new(ty.span(), "application state can only be borrowed immutably")),
+    }
 }
 
-fn route(attr: TokenStream, item: TokenStream, request_type: TokenStream2) ->  TokenStream {
-    let args = parse_macro_input!(attr as LitStr);
-    // println!("{:?}", args);
+fn handler_args(fn_decl: &ItemFn, path: &LitStr, pattern: &RoutePattern) -> syn::Result<Vec<HandlerArg>> {
+    let mut args = vec![];
+    let mut takes_request = false;
+    let mut takes_all_params = false;
+    for input in &fn_decl.sig.inputs {
+        let pat_type = match input {
+            FnArg::Typed(pat_type) => pat_type,
+            FnArg::Receiver(receiver) => return Err(syn::Error::new(receiver.span(), "route handlers cannot take `self`")),
+        };
+        let ty = (*pat_type.ty).clone();
+        let name = binding(&pat_type.pat);
+        let is_param = name.as_ref().is_some_and(|name| pattern.params().any(|param| param == name));
+        let is_ident = matches!(*pat_type.pat, Pat::Ident(_));
 
-    let fn_decl = parse_macro_input!(item as ItemFn);
-    // let item_copy = fn_decl.clone();
-    let name = fn_decl.clone().sig.ident;
+        let arg = match (type_name(&ty).as_deref(), name) {
+            (Some("HttpRequest"), _) => {
+                if takes_request {
+                    return Err(syn::Error::new(pat_type.span(), "route handlers can take at most one `HttpRequest`"));
+                }
+                takes_request = true;
+                HandlerArg::Request
+            },
+            (Some("Path"), Some(name)) if is_param => HandlerArg::Path(name, ty),
+            (Some("Path"), _) => {
+                if pattern.is_static() {
+                    let message = format!("route {:?} has no path parameters", path.value());
+                    return Err(syn::Error::new(pat_type.span(), message));
+                }
+                takes_all_params = true;
+                HandlerArg::Extractor(ty)
+            },
+            (Some("WebSocket"), _) => HandlerArg::WebSocket,
+            (Some("Header"), Some(name)) => HandlerArg::Header(name.replace('_', "-"), ty),
+            (Some("Header"), None) => {
+                let message = "`Header` arguments must bind a name, e.g. `Header(user_agent): Header<String>`";
+                return Err(syn::Error::new(pat_type.pat.span(), message));
+            },
+            (_, Some(name)) if is_param && is_ident => HandlerArg::PathParam(name, ty),
+            (_, _) if matches!(ty, Type::Reference(_)) => state_ref(ty)?,
+            (_, _) => HandlerArg::Extractor(ty),
+        };
+        args.push(arg);
+    }
 
-    let struct_def: ItemStruct = parse_quote!(
-        #[allow(non_camel_case_types)]
-        struct #name;
-    );
+    if !takes_request && !takes_all_params {
+        let unused = pattern.params().find(|param| !args.iter().any(|arg| match arg {
+            HandlerArg::PathParam(name, _) | HandlerArg::Path(name, _) => name == param,
+            _ => false,
+        }));
+        if let Some(param) = unused {
+            let message = format!("path parameter `{{{}}}` has no matching argument in `{}`", param, fn_decl.sig.ident);
+            return Err(syn::Error::new(path.span(), message));
+        }
+    }
+    Ok(args)
+}
+
+/// Checks that a WebSocket argument is taken exactly by `#[websocket]` routes.
+fn check_websocket_args(fn_decl: &ItemFn, args: &[HandlerArg], websocket: bool) -> syn::Result<()> {
+    let sockets = args.iter().filter(|arg| matches!(arg, HandlerArg::WebSocket)).count();
+    match (websocket, sockets) {
+        (true, 1) | (false, 0) => Ok(()),
+        (true, _) => Err(syn::Error::new(fn_decl.sig.span(), "#[websocket] handlers take exactly one `WebSocket` argument")),
+        (false, _) => Err(syn::Error::new(fn_decl.sig.span(), "`WebSocket` arguments are only available in #[websocket] handlers")),
+    }
+}
+
+fn expand_handler(fn_decl: &ItemFn, args: &[HandlerArg], path: &LitStr, instrument: bool) -> TokenStream2 {
+    let name = &fn_decl.sig.ident;
+    let mut extractions = vec![];
+    let mut call_args = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        let extraction = match arg {
+            HandlerArg::Request => {
+                call_args.push(quote!(request));
+                continue;
+            },
+            HandlerArg::WebSocket => {
+                call_args.push(quote!(socket));
+                continue;
+            },
+            HandlerArg::PathParam(param, ty) => quote!(request.path_param::<#ty>(#param)),
+            HandlerArg::Path(param, ty) => quote!(<#ty>::from_param(&request, #param)),
+            HandlerArg::Header(header, ty) => quote!(<#ty>::from_name(&request, #header)),
+            HandlerArg::StateRef(ty) => quote!(<::fast_web_server_types::State<#ty> as ::fast_web_server_types::FromRequest>::from_request(&request)),
+            HandlerArg::Extractor(ty) => quote_spanned!(ty.span()=> <#ty as ::fast_web_server_types::FromRequest>::from_request(&request)),
+        };
+        let var = format_ident!("__fast_web_server_arg{}", i);
+        extractions.push(quote!(
+            let #var = match #extraction {
+                Ok(value) => value,
+                Err(e) => return e.into(),
+            };
+        ));
+        match arg {
+            HandlerArg::StateRef(_) => call_args.push(quote!(&#var)),
+            _ => call_args.push(quote!(#var)),
+        }
+    }
 
-    let new_fn = quote!(
+    if args.iter().any(|arg| matches!(arg, HandlerArg::WebSocket)) {
+        // The handshake is checked first, and the arguments move into the
+        // callback that runs once the connection has been upgraded.
+        return quote!(
+            fn __fast_web_server_handler(request: ::fast_web_server_types::HttpRequest) -> ::fast_web_server_types::HttpResponse {
+                let __fast_web_server_upgrade = match ::fast_web_server_impl::WebSocketUpgrade::from_request(&request) {
+                    Ok(upgrade) => upgrade,
+                    Err(response) => return response,
+                };
+                #(#extractions)*
+                __fast_web_server_upgrade.on_upgrade(move |socket| {
+                    #name(#(#call_args),*);
+                })
+            }
+        );
+    }
 
-        #struct_def
+    if !instrument {
+        return quote!(
+            fn __fast_web_server_handler(request: ::fast_web_server_types::HttpRequest) -> ::fast_web_server_types::HttpResponse {
+                #(#extractions)*
+                ::fast_web_server_types::Responder::respond(#name(#(#call_args),*))
+            }
+        );
+    }
 
-        impl RegisterEndpoint for #name {
-            fn register(&self, server: &mut FastWebServer) {
+    // Labels are taken before the extractions, which may move the request.
+    quote!(
+        fn __fast_web_server_handler(request: ::fast_web_server_types::HttpRequest) -> ::fast_web_server_types::HttpResponse {
+            let __fast_web_server_metrics = request.state
+                .get::<::std::sync::Arc<::fast_web_server_impl::Metrics>>()
+                .cloned();
+            let __fast_web_server_method = request.start_line.request_type.clone();
+            let __fast_web_server_route = request.extensions
+                .get::<::fast_web_server_impl::MatchedRoute>()
+                .map(|route| route.0.to_string())
+                .unwrap_or_else(|| #path.to_string());
+            #(#extractions)*
+            let __fast_web_server_start = ::std::time::Instant::now();
+            let response = ::fast_web_server_types::Responder::respond(#name(#(#call_args),*));
+            if let Some(metrics) = __fast_web_server_metrics {
+                metrics.observe_handler(&__fast_web_server_method, &__fast_web_server_route, __fast_web_server_start.elapsed());
+            }
+            response
+        }
+    )
+}
+
+const ROUTE_ATTRIBUTES: [&str; 11] = ["get", "head", "post", "put", "delete", "connect", "options", "trace", "patch", "route", "websocket"];
+
+/// Whether `attr` is `#[name]` or `#[fast_web_server_macros::name]`
+/// for one of `names`, so `#[tracing::instrument]` is left alone.
+fn is_own_attribute(attr: &Attribute, names: &[&str]) -> bool {
+    let segments = &attr.path().segments;
+    let last = match segments.last() {
+        Some(last) => last.ident.to_string(),
+        None => return false,
+    };
+    let own_crate = match segments.len() {
+        1 => true,
+        2 => segments[0].ident == "fast_web_server_macros",
+        _ => false,
+    };
+    own_crate && names.contains(&last.as_str())
+}
+
+/// Removes an `#[instrument]` written below the route attribute, which
+/// would otherwise never run because the route macro expands first.
+fn take_instrument(fn_decl: &mut ItemFn) -> bool {
+    let len = fn_decl.attrs.len();
+    fn_decl.attrs.retain(|attr| !is_own_attribute(attr, &["instrument"]));
+    fn_decl.attrs.len() != len
+}
+
+fn expand_route(path: LitStr, request_types: Vec<RequestType>, websocket: bool, mut fn_decl: ItemFn) -> syn::Result<TokenStream2> {
+    let instrument = take_instrument(&mut fn_decl);
+    if instrument && websocket {
+        return Err(syn::Error::new(fn_decl.sig.ident.span(), "#[instrument] is not supported on #[websocket] routes"));
+    }
+    let pattern = parse_path(&path)?;
+    let args = handler_args(&fn_decl, &path, &pattern)?;
+    check_websocket_args(&fn_decl, &args, websocket)?;
+    let handler = expand_handler(&fn_decl, &args, &path, instrument);
+    let name = fn_decl.sig.ident.clone();
+    let vis = fn_decl.vis.clone();
+    let request_types = request_types.iter()
+        .map(|request_type| format_ident!("{}", request_type.as_str()));
+
+    Ok(quote!(
+        #[allow(non_camel_case_types)]
+        #vis struct #name;
+
+        impl ::fast_web_server_impl::RegisterEndpoint for #name {
+            fn register(&self, server: &mut dyn ::fast_web_server_impl::Routes) {
                 #fn_decl
-                server.bind(Self::request_type(), Self::route().as_str(), #name);
-        
+                #handler
+                let handler: ::std::sync::Arc<dyn ::fast_web_server_types::Handler> = ::std::sync::Arc::new(__fast_web_server_handler);
+                for request_type in Self::request_types() {
+                    ::fast_web_server_impl::Routes::bind_arc(server, request_type, Self::route().as_str(), handler.clone());
+                }
             }
-        
+
             fn route() -> String {
-                #args.to_string()
+                #path.to_string()
             }
-        
-            fn request_type() -> RequestType{
-                #request_type
+
+            fn request_types() -> Vec<::fast_web_server_types::RequestType> {
+                vec![#(::fast_web_server_types::RequestType::#request_types),*]
             }
         }
-    );
-    // println!("{}", new_fn.clone().to_string());
-    // return item_copy.into_token_stream().into();
-    new_fn.into()
+    ))
 }
 
+fn method_route(attr: TokenStream, item: TokenStream, request_type: RequestType) -> TokenStream {
+    let path = parse_macro_input!(attr as LitStr);
+    let fn_decl = parse_macro_input!(item as ItemFn);
+    expand_route(path, vec![request_type], false, fn_decl)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Registers a handler for several HTTP methods at once:
+/// `#[route("/mirror", method = "PUT", method = "PATCH")]`.
+#[proc_macro_attribute]
+pub fn route(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as RouteArgs);
+    let fn_decl = parse_macro_input!(item as ItemFn);
+
+    let mut request_types = vec![];
+    for method in &args.methods {
+        let request_type = match parse_method(method) {
+            Ok(request_type) => request_type,
+            Err(e) => return e.into_compile_error().into(),
+        };
+        if request_types.contains(&request_type) {
+            let message = format!("duplicate HTTP method `{}`", request_type);
+            return syn::Error::new(method.span(), message).into_compile_error().into();
+        }
+        request_types.push(request_type);
+    }
+    expand_route(args.path, request_types, false, fn_decl)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
 
 #[proc_macro_attribute]
 pub fn get(attr: TokenStream, item: TokenStream) -> TokenStream {
-    let request_type = quote!(RequestType::GET);
-    route(attr, item, request_type)
-    
+    method_route(attr, item, RequestType::GET)
+}
+
+#[proc_macro_attribute]
+pub fn head(attr: TokenStream, item: TokenStream) -> TokenStream {
+    method_route(attr, item, RequestType::HEAD)
 }
 
 #[proc_macro_attribute]
 pub fn post(attr: TokenStream, item: TokenStream) -> TokenStream {
-    let request_type = quote!(RequestType::POST);
-    route(attr, item, request_type)
+    method_route(attr, item, RequestType::POST)
+}
+
+#[proc_macro_attribute]
+pub fn put(attr: TokenStream, item: TokenStream) -> TokenStream {
+    method_route(attr, item, RequestType::PUT)
+}
+
+#[proc_macro_attribute]
+pub fn delete(attr: TokenStream, item: TokenStream) -> TokenStream {
+    method_route(attr, item, RequestType::DELETE)
+}
+
+#[proc_macro_attribute]
+pub fn connect(attr: TokenStream, item: TokenStream) -> TokenStream {
+    method_route(attr, item, RequestType::CONNECT)
+}
+
+#[proc_macro_attribute]
+pub fn options(attr: TokenStream, item: TokenStream) -> TokenStream {
+    method_route(attr, item, RequestType::OPTIONS)
+}
+
+#[proc_macro_attribute]
+pub fn trace(attr: TokenStream, item: TokenStream) -> TokenStream {
+    method_route(attr, item, RequestType::TRACE)
+}
+
+#[proc_macro_attribute]
+pub fn patch(attr: TokenStream, item: TokenStream) -> TokenStream {
+    method_route(attr, item, RequestType::PATCH)
+}
+
+/// Serves WebSocket connections on a `GET` route. The handler takes a
+/// `WebSocket` besides the usual path parameters and extractors, and runs
+/// once the handshake has been answered with `101 Switching Protocols`:
+///
+/// ```ignore
+/// #[websocket("/echo")]
+/// fn echo(mut socket: WebSocket) {
+///     while let Ok(Message::Text(text)) = socket.recv() {
+///         let _ = socket.send(text);
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn websocket(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let path = parse_macro_input!(attr as LitStr);
+    let fn_decl = parse_macro_input!(item as ItemFn);
+    expand_route(path, vec![RequestType::GET], true, fn_decl)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Records the latency of a route handler into the server's `Metrics`,
+/// labelled with the HTTP method and the route pattern. Combine it with a
+/// route attribute, in either order:
+///
+/// ```ignore
+/// #[instrument]
+/// #[get("/users/{id}")]
+/// fn get_user(id: u64) -> String { ... }
+/// ```
+#[proc_macro_attribute]
+pub fn instrument(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = TokenStream2::from(attr);
+        return syn::Error::new(attr.span(), "#[instrument] takes no arguments").into_compile_error().into();
+    }
+    let mut fn_decl = parse_macro_input!(item as ItemFn);
+
+    // Written above the route attribute: move below it, where the route
+    // macro picks it up.
+    match fn_decl.attrs.iter().position(|attr| is_own_attribute(attr, &ROUTE_ATTRIBUTES)) {
+        Some(index) => {
+            fn_decl.attrs.insert(index + 1, parse_quote!(#[::fast_web_server_macros::instrument]));
+            quote!(#fn_decl).into()
+        },
+        None => {
+            let message = "#[instrument] must be combined with a route attribute such as #[get(\"/path\")]";
+            syn::Error::new(fn_decl.sig.ident.span(), message).into_compile_error().into()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_route_args() {
+        let args: RouteArgs = syn::parse_str(r#""/mirror", method = "PUT", method = "PATCH""#).unwrap();
+        assert_eq!(args.path.value(), "/mirror");
+        let methods: Vec<String> = args.methods.iter().map(LitStr::value).collect();
+        assert_eq!(methods, vec!["PUT", "PATCH"]);
+    }
+
+    #[test]
+    fn parse_route_args_without_method() {
+        let result = syn::parse_str::<RouteArgs>(r#""/mirror""#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_route_args_with_unknown_key() {
+        let result = syn::parse_str::<RouteArgs>(r#""/mirror", verb = "PUT""#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn invalid_method() {
+        let method: LitStr = syn::parse_str(r#""FETCH""#).unwrap();
+        let error = parse_method(&method).unwrap_err();
+        assert_eq!(error.to_string(), "invalid HTTP method `FETCH`");
+    }
+
+    #[test]
+    fn malformed_paths() {
+        for path in [r#""test""#, r#""/te st""#, r#""/test?a=b""#, r#""/a//b""#, r#""/users/{id""#, r#""/users/{1d}""#] {
+            let path: LitStr = syn::parse_str(path).unwrap();
+            assert!(parse_path(&path).is_err());
+        }
+        let path: LitStr = syn::parse_str(r#""/users/{id}""#).unwrap();
+        assert!(parse_path(&path).is_ok());
+    }
+
+    fn check_handler(path: &str, handler: &str) -> syn::Result<Vec<HandlerArg>> {
+        let path: LitStr = syn::parse_str(&format!("{:?}", path)).unwrap();
+        let fn_decl: ItemFn = syn::parse_str(handler).unwrap();
+        handler_args(&fn_decl, &path, &parse_path(&path).unwrap())
+    }
+
+    #[test]
+    fn path_params_match_arguments() {
+        let args = check_handler("/users/{id}", "fn get_user(id: u64, request: HttpRequest) -> Vec<u8> { vec![] }").unwrap();
+        assert!(matches!(&args[..], [HandlerArg::PathParam(name, _), HandlerArg::Request] if name == "id"));
+    }
+
+    #[test]
+    fn custom_extractor_arguments() {
+        let handler = "fn get_user(session: Session, Query(id): Query<u64>) -> Vec<u8> { vec![] }";
+        let error = check_handler("/users/{id}", handler).err().unwrap();
+        assert_eq!(error.to_string(), "path parameter `{id}` has no matching argument in `get_user`");
+        let args = check_handler("/users", handler).unwrap();
+        assert!(matches!(&args[..], [HandlerArg::Extractor(_), HandlerArg::Extractor(_)]));
+    }
+
+    #[test]
+    fn extractor_arguments() {
+        let handler = "fn get_user(Path(id): Path<u64>, Query(q): Query<Filter>, Header(user_agent): Header<String>, Json(body): Json<NewUser>) -> Vec<u8> { vec![] }";
+        let args = check_handler("/users/{id}", handler).unwrap();
+        assert!(matches!(&args[0], HandlerArg::Path(name, _) if name == "id"));
+        assert!(matches!(&args[1], HandlerArg::Extractor(_)));
+        assert!(matches!(&args[2], HandlerArg::Header(name, _) if name == "user-agent"));
+        assert!(matches!(&args[3], HandlerArg::Extractor(_)));
+    }
+
+    #[test]
+    fn state_arguments() {
+        let handler = "fn get_config(State(config): State<Config>, pool: &Pool) -> Vec<u8> { vec![] }";
+        let args = check_handler("/config", handler).unwrap();
+        assert!(matches!(&args[0], HandlerArg::Extractor(_)));
+        assert!(matches!(&args[1], HandlerArg::StateRef(_)));
+        assert!(check_handler("/config", "fn get_config(pool: &mut Pool) -> Vec<u8> { vec![] }").is_err());
+    }
+
+    #[test]
+    fn path_struct_covers_all_params() {
+        let handler = "fn get_post(Path(params): Path<PostParams>) -> Vec<u8> { vec![] }";
+        assert!(check_handler("/users/{id}/posts/{post_id}", handler).is_ok());
+        let error = check_handler("/users", handler).err().unwrap();
+        assert_eq!(error.to_string(), "route \"/users\" has no path parameters");
+    }
+
+    #[test]
+    fn instrument_attribute() {
+        let mut fn_decl: ItemFn = syn::parse_str("#[inline] #[instrument] fn index() -> Vec<u8> { vec![] }").unwrap();
+        assert!(take_instrument(&mut fn_decl));
+        assert_eq!(fn_decl.attrs.len(), 1);
+
+        let mut fn_decl: ItemFn = syn::parse_str("#[fast_web_server_macros::instrument] fn index() -> Vec<u8> { vec![] }").unwrap();
+        assert!(take_instrument(&mut fn_decl));
+
+        let mut fn_decl: ItemFn = syn::parse_str("#[tracing::instrument] fn index() -> Vec<u8> { vec![] }").unwrap();
+        assert!(!take_instrument(&mut fn_decl));
+        assert_eq!(fn_decl.attrs.len(), 1);
+    }
+
+    #[test]
+    fn websocket_arguments() {
+        let fn_decl: ItemFn = syn::parse_str("fn chat(room: String, mut socket: WebSocket, config: &Config) {}").unwrap();
+        let args = check_handler("/chat/{room}", "fn chat(room: String, mut socket: WebSocket, config: &Config) {}").unwrap();
+        assert!(matches!(&args[..], [HandlerArg::PathParam(..), HandlerArg::WebSocket, HandlerArg::StateRef(_)]));
+        assert!(check_websocket_args(&fn_decl, &args, true).is_ok());
+        let error = check_websocket_args(&fn_decl, &args, false).unwrap_err();
+        assert_eq!(error.to_string(), "`WebSocket` arguments are only available in #[websocket] handlers");
+        assert!(check_websocket_args(&fn_decl, &args[..1], true).is_err());
+    }
 
+    #[test]
+    fn unused_path_param() {
+        let error = check_handler("/users/{id}", "fn get_user() -> Vec<u8> { vec![] }").err().unwrap();
+        assert_eq!(error.to_string(), "path parameter `{id}` has no matching argument in `get_user`");
+        assert!(check_handler("/users/{id}", "fn get_user(request: HttpRequest) -> Vec<u8> { vec![] }").is_ok());
+    }
 }
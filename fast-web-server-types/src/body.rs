@@ -0,0 +1,114 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+
+/// Largest chunk a `FileBody` yields when read as a `BodyStream`.
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The next piece of a streamed response body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    Data(Vec<u8>),
+    /// Nothing to send yet; the server polls again later.
+    Pending,
+    End,
+}
+
+/// A response body produced while the response is being sent, such as
+/// server-sent events. `poll_chunk` must not block, so one thread can
+/// serve many idle streams.
+pub trait BodyStream: Send {
+    fn poll_chunk(&mut self) -> Chunk;
+}
+
+/// A `BodyStream` attached to a response. The response head goes out
+/// without `Content-Length` and the body ends when the connection closes,
+/// or with the stream on HTTP/2.
+pub struct StreamingBody(Box<dyn BodyStream>);
+
+impl StreamingBody {
+    pub fn new(stream: impl BodyStream + 'static) -> Self {
+        Self(Box::new(stream))
+    }
+
+    pub fn poll_chunk(&mut self) -> Chunk {
+        self.0.poll_chunk()
+    }
+}
+
+impl fmt::Debug for StreamingBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("StreamingBody")
+    }
+}
+
+/// A response body sent straight from a file. On plain HTTP/1 connections
+/// the server hands it to the kernel with `sendfile(2)`, so it is never
+/// copied into memory; on TLS and HTTP/2 it is read in chunks.
+#[derive(Debug)]
+pub struct FileBody {
+    file: File,
+    offset: u64,
+    len: u64,
+}
+
+impl FileBody {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+
+    /// The whole of `file`, as long as it is now.
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self { file, offset: 0, len })
+    }
+
+    /// `len` bytes of `file` starting at `offset`.
+    pub fn with_range(file: File, offset: u64, len: u64) -> Self {
+        Self { file, offset, len }
+    }
+
+    /// Narrows the body to `len` bytes starting `start` bytes into it.
+    pub fn slice(self, start: u64, len: u64) -> Self {
+        Self { offset: self.offset + start, len, ..self }
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl BodyStream for FileBody {
+    /// Yields the next chunk and moves the start of the body past it.
+    /// Ends early if the file cannot be read or got shorter, leaving the
+    /// body shorter than its `Content-Length`.
+    fn poll_chunk(&mut self) -> Chunk {
+        if self.len == 0 || self.file.seek(SeekFrom::Start(self.offset)).is_err() {
+            return Chunk::End;
+        }
+        let mut chunk = vec![0; self.len.min(FILE_CHUNK_SIZE as u64) as usize];
+        match self.file.read(&mut chunk) {
+            Ok(0) | Err(_) => Chunk::End,
+            Ok(len) => {
+                chunk.truncate(len);
+                self.offset += len as u64;
+                self.len -= len as u64;
+                Chunk::Data(chunk)
+            },
+        }
+    }
+}
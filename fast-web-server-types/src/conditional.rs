@@ -0,0 +1,285 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{ExtractError, FromRequest, HttpRequest, RequestType};
+
+
+/// An entity tag, the validator sent in `ETag` and compared against the
+/// tags of `If-Match`, `If-None-Match` and `If-Range`, RFC 9110 section 8.8.3.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ETag {
+    tag: String,
+    weak: bool,
+}
+
+impl ETag {
+    /// A tag for a representation whose bytes only change along with the
+    /// tag. `tag` is given without quotes; characters not allowed in entity
+    /// tags are dropped.
+    pub fn strong(tag: &str) -> Self {
+        Self { tag: tag.chars().filter(|&c| is_etagc(c)).collect(), weak: false }
+    }
+
+    /// A tag for representations that are equivalent but may differ in
+    /// their bytes, for example when they are compressed on the fly.
+    pub fn weak(tag: &str) -> Self {
+        Self { weak: true, ..Self::strong(tag) }
+    }
+
+    /// A strong tag derived from the body's bytes and length.
+    pub fn from_body(body: &[u8]) -> Self {
+        // 64-bit FNV-1a, stable across builds unlike the std hashers.
+        let hash = body.iter().fold(0xcbf29ce484222325u64, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3));
+        Self::strong(&format!("{:016x}-{:x}", hash, body.len()))
+    }
+
+    /// Parses a single tag like `"xyzzy"` or `W/"xyzzy"`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (etag, rest) = Self::parse_prefix(value.trim())?;
+        rest.is_empty().then_some(etag)
+    }
+
+    /// Parses the comma separated tags of `If-Match` or `If-None-Match`.
+    /// Tags may themselves contain commas, so the list is not simply split.
+    pub fn parse_list(value: &str) -> Option<Vec<Self>> {
+        let mut tags = vec![];
+        let mut rest = value.trim_start_matches([' ', '\t', ',']);
+        while !rest.is_empty() {
+            let (etag, after) = Self::parse_prefix(rest)?;
+            tags.push(etag);
+            let after = after.trim_start_matches([' ', '\t']);
+            if !after.is_empty() && !after.starts_with(',') {
+                return None;
+            }
+            rest = after.trim_start_matches([' ', '\t', ',']);
+        }
+        Some(tags)
+    }
+
+    fn parse_prefix(value: &str) -> Option<(Self, &str)> {
+        let (weak, quoted) = match value.strip_prefix("W/") {
+            Some(quoted) => (true, quoted),
+            None => (false, value),
+        };
+        let (tag, rest) = quoted.strip_prefix('"')?.split_once('"')?;
+        tag.chars().all(is_etagc).then(|| (Self { tag: tag.to_string(), weak }, rest))
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// The opaque tag, without quotes.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Both tags are strong and equal, as required for `If-Match` and `If-Range`.
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// The tags are equal regardless of weakness, as used for `If-None-Match`.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.weak {
+            true => write!(f, "W/\"{}\"", self.tag),
+            false => write!(f, "\"{}\"", self.tag),
+        }
+    }
+}
+
+/// `etagc`: visible ASCII other than `"`, and non-ASCII text.
+fn is_etagc(c: char) -> bool {
+    c == '!' || ('#'..='~').contains(&c) || !c.is_ascii()
+}
+
+/// The outcome of evaluating a request's preconditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    /// The request is served as usual.
+    Passed,
+    /// The client's copy is current; answer `304 Not Modified`.
+    NotModified,
+    /// Answer `412 Precondition Failed`.
+    Failed,
+}
+
+/// The conditional headers of a request: `If-Match`, `If-None-Match`,
+/// `If-Modified-Since` and `If-Unmodified-Since`. Malformed headers are
+/// ignored.
+///
+/// Handlers that know a resource's validators before producing its body
+/// can evaluate them first, so a `304` or `412` skips that work. Handlers
+/// of state-changing requests must do so before applying the change.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Preconditions {
+    if_match: Option<Condition>,
+    if_none_match: Option<Condition>,
+    if_modified_since: Option<SystemTime>,
+    if_unmodified_since: Option<SystemTime>,
+    /// `GET` or `HEAD`, which answer a matching `If-None-Match` with 304.
+    read_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Condition {
+    Any,
+    Tags(Vec<ETag>),
+}
+
+impl Condition {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "*" => Some(Condition::Any),
+            value => ETag::parse_list(value).map(Condition::Tags),
+        }
+    }
+
+    /// `*` matches any current representation.
+    fn matches(&self, etag: Option<&ETag>, eq: fn(&ETag, &ETag) -> bool) -> bool {
+        match (self, etag) {
+            (Condition::Any, _) => true,
+            (Condition::Tags(tags), Some(etag)) => tags.iter().any(|tag| eq(tag, etag)),
+            (Condition::Tags(_), None) => false,
+        }
+    }
+}
+
+impl Preconditions {
+    pub fn for_request(request: &HttpRequest) -> Self {
+        let header = |name: &str| request.headers.get(name);
+        let date = |name: &str| header(name).and_then(|date| httpdate::parse_http_date(date).ok());
+        let read_only = matches!(request.start_line.request_type, RequestType::GET | RequestType::HEAD);
+        Self {
+            if_match: header("If-Match").and_then(|value| Condition::parse(value)),
+            if_none_match: header("If-None-Match").and_then(|value| Condition::parse(value)),
+            // Only defined for GET and HEAD, RFC 9110 section 13.1.3.
+            if_modified_since: date("If-Modified-Since").filter(|_| read_only),
+            if_unmodified_since: date("If-Unmodified-Since"),
+            read_only,
+        }
+    }
+
+    /// Whether the request carries no (valid) conditional headers.
+    pub fn is_empty(&self) -> bool {
+        self.if_match.is_none() && self.if_none_match.is_none()
+            && self.if_modified_since.is_none() && self.if_unmodified_since.is_none()
+    }
+
+    /// Evaluates the preconditions against the validators of the current
+    /// representation, in the order of RFC 9110 section 13.2.2. Dates are
+    /// compared in whole seconds, the precision of HTTP dates.
+    pub fn evaluate(&self, etag: Option<&ETag>, last_modified: Option<SystemTime>) -> Precondition {
+        let last_modified = last_modified.map(seconds);
+        if let Some(condition) = &self.if_match {
+            if !condition.matches(etag, ETag::strong_eq) {
+                return Precondition::Failed;
+            }
+        } else if let (Some(since), Some(modified)) = (self.if_unmodified_since, last_modified) {
+            if modified > seconds(since) {
+                return Precondition::Failed;
+            }
+        }
+        if let Some(condition) = &self.if_none_match {
+            if condition.matches(etag, ETag::weak_eq) {
+                return match self.read_only {
+                    true => Precondition::NotModified,
+                    false => Precondition::Failed,
+                };
+            }
+        } else if let (Some(since), Some(modified)) = (self.if_modified_since, last_modified) {
+            if modified <= seconds(since) {
+                return Precondition::NotModified;
+            }
+        }
+        Precondition::Passed
+    }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+impl FromRequest for Preconditions {
+    fn from_request(request: &HttpRequest) -> Result<Self, ExtractError> {
+        Ok(Self::for_request(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn preconditions(method: &str, headers: &str) -> Preconditions {
+        let raw = format!("{} / HTTP/1.1\r\n{}\r\n", method, headers);
+        Preconditions::for_request(&HttpRequest::new(&mut Cursor::new(raw.as_bytes())).unwrap())
+    }
+
+    #[test]
+    fn test_etag() {
+        assert_eq!(ETag::parse("\"abc\""), Some(ETag::strong("abc")));
+        assert_eq!(ETag::parse(" W/\"abc\" "), Some(ETag::weak("abc")));
+        assert_eq!(ETag::parse("abc"), None);
+        assert_eq!(ETag::parse("\"a\"b"), None);
+        assert_eq!(ETag::weak("abc").to_string(), "W/\"abc\"");
+        assert_eq!(ETag::strong("a\"b c").to_string(), "\"abc\"");
+        assert_eq!(ETag::parse_list("\"a,b\", W/\"c\",,\"d\""), Some(vec![ETag::strong("a,b"), ETag::weak("c"), ETag::strong("d")]));
+        assert_eq!(ETag::parse_list("\"a\" \"b\""), None);
+
+        assert!(ETag::strong("a").strong_eq(&ETag::strong("a")));
+        assert!(!ETag::weak("a").strong_eq(&ETag::strong("a")));
+        assert!(ETag::weak("a").weak_eq(&ETag::strong("a")));
+        assert_eq!(ETag::from_body(b"abc"), ETag::from_body(b"abc"));
+        assert_ne!(ETag::from_body(b"abc"), ETag::from_body(b"abd"));
+    }
+
+    #[test]
+    fn test_if_none_match_and_modified_since() {
+        let etag = ETag::strong("v1");
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let date = httpdate::fmt_http_date(modified);
+
+        assert_eq!(preconditions("GET", "").evaluate(Some(&etag), Some(modified)), Precondition::Passed);
+        assert_eq!(preconditions("GET", "If-None-Match: W/\"v1\"\r\n").evaluate(Some(&etag), None), Precondition::NotModified);
+        assert_eq!(preconditions("GET", "If-None-Match: *\r\n").evaluate(None, None), Precondition::NotModified);
+        assert_eq!(preconditions("PUT", "If-None-Match: \"v1\"\r\n").evaluate(Some(&etag), None), Precondition::Failed);
+        assert_eq!(preconditions("GET", "If-None-Match: \"v2\"\r\n").evaluate(Some(&etag), None), Precondition::Passed);
+
+        let since = format!("If-Modified-Since: {}\r\n", date);
+        assert_eq!(preconditions("GET", &since).evaluate(None, Some(modified + Duration::from_millis(500))), Precondition::NotModified);
+        assert_eq!(preconditions("GET", &since).evaluate(None, Some(modified + Duration::from_secs(1))), Precondition::Passed);
+        assert_eq!(preconditions("POST", &since).evaluate(None, Some(modified)), Precondition::Passed);
+        // If-None-Match takes precedence over If-Modified-Since.
+        let both = format!("If-None-Match: \"v2\"\r\n{}", since);
+        assert_eq!(preconditions("GET", &both).evaluate(Some(&etag), Some(modified)), Precondition::Passed);
+    }
+
+    #[test]
+    fn test_if_match_and_unmodified_since() {
+        let etag = ETag::strong("v1");
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        assert_eq!(preconditions("PUT", "If-Match: \"v0\", \"v1\"\r\n").evaluate(Some(&etag), None), Precondition::Passed);
+        assert_eq!(preconditions("PUT", "If-Match: W/\"v1\"\r\n").evaluate(Some(&etag), None), Precondition::Failed);
+        assert_eq!(preconditions("PUT", "If-Match: \"v1\"\r\n").evaluate(None, None), Precondition::Failed);
+        assert_eq!(preconditions("DELETE", "If-Match: *\r\n").evaluate(None, None), Precondition::Passed);
+
+        let since = format!("If-Unmodified-Since: {}\r\n", httpdate::fmt_http_date(modified));
+        assert_eq!(preconditions("PUT", &since).evaluate(None, Some(modified)), Precondition::Passed);
+        assert_eq!(preconditions("PUT", &since).evaluate(None, Some(modified + Duration::from_secs(1))), Precondition::Failed);
+        // If-Match takes precedence over If-Unmodified-Since.
+        let both = format!("If-Match: \"v1\"\r\n{}", since);
+        assert_eq!(preconditions("PUT", &both).evaluate(Some(&etag), Some(modified + Duration::from_secs(1))), Precondition::Passed);
+        // A malformed date is ignored.
+        assert!(preconditions("PUT", "If-Unmodified-Since: yesterday\r\n").is_empty());
+    }
+}
@@ -0,0 +1,268 @@
+use std::{any::type_name, fmt::Display, ops::Deref, str::FromStr, sync::Arc};
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use crate::{HttpRequest, HttpResponse, PathParamError, StatusCode};
+
+
+/// Types that can be built from an incoming request, used for the
+/// arguments of handlers generated by the route macros.
+pub trait FromRequest: Sized {
+    fn from_request(request: &HttpRequest) -> Result<Self, ExtractError>;
+}
+
+/// Path parameters. `Path(id): Path<u64>` extracts the single `{id}`
+/// parameter, any other binding deserializes all parameters into `T`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path<T>(pub T);
+
+/// Query string deserialized with `serde_urlencoded`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query<T>(pub T);
+
+/// Request body deserialized as JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+/// Request body deserialized as `application/x-www-form-urlencoded`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Form<T>(pub T);
+
+/// A single header, named after the binding: `Header(user_agent): Header<String>`
+/// reads `User-Agent`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header<T>(pub T);
+
+/// Shared application state registered with `FastWebServer::with_state`.
+/// Handlers can also take `&T` directly.
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+impl<T> Clone for State<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Send + Sync + 'static> FromRequest for State<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, ExtractError> {
+        match request.state.get::<Arc<T>>() {
+            Some(state) => Ok(Self(state.clone())),
+            None => Err(ExtractError::MissingState(type_name::<T>())),
+        }
+    }
+}
+
+impl<T: FromStr> Path<T> where T::Err: Display {
+    pub fn from_param(request: &HttpRequest, name: &str) -> Result<Self, ExtractError> {
+        Ok(Self(request.path_param(name)?))
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Path<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, ExtractError> {
+        let encoded = serde_urlencoded::to_string(&request.path_params)
+            .map_err(|e| ExtractError::Path(e.to_string()))?;
+        serde_urlencoded::from_str(&encoded)
+            .map(Self)
+            .map_err(|e| ExtractError::Path(e.to_string()))
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, ExtractError> {
+        // The raw query keeps repeated keys and their order, which
+        // `request_params` loses.
+        let query = request.start_line.request_target.raw.split_once('?').map_or("", |(_, query)| query);
+        serde_urlencoded::from_str(query)
+            .map(Self)
+            .map_err(|e| ExtractError::Query(e.to_string()))
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, ExtractError> {
+        expect_content_type(request, "application/json")?;
+        serde_json::from_str(&request.body)
+            .map(Self)
+            .map_err(|e| ExtractError::Json(e.to_string()))
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Form<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self, ExtractError> {
+        expect_content_type(request, "application/x-www-form-urlencoded")?;
+        serde_urlencoded::from_str(&request.body)
+            .map(Self)
+            .map_err(|e| ExtractError::Form(e.to_string()))
+    }
+}
+
+impl<T: FromStr> Header<T> where T::Err: Display {
+    pub fn from_name(request: &HttpRequest, name: &str) -> Result<Self, ExtractError> {
+        let value = match request.headers.get(name) {
+            Some(value) => value,
+            None => return Err(ExtractError::MissingHeader(name.to_owned())),
+        };
+        value.parse::<T>()
+            .map(Self)
+            .map_err(|e| ExtractError::InvalidHeader(name.to_owned(), e.to_string()))
+    }
+}
+
+fn expect_content_type(request: &HttpRequest, expected: &str) -> Result<(), ExtractError> {
+    let content_type = request.headers.get("Content-Type").map_or("", String::as_str);
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    if mime.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(ExtractError::ContentType(expected.to_owned(), content_type.to_owned()))
+    }
+}
+
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ExtractError {
+    #[error(transparent)]
+    PathParam(#[from] PathParamError),
+    #[error("invalid path parameters: {0}")]
+    Path(String),
+    #[error("invalid query string: {0}")]
+    Query(String),
+    #[error("invalid JSON body: {0}")]
+    Json(String),
+    #[error("invalid form body: {0}")]
+    Form(String),
+    #[error("expected Content-Type `{0}`, got {1:?}")]
+    ContentType(String, String),
+    #[error("missing header `{0}`")]
+    MissingHeader(String),
+    #[error("invalid header `{0}`: {1}")]
+    InvalidHeader(String, String),
+    #[error("no application state of type `{0}` was registered")]
+    MissingState(&'static str),
+    #[error("`{0}` was not set by the server")]
+    MissingExtension(&'static str),
+}
+
+impl From<ExtractError> for HttpResponse {
+    fn from(error: ExtractError) -> Self {
+        match error {
+            ExtractError::MissingState(_) | ExtractError::MissingExtension(_) => HttpResponse::json_error(StatusCode::Code500, "internal_server_error", None),
+            error => HttpResponse::json_error(StatusCode::Code400, "bad_request", Some(&error.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde::Deserialize;
+
+    use crate::Extensions;
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Filter {
+        name: String,
+        limit: u32,
+    }
+
+    fn request(input: &str) -> HttpRequest {
+        HttpRequest::new(&mut Cursor::new(input.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_path_from_param() {
+        let mut request = request("GET /users/42 HTTP/1.1\r\n\r\n");
+        request.path_params.insert("id".to_owned(), "42".to_owned());
+        assert_eq!(Path::<u64>::from_param(&request, "id"), Ok(Path(42)));
+        assert!(matches!(Path::<u64>::from_param(&request, "other"), Err(ExtractError::PathParam(_))));
+    }
+
+    #[test]
+    fn test_path_struct() {
+        let mut request = request("GET /users/a%20b/5 HTTP/1.1\r\n\r\n");
+        request.path_params.insert("name".to_owned(), "a b".to_owned());
+        request.path_params.insert("limit".to_owned(), "5".to_owned());
+        let Path(filter) = Path::<Filter>::from_request(&request).unwrap();
+        assert_eq!(filter, Filter { name: "a b".to_owned(), limit: 5 });
+    }
+
+    #[test]
+    fn test_query() {
+        let request = request("GET /users?name=a%20b&limit=10 HTTP/1.1\r\n\r\n");
+        let Query(filter) = Query::<Filter>::from_request(&request).unwrap();
+        assert_eq!(filter, Filter { name: "a b".to_owned(), limit: 10 });
+
+        let request = super::tests::request("GET /users?name=x&limit=many HTTP/1.1\r\n\r\n");
+        assert!(matches!(Query::<Filter>::from_request(&request), Err(ExtractError::Query(_))));
+
+        let request = super::tests::request("GET /search?tag=b&q=x&tag=a HTTP/1.1\r\n\r\n");
+        let Query(pairs) = Query::<Vec<(String, String)>>::from_request(&request).unwrap();
+        assert_eq!(pairs, [("tag", "b"), ("q", "x"), ("tag", "a")].map(|(k, v)| (k.to_owned(), v.to_owned())));
+        let request = super::tests::request("GET /search HTTP/1.1\r\n\r\n");
+        assert_eq!(Query::<Vec<(String, String)>>::from_request(&request).unwrap().0, vec![]);
+    }
+
+    #[test]
+    fn test_json() {
+        let body = r#"{"name": "x", "limit": 3}"#;
+        let input = format!("POST /users HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let Json(filter) = Json::<Filter>::from_request(&request(&input)).unwrap();
+        assert_eq!(filter, Filter { name: "x".to_owned(), limit: 3 });
+
+        let input = "POST /users HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{]";
+        assert!(matches!(Json::<Filter>::from_request(&request(input)), Err(ExtractError::Json(_))));
+    }
+
+    #[test]
+    fn test_json_wrong_content_type() {
+        let input = "POST /users HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\n{}";
+        let error = Json::<Filter>::from_request(&request(input)).unwrap_err();
+        assert_eq!(error.to_string(), "expected Content-Type `application/json`, got \"text/plain\"");
+    }
+
+    #[test]
+    fn test_form() {
+        let body = "name=x+y&limit=7";
+        let input = format!("POST /users HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let Form(filter) = Form::<Filter>::from_request(&request(&input)).unwrap();
+        assert_eq!(filter, Filter { name: "x y".to_owned(), limit: 7 });
+    }
+
+    #[test]
+    fn test_header() {
+        let request = request("GET / HTTP/1.1\r\nuser-agent: curl/7.64.1\r\nX-Count: 3\r\n\r\n");
+        assert_eq!(Header::<String>::from_name(&request, "User-Agent"), Ok(Header("curl/7.64.1".to_owned())));
+        assert_eq!(Header::<u8>::from_name(&request, "x-count"), Ok(Header(3)));
+        assert_eq!(Header::<u8>::from_name(&request, "accept"), Err(ExtractError::MissingHeader("accept".to_owned())));
+    }
+
+    #[test]
+    fn test_state() {
+        let mut request = request("GET / HTTP/1.1\r\n\r\n");
+        assert!(matches!(State::<Filter>::from_request(&request), Err(ExtractError::MissingState(_))));
+
+        let mut state = Extensions::new();
+        state.insert(Arc::new(Filter { name: "x".to_owned(), limit: 1 }));
+        request.state = Arc::new(state);
+        let filter = State::<Filter>::from_request(&request).unwrap();
+        assert_eq!(filter.limit, 1);
+    }
+
+    #[test]
+    fn test_into_response() {
+        let response: HttpResponse = ExtractError::MissingHeader("accept".to_owned()).into();
+        assert_eq!(response.body, b"{\"error\": \"bad_request\", \"message\": \"missing header `accept`\"}".to_vec());
+    }
+}
@@ -1,46 +1,65 @@
-use std::collections::{HashMap, hash_map::Iter};
-use std::fmt::Write;
-
-use itertools::Itertools;
-
-
+/// Header fields in insertion order. Names are matched case-insensitively;
+/// inserting a name that is already present replaces its value in place.
 #[derive(Debug, Default, Clone)]
 pub struct HttpHeaders {
-    headers: HashMap<String, String>,
+    headers: Vec<(String, String)>,
 }
 
 impl HttpHeaders {
     pub fn new() -> Self {
         Self {
-            headers: HashMap::new(),
+            headers: Vec::new(),
         }
     }
 
-    pub fn iter(&self) -> Iter<String, String> {
-        self.headers.iter()
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.headers.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
     }
 
     pub fn insert(&mut self, key: String, value: String) {
-        self.headers.insert(key, value);
+        match self.headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(&key)) {
+            Some(header) => *header = (key, value),
+            None => self.headers.push((key, value)),
+        }
+    }
+
+    /// Removes a header, matching the name case-insensitively.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let index = self.headers.iter().position(|(k, _)| k.eq_ignore_ascii_case(key))?;
+        Some(self.headers.remove(index).1)
     }
 
+    /// Looks up a header, matching the name case-insensitively.
     pub fn get(&self, key: &str) -> Option<&String> {
-        self.headers.get(key)
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+
+    /// Appends the headers as `Name: value` lines, in insertion order.
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
+        for (k, v) in &self.headers {
+            buf.extend_from_slice(k.as_bytes());
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(v.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
     }
 }
 
 impl Into<Vec<u8>> for HttpHeaders {
     fn into(self) -> Vec<u8> {
-        let mut buf = String::new();
-        self.headers.iter().sorted()
-            .fold(
-                &mut buf, 
-                |buf, (k, v)| {
-                    write!(buf, "{}: {}\r\n", k, v).unwrap();
-                    buf
-                }
-        );
-        buf.into_bytes()
+        let mut buf = Vec::new();
+        self.write_to(&mut buf);
+        buf
     }
 }
 
@@ -69,6 +88,34 @@ mod tests {
         assert_eq!(headers.get("Accept"), None);
     }
 
+    #[test]
+    fn test_get_case_insensitive() {
+        let mut headers = HttpHeaders::new();
+        headers.insert("content-type".to_string(), "application/json".to_string());
+        assert_eq!(headers.get("Content-Type"), Some(&"application/json".to_string()));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some(&"application/json".to_string()));
+    }
+
+    #[test]
+    fn test_insert_replaces_in_place() {
+        let mut headers = HttpHeaders::new();
+        headers.insert("Content-Length".to_string(), "0".to_string());
+        headers.insert("Connection".to_string(), "close".to_string());
+        headers.insert("content-length".to_string(), "4".to_string());
+        assert_eq!(headers.len(), 2);
+        let bytes: Vec<u8> = headers.into();
+        assert_eq!(bytes, b"content-length: 4\r\nConnection: close\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut headers = HttpHeaders::new();
+        headers.insert("Content-Length".to_string(), "0".to_string());
+        assert_eq!(headers.remove("content-length"), Some("0".to_string()));
+        assert_eq!(headers.remove("Content-Length"), None);
+        assert!(headers.is_empty());
+    }
+
     #[test]
     fn test_iter() {
         let mut headers = HttpHeaders::new();
@@ -83,7 +130,7 @@ mod tests {
         let mut headers = HttpHeaders::new();
         headers.insert("Content-Type".to_string(), "application/json".to_string());
         headers.insert("Accept".to_string(), "text/html".to_string());
-        let expected_bytes = b"Accept: text/html\r\nContent-Type: application/json\r\n".to_vec();
+        let expected_bytes = b"Content-Type: application/json\r\nAccept: text/html\r\n".to_vec();
         let bytes: Vec<u8> = headers.into();
         assert_eq!(bytes, expected_bytes);
     }
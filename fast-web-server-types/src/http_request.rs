@@ -1,8 +1,8 @@
-use std::{io::{BufReader, Read, BufRead, self, ErrorKind}, error::Error};
+use std::{io::{BufReader, Read, BufRead, self}, collections::HashMap, str::FromStr, sync::Arc};
 
 use thiserror::Error;
 
-use crate::{start_line::StartLine, HttpHeaders};
+use crate::{start_line::StartLine, request_target::RequestTarget, Extensions, HttpHeaders, HttpResponse, HttpVersion, RequestLimits, RequestType, StatusCode};
 
 
 #[derive(Debug)]
@@ -10,81 +10,258 @@ pub struct HttpRequest {
     pub start_line: StartLine,
     pub headers: HttpHeaders,
     pub body: String,
+    /// Values of the `{name}` segments of the matched route, filled in by the router.
+    pub path_params: HashMap<String, String>,
+    /// Application state registered with `FastWebServer::with_state`.
+    pub state: Arc<Extensions>,
+    /// Per-request data attached by the server and middlewares.
+    pub extensions: Extensions,
 }
 
 impl HttpRequest {
-    pub fn new(stream: &mut dyn Read) -> Result<Self, Box<dyn Error>> {
+    /// Reads a whole request without any size limits, for trusted input.
+    /// Requests from the network should be read with `with_limits`.
+    pub fn new(stream: &mut dyn Read) -> Result<Self, HttpRequestError> {
+        Self::with_limits(stream, &RequestLimits::unbounded())
+    }
 
+    /// Reads a whole request, refusing one that exceeds `limits`.
+    pub fn with_limits(stream: &mut dyn Read, limits: &RequestLimits) -> Result<Self, HttpRequestError> {
         let mut reader: BufReader<&mut dyn Read> = BufReader::new(stream);
+        let mut request = Self::read_head(&mut reader, limits)?;
+        request.read_body(&mut reader, limits.max_body)?;
+        Ok(request)
+    }
 
-        let start_line = StartLine::new(&mut reader)?;
-        let headers = Self::parse_headers(&mut reader)?;
-        let content_length = headers.get("Content-Length").map_or("0", String::as_str);
-        let content_length = content_length.parse::<usize>().unwrap();
-        let body = Self::parse_body(&mut reader, content_length);
+    /// Reads the start line and headers, leaving the body empty. Together
+    /// with `read_body` this lets the server bound each phase separately and
+    /// pick the body limit of the matched route.
+    pub fn read_head(reader: &mut dyn BufRead, limits: &RequestLimits) -> Result<Self, HttpRequestError> {
+        let line = match Self::read_line(reader, limits.max_start_line)? {
+            Some(line) => line,
+            None => return Err(HttpRequestError::UriTooLong),
+        };
+        let start_line = StartLine::new(&mut line.as_bytes())
+            .map_err(|e| HttpRequestError::StartLine(e.to_string()))?;
+        let headers = Self::parse_headers(reader, limits)?;
 
         Ok(Self {
             start_line,
             headers,
-            body: body?,
+            body: String::new(),
+            path_params: HashMap::default(),
+            state: Arc::default(),
+            extensions: Extensions::default(),
+        })
+    }
+
+    /// Builds a request from an already parsed method, target and headers,
+    /// e.g. from the pseudo-header fields of an HTTP/2 stream.
+    pub fn from_parts(request_type: RequestType, target: &str, http_version: HttpVersion, headers: HttpHeaders) -> Result<Self, HttpRequestError> {
+        let request_target = RequestTarget::new(&target.to_string())
+            .map_err(HttpRequestError::StartLine)?;
+        Ok(Self {
+            start_line: StartLine { request_type, request_target, http_version },
+            headers,
+            body: String::new(),
+            path_params: HashMap::default(),
+            state: Arc::default(),
+            extensions: Extensions::default(),
         })
     }
 
-    fn parse_headers(reader: &mut dyn BufRead) -> Result<HttpHeaders, Box<dyn Error>> {
+    /// The declared body length, 0 without a `Content-Length` header.
+    pub fn content_length(&self) -> Result<usize, HttpRequestError> {
+        let content_length = self.headers.get("Content-Length").map_or("0", String::as_str);
+        content_length.parse::<usize>()
+            .map_err(|_| HttpRequestError::ContentLength(content_length.to_owned()))
+    }
+
+    /// Reads the `Content-Length` bytes of body following the head,
+    /// refusing bodies larger than `max_body` before reading them.
+    pub fn read_body(&mut self, reader: &mut dyn BufRead, max_body: usize) -> Result<(), HttpRequestError> {
+        let body = self.read_raw_body(reader, max_body)?;
+        self.set_body(body)
+    }
+
+    /// Like `read_body`, but returns the bytes as received instead of
+    /// setting them as the body, e.g. to undo a `Content-Encoding` first.
+    pub fn read_raw_body(&self, reader: &mut dyn BufRead, max_body: usize) -> Result<Vec<u8>, HttpRequestError> {
+        let content_length = self.content_length()?;
+        if content_length > max_body {
+            return Err(HttpRequestError::BodyTooLarge(content_length, max_body));
+        }
+        Self::parse_body(reader, content_length)
+    }
+
+    /// Sets the body, which must be valid UTF-8.
+    pub fn set_body(&mut self, body: Vec<u8>) -> Result<(), HttpRequestError> {
+        self.body = String::from_utf8(body).map_err(|_| HttpRequestError::Encoding)?;
+        Ok(())
+    }
+
+    /// Reads a line of at most `limit` bytes, without the line ending.
+    /// Returns `None` if the line is longer.
+    fn read_line(reader: &mut dyn BufRead, limit: usize) -> Result<Option<String>, HttpRequestError> {
+        let mut line = vec![];
+        reader.take((limit as u64).saturating_add(2)).read_until(b'\n', &mut line)?;
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        if line.len() > limit {
+            return Ok(None);
+        }
+        String::from_utf8(line).map(Some).map_err(|_| HttpRequestError::Encoding)
+    }
+
+    pub fn path_param<T: FromStr>(&self, name: &str) -> Result<T, PathParamError>
+    where T::Err: std::fmt::Display {
+        let value = match self.path_params.get(name) {
+            Some(value) => value,
+            None => return Err(PathParamError::Missing(name.to_owned())),
+        };
+        value.parse::<T>()
+            .map_err(|e| PathParamError::Invalid(name.to_owned(), value.to_owned(), e.to_string()))
+    }
+
+    fn parse_headers(reader: &mut dyn BufRead, limits: &RequestLimits) -> Result<HttpHeaders, HttpRequestError> {
         let mut headers = HttpHeaders::new();
+        let mut header_bytes = 0;
         loop {
-            let mut line = String::new();
-            let len = reader.read_line(&mut line).expect("Could not read header line");
-            line = line.trim().to_string();
-            if len == 0 || line.is_empty() {
+            let remaining = limits.max_header_bytes.saturating_sub(header_bytes);
+            let line = match Self::read_line(reader, remaining)? {
+                Some(line) => line,
+                None => return Err(HttpRequestError::HeadersTooLarge),
+            };
+            header_bytes += line.len() + 2;
+            let line = line.trim();
+            if line.is_empty() {
                 break;
             }
+            if headers.len() == limits.max_headers {
+                return Err(HttpRequestError::HeadersTooLarge);
+            }
             let parts: Vec<&str> = line.splitn(2, ": ").collect();
             let (key, value) = match parts[..] {
                 [a, b] => (a, b),
-                _ => return Err(HttpRequestError(String::from("Could not parse header")).into()),
+                _ => return Err(HttpRequestError::Header),
             };
             headers.insert(key.to_owned(), value.to_owned());
         }
-        // reader.take(2).read_to_string(&mut line);
-        // match reader.read_line(&mut line) {
-            // Ok(_) => {},
-            // Err(e) => return Err(e.to_string()),
-        // }
         Ok(headers)
     }
 
-    fn parse_body(reader: &mut dyn BufRead, content_length: usize) -> Result<String, io::Error> {
-        let mut body = vec![];
-        let mut remaining = content_length;
-        let mut buf = [0u8; 4096];
+    /// Reads exactly `content_length` bytes, leaving anything after them,
+    /// such as a pipelined request, in `reader`.
+    fn parse_body(reader: &mut dyn BufRead, content_length: usize) -> Result<Vec<u8>, HttpRequestError> {
+        let mut body = Vec::with_capacity(content_length.min(64 * 1024));
+        reader.take(content_length as u64).read_to_end(&mut body)?;
+        if body.len() < content_length {
+            return Err(HttpRequestError::IncompleteBody);
+        }
+        Ok(body)
+    }
+}
 
-        while remaining > 0 {
-            let len = reader.read(&mut buf)?;
-            if len == 0 {
-                return Err(io::Error::new(ErrorKind::InvalidData, "Could not read entire body"));
-            }
-            body.extend_from_slice(&buf[..len]);
-            remaining -= len as usize;
+/// Why a request could not be read from the connection.
+#[derive(Debug, Error)]
+pub enum HttpRequestError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    StartLine(String),
+    #[error("Could not parse header")]
+    Header,
+    #[error("invalid Content-Length {0:?}")]
+    ContentLength(String),
+    #[error("Could not read entire body")]
+    IncompleteBody,
+    #[error("request line too long")]
+    UriTooLong,
+    #[error("too many or too large header fields")]
+    HeadersTooLarge,
+    #[error("body of {0} bytes exceeds the limit of {1} bytes")]
+    BodyTooLarge(usize, usize),
+    #[error("request body is not valid UTF-8")]
+    Encoding,
+    #[error("unsupported content coding {0:?}")]
+    UnsupportedEncoding(String),
+    #[error("decoded body exceeds the limit of {0} bytes")]
+    DecodedBodyTooLarge(usize),
+    #[error("could not decode {0} body: {1}")]
+    Decoding(&'static str, String),
+    #[error("unsupported expectation {0:?}")]
+    ExpectationFailed(String),
+}
+
+impl HttpRequestError {
+    /// Whether reading stopped because a deadline or socket timeout expired.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Self::Io(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock))
+    }
+
+    /// The status to answer with, if the connection can still take a response.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            _ if self.is_timeout() => Some(StatusCode::Code408),
+            Self::Io(_) => None,
+            Self::UriTooLong => Some(StatusCode::Code414),
+            Self::HeadersTooLarge => Some(StatusCode::Code431),
+            Self::BodyTooLarge(..) | Self::DecodedBodyTooLarge(_) => Some(StatusCode::Code413),
+            Self::UnsupportedEncoding(_) => Some(StatusCode::Code415),
+            Self::ExpectationFailed(_) => Some(StatusCode::Code417),
+            _ => Some(StatusCode::Code400),
+        }
+    }
+
+    /// Short label for the error, e.g. for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Io(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => "timeout",
+            Self::Io(_) => "io",
+            Self::StartLine(_) => "start_line",
+            Self::Header => "header",
+            Self::ContentLength(_) => "content_length",
+            Self::IncompleteBody => "incomplete_body",
+            Self::UriTooLong => "uri_too_long",
+            Self::HeadersTooLarge => "headers_too_large",
+            Self::BodyTooLarge(..) => "body_too_large",
+            Self::Encoding => "encoding",
+            Self::UnsupportedEncoding(_) => "unsupported_encoding",
+            Self::DecodedBodyTooLarge(_) => "decoded_body_too_large",
+            Self::Decoding(..) => "decoding",
+            Self::ExpectationFailed(_) => "expectation_failed",
         }
-        Ok(String::from_utf8(body).expect("Could not transform body to utf8"))
     }
 }
 
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
-#[error("{0}")]
-struct HttpRequestError(String);
+pub enum PathParamError {
+    #[error("missing path parameter `{0}`")]
+    Missing(String),
+    #[error("invalid path parameter `{0}` = {1:?}: {2}")]
+    Invalid(String, String, String),
+}
+
+impl From<PathParamError> for HttpResponse {
+    fn from(error: PathParamError) -> Self {
+        HttpResponse::json_error(StatusCode::Code400, "bad_request", Some(&error.to_string()))
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use std::{io::{Cursor, ErrorKind}, collections::HashMap};
+    use std::{io::{BufRead, BufReader, Cursor}, collections::HashMap};
 
-    use crate::{http_request::{HttpRequest}, start_line::StartLine, RequestType, request_target::RequestTarget};
+    use crate::{http_request::{HttpRequest, HttpRequestError, PathParamError}, RequestLimits, StatusCode, start_line::StartLine, RequestType, request_target::RequestTarget};
 
     #[test]
     fn test_parse_headers() {
         let mut input = b"Content-Type: text/plain\r\nUser-Agent: curl/7.64.1\r\n\r\n" as &[u8];
-        let headers = HttpRequest::parse_headers(&mut input).unwrap();
+        let headers = HttpRequest::parse_headers(&mut input, &RequestLimits::default()).unwrap();
         assert_eq!(headers.get("Content-Type"), Some(&"text/plain".to_owned()));
         assert_eq!(headers.get("User-Agent"), Some(&"curl/7.64.1".to_owned()));
         assert_eq!(headers.get("Content-Length"), None);
@@ -92,7 +269,7 @@ mod tests {
     #[test]
     fn test_parse_headers_with_invalid_header() {
         let mut input = b"Content-Type text/plain\r\n\r\n" as &[u8];
-        let result = HttpRequest::parse_headers(&mut input);
+        let result = HttpRequest::parse_headers(&mut input, &RequestLimits::default());
         assert!(result.is_err());
         assert_eq!(result.err().unwrap().to_string(), "Could not parse header");
     }
@@ -102,7 +279,22 @@ mod tests {
         let mut input = b"hello world" as &[u8];
         let len = input.len();
         let body = HttpRequest::parse_body(&mut input, len).unwrap();
-        assert_eq!(body, "hello world");
+        assert_eq!(body, b"hello world");
+    }
+
+    #[test]
+    fn test_parse_body_longer_than_content_length() {
+        let mut input = b"hello world" as &[u8];
+        let body = HttpRequest::parse_body(&mut input, 5).unwrap();
+        assert_eq!(body, b"hello");
+        assert_eq!(input, b" world");
+
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhello";
+        let mut reader = BufReader::new(&raw[..]);
+        let mut request = HttpRequest::read_head(&mut reader, &RequestLimits::default()).unwrap();
+        request.read_body(&mut reader, 1024).unwrap();
+        assert_eq!(request.body, "he");
+        assert_eq!(reader.fill_buf().unwrap(), b"llo");
     }
 
     #[test]
@@ -110,9 +302,7 @@ mod tests {
         let mut input = b"hello world" as &[u8];
         let len = input.len();
         let body = HttpRequest::parse_body(&mut input, len + 1); // input.len() + 1 bytes
-        assert!(body.is_err());
-        let err = body.err().unwrap().kind();
-        assert_eq!(err, ErrorKind::InvalidData);
+        assert!(matches!(body, Err(HttpRequestError::IncompleteBody)));
     }
 
     #[test]
@@ -120,7 +310,7 @@ mod tests {
         let input = b"GET / HTTP/1.1\r\nHost: localhost:3000\r\nContent-Type: text/plain\r\nContent-Length: 11\r\n\r\nhello world";
         let mut stream = Cursor::new(input.as_ref());
         let request = HttpRequest::new(&mut stream).unwrap();
-        let expected = StartLine { request_type: RequestType::GET, request_target: RequestTarget {uri: String::from("/"), request_params: HashMap::default() }, http_version: crate::HttpVersion::HTTP1_1 };
+        let expected = StartLine { request_type: RequestType::GET, request_target: RequestTarget {raw: String::from("/"), uri: String::from("/"), request_params: HashMap::default() }, http_version: crate::HttpVersion::HTTP1_1 };
         assert_eq!(request.start_line, expected);
         let headers = request.headers;
         assert_eq!(headers.get("Host"), Some(&"localhost:3000".to_owned()));
@@ -137,4 +327,62 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(result.err().unwrap().to_string(), "Could not read entire body");
     }
+
+    #[test]
+    fn test_limits() {
+        let limits = RequestLimits::new().max_start_line(16).max_headers(2).max_header_bytes(40).max_body(4);
+        let parse = |input: &str| HttpRequest::with_limits(&mut input.as_bytes(), &limits);
+
+        assert!(parse("GET / HTTP/1.1\r\nA: 1\r\nContent-Length: 4\r\n\r\nbody").is_ok());
+
+        let error = parse("GET /abcdefghijk HTTP/1.1\r\n\r\n").err().unwrap();
+        assert!(matches!(error, HttpRequestError::UriTooLong));
+        assert_eq!(error.status_code(), Some(StatusCode::Code414));
+
+        let error = parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n").err().unwrap();
+        assert_eq!(error.status_code(), Some(StatusCode::Code431));
+        let error = parse(&format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(40))).err().unwrap();
+        assert!(matches!(error, HttpRequestError::HeadersTooLarge));
+
+        let error = parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").err().unwrap();
+        assert!(matches!(error, HttpRequestError::BodyTooLarge(5, 4)));
+        assert_eq!(error.status_code(), Some(StatusCode::Code413));
+
+        // `new` applies no limits, not even the defaults.
+        let body = "a".repeat(RequestLimits::default().max_body + 1);
+        let input = format!("POST /{} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", "a".repeat(10_000), body.len(), body);
+        assert_eq!(HttpRequest::new(&mut input.as_bytes()).unwrap().body, body);
+        assert!(HttpRequest::with_limits(&mut input.as_bytes(), &RequestLimits::default()).is_err());
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let parse = |input: &str| HttpRequest::new(&mut Cursor::new(input.as_bytes())).err().unwrap().kind();
+        assert_eq!(parse("GET /\r\n\r\n"), "start_line");
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost localhost\r\n\r\n"), "header");
+        assert_eq!(parse("GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"), "content_length");
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let mut headers = crate::HttpHeaders::new();
+        headers.insert("host".to_owned(), "example.test".to_owned());
+        let request = HttpRequest::from_parts(RequestType::GET, "/a?b=c", crate::HttpVersion::HTTP2, headers).unwrap();
+        assert_eq!(request.start_line.request_target.uri, "/a");
+        assert_eq!(request.start_line.request_target.raw, "/a?b=c");
+        assert_eq!(request.headers.get("Host").unwrap(), "example.test");
+        assert!(matches!(HttpRequest::from_parts(RequestType::GET, "/a?b", crate::HttpVersion::HTTP2, Default::default()), Err(HttpRequestError::StartLine(_))));
+    }
+
+    #[test]
+    fn test_path_param() {
+        let input = b"GET /users/42 HTTP/1.1\r\n\r\n";
+        let mut request = HttpRequest::new(&mut Cursor::new(input.as_ref())).unwrap();
+        request.path_params.insert("id".to_owned(), "42".to_owned());
+        assert_eq!(request.path_param::<u64>("id"), Ok(42));
+        assert_eq!(request.path_param::<u64>("name"), Err(PathParamError::Missing("name".to_owned())));
+        assert!(matches!(request.path_param::<u8>("id"), Ok(42)));
+        request.path_params.insert("id".to_owned(), "abc".to_owned());
+        assert!(matches!(request.path_param::<u64>("id"), Err(PathParamError::Invalid(..))));
+    }
 }
\ No newline at end of file
@@ -1,41 +1,150 @@
-use crate::{status_line::StatusLine, HttpHeaders};
+use std::time::SystemTime;
+use crate::{status_line::StatusLine, ETag, FileBody, HttpHeaders, StatusCode, StreamingBody};
 
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct HttpResponse {
     pub status_line: StatusLine,
     pub headers: HttpHeaders,
-    pub body: String,
+    pub body: Vec<u8>,
+    /// A body sent after `body` while the response is being written.
+    pub stream: Option<StreamingBody>,
+    /// A file sent after `body`, counted in `Content-Length`.
+    pub file: Option<FileBody>,
 }
 
 impl HttpResponse {
 
-    pub fn from_body(body: String) -> Self {
+    pub fn from_body(body: impl Into<Vec<u8>>) -> Self {
+        let body = body.into();
         let mut headers = HttpHeaders::default();
-        headers.insert(String::from("Content-Length"), body.len().to_string());
         headers.insert(String::from("Connection"), String::from("close"));
+        headers.insert(String::from("Content-Length"), body.len().to_string());
         Self {
             status_line: Default::default(),
             headers: headers,
             body,
+            stream: None,
+            file: None,
         }
     }
+
+    /// A response whose body is produced by `stream` as it is sent.
+    pub fn streaming(stream: StreamingBody) -> Self {
+        let mut response = Self::from_body(vec![]);
+        response.headers.remove("Content-Length");
+        response.stream = Some(stream);
+        response
+    }
+
+    /// A response whose body is sent from `file` without being read into
+    /// memory where the connection allows it.
+    pub fn from_file(file: FileBody) -> Self {
+        let mut response = Self::from_body(vec![]);
+        response.headers.insert(String::from("Content-Length"), file.len().to_string());
+        response.file = Some(file);
+        response
+    }
+
+    pub fn with_status(status_code: StatusCode, body: impl Into<Vec<u8>>) -> Self {
+        let mut response = Self::from_body(body);
+        response.status_line.status_code = status_code;
+        response
+    }
+
+    /// Replaces the body and updates `Content-Length` to match.
+    pub fn set_body(&mut self, body: impl Into<Vec<u8>>) {
+        self.body = body.into();
+        self.headers.insert(String::from("Content-Length"), self.body.len().to_string());
+    }
+
+    /// Adds `header` to the `Vary` list, keeping the headers already there.
+    pub fn add_vary(&mut self, header: &str) {
+        let vary = match self.headers.get("Vary") {
+            Some(vary) if vary.split(',').any(|name| name.trim().eq_ignore_ascii_case(header) || name.trim() == "*") => return,
+            Some(vary) => format!("{}, {}", vary, header),
+            None => header.to_string(),
+        };
+        self.headers.insert(String::from("Vary"), vary);
+    }
+
+    pub fn set_etag(&mut self, etag: &ETag) {
+        self.headers.insert(String::from("ETag"), etag.to_string());
+    }
+
+    /// The `ETag` header, if it holds a valid entity tag.
+    pub fn etag(&self) -> Option<ETag> {
+        self.headers.get("ETag").and_then(|etag| ETag::parse(etag))
+    }
+
+    pub fn set_last_modified(&mut self, modified: SystemTime) {
+        self.headers.insert(String::from("Last-Modified"), httpdate::fmt_http_date(modified));
+    }
+
+    /// The `Last-Modified` header, if it holds a valid HTTP date.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.headers.get("Last-Modified").and_then(|modified| httpdate::parse_http_date(modified).ok())
+    }
+
+    /// Turns the response into a `304 Not Modified`. The body and the
+    /// headers describing it are dropped; validators and caching headers
+    /// like `ETag`, `Cache-Control` and `Vary` are kept, RFC 9110 section 15.4.5.
+    pub fn into_not_modified(mut self) -> Self {
+        for name in ["Content-Length", "Content-Type", "Content-Encoding", "Content-Language", "Content-Range"] {
+            self.headers.remove(name);
+        }
+        self.status_line.status_code = StatusCode::Code304;
+        self.body = vec![];
+        self.stream = None;
+        self.file = None;
+        self
+    }
+
+    /// Appends the status line and headers, up to and including the blank
+    /// line before the body. The server writes this head and the body
+    /// without joining them into one buffer.
+    pub fn write_head(&self, buf: &mut Vec<u8>) {
+        self.status_line.write_to(buf);
+        self.headers.write_to(buf);
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    /// Builds a JSON error response like `{"error": "not_found"}`, with an
+    /// optional human readable `message`.
+    pub fn json_error(status_code: StatusCode, error: &str, message: Option<&str>) -> Self {
+        let body = match message {
+            Some(message) => format!("{{\"error\": \"{}\", \"message\": \"{}\"}}", escape_json(error), escape_json(message)),
+            None => format!("{{\"error\": \"{}\"}}", escape_json(error)),
+        };
+        let mut response = Self::with_status(status_code, body);
+        response.headers.insert("Content-Type".to_string(), "application/json".to_string());
+        response
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
 }
 
 impl From<HttpResponse> for Vec<u8> {
     fn from(http_response: HttpResponse) -> Self {
-        let mut status_line: Vec<u8> = http_response.status_line.into();
-        let mut headers: Vec<u8> = http_response.headers.into();
-        let body = http_response.body.as_bytes();
-
-        let size = status_line.len() + headers.len() + 4 + body.len();
-        let mut buf = Vec::with_capacity(size);
-
-        buf.append(&mut status_line);
-        buf.append(&mut headers);
-        buf.extend(b"\r\n");
-        buf.extend_from_slice(body);
+        let mut buf = Vec::with_capacity(256 + http_response.body.len());
+        http_response.write_head(&mut buf);
+        // Streaming and file bodies are written separately, after this head.
+        buf.extend_from_slice(&http_response.body);
         buf
     }
 }
@@ -45,6 +154,7 @@ impl From<HttpResponse> for Vec<u8> {
 mod tests {
 
     use super::*;
+    use crate::BodyStream;
 
     #[test]
     fn test_empty() {
@@ -61,4 +171,76 @@ mod tests {
         let actual: Vec<u8> = response.into();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn test_add_vary() {
+        let mut response = HttpResponse::from_body("");
+        response.add_vary("Origin");
+        response.add_vary("Accept-Encoding");
+        response.add_vary("origin");
+        assert_eq!(response.headers.get("Vary"), Some(&"Origin, Accept-Encoding".to_string()));
+    }
+
+    #[test]
+    fn test_validators() {
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+        let mut response = HttpResponse::from_body("body");
+        response.set_etag(&ETag::weak("v1"));
+        response.set_last_modified(modified);
+        response.headers.insert("Content-Type".to_string(), "text/plain".to_string());
+        response.headers.insert("Cache-Control".to_string(), "max-age=60".to_string());
+        assert_eq!(response.etag(), Some(ETag::weak("v1")));
+        assert_eq!(response.last_modified(), Some(modified));
+
+        let response = response.into_not_modified();
+        let head: Vec<u8> = response.into();
+        assert_eq!(String::from_utf8(head).unwrap(), "HTTP/1.1 304 Not Modified\r\nConnection: close\r\nETag: W/\"v1\"\r\n\
+            Last-Modified: Sun, 06 Nov 1994 08:49:37 GMT\r\nCache-Control: max-age=60\r\n\r\n");
+    }
+
+    #[test]
+    fn test_streaming() {
+        struct Once(bool);
+        impl crate::BodyStream for Once {
+            fn poll_chunk(&mut self) -> crate::Chunk {
+                match std::mem::replace(&mut self.0, true) {
+                    false => crate::Chunk::Data(b"data".to_vec()),
+                    true => crate::Chunk::End,
+                }
+            }
+        }
+        let mut response = HttpResponse::streaming(StreamingBody::new(Once(false)));
+        let mut stream = response.stream.take().unwrap();
+        assert_eq!(stream.poll_chunk(), crate::Chunk::Data(b"data".to_vec()));
+        assert_eq!(stream.poll_chunk(), crate::Chunk::End);
+        let head: Vec<u8> = response.into();
+        assert_eq!(head, b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_from_file() {
+        let path = std::env::temp_dir().join(format!("fast-web-server-file-body-{}", std::process::id()));
+        std::fs::write(&path, "0123456789").unwrap();
+        let (body, range) = (FileBody::open(&path), std::fs::File::open(&path));
+        // Open handles keep the contents; removing the file first cleans up after failures too.
+        std::fs::remove_file(&path).unwrap();
+        let mut response = HttpResponse::from_file(body.unwrap());
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "10");
+        assert!(response.body.is_empty());
+        let mut file = response.file.take().unwrap();
+        assert_eq!(file.poll_chunk(), crate::Chunk::Data(b"0123456789".to_vec()));
+        assert_eq!(file.poll_chunk(), crate::Chunk::End);
+
+        let mut range = FileBody::with_range(range.unwrap(), 2, 6).slice(1, 4);
+        assert_eq!(range.poll_chunk(), crate::Chunk::Data(b"3456".to_vec()));
+        assert_eq!(range.poll_chunk(), crate::Chunk::End);
+    }
+
+    #[test]
+    fn test_json_error() {
+        let response = HttpResponse::json_error(StatusCode::Code400, "bad_request", Some("invalid \"id\""));
+        let expected = "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 53\r\nContent-Type: application/json\r\n\r\n{\"error\": \"bad_request\", \"message\": \"invalid \\\"id\\\"\"}".as_bytes().to_vec();
+        let actual: Vec<u8> = response.into();
+        assert_eq!(expected, actual);
+    }
 }
\ No newline at end of file
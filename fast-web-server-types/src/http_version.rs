@@ -5,20 +5,27 @@ pub enum HttpVersion {
     HTTP1_0,
     #[default]
     HTTP1_1,
+    HTTP2,
 }
 
 impl HttpVersion {
-    pub fn to_string(&self) -> String {
+    pub const fn as_str(&self) -> &'static str {
         match self {
             HttpVersion::HTTP1_0 => "HTTP/1.0",
             HttpVersion::HTTP1_1 => "HTTP/1.1",
-        }.to_string()
+            HttpVersion::HTTP2 => "HTTP/2",
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        self.as_str().to_string()
     }
 
     pub fn from_string(s: &String) -> Self {
         match s.as_str() {
             "HTTP/1.0" => Self::HTTP1_0,
             "HTTP/1.1" => Self::HTTP1_1,
+            "HTTP/2" | "HTTP/2.0" => Self::HTTP2,
             _ => unimplemented!(),
         }
     }
@@ -7,8 +7,15 @@ mod http_response;
 mod request_type;
 mod status_line;
 mod status_code;
+mod route_pattern;
+mod extract;
+mod extensions;
+mod request_id;
+mod limits;
+mod body;
+mod conditional;
 
-pub use crate::http_request::HttpRequest;
+pub use crate::http_request::{HttpRequest, HttpRequestError, PathParamError};
 pub use crate::http_headers::HttpHeaders;
 pub use crate::http_version::HttpVersion;
 // use crate::start_line::StartLine;
@@ -16,21 +23,70 @@ pub use crate::request_type::RequestType;
 pub use crate::http_response::HttpResponse;
 pub use crate::status_code::StatusCode;
 pub use crate::status_line::StatusLine;
+pub use crate::route_pattern::{RoutePattern, RoutePatternError, Segment};
+pub use crate::extract::{ExtractError, Form, FromRequest, Header, Json, Path, Query, State};
+pub use crate::extensions::Extensions;
+pub use crate::request_id::RequestId;
+pub use crate::limits::RequestLimits;
+pub use crate::body::{BodyStream, Chunk, FileBody, StreamingBody};
+pub use crate::conditional::{ETag, Precondition, Preconditions};
 
 
 
-// pub type HttpFn = fn(HttpRequest) -> HttpResponse;
-// #![feature(type_alias_impl_trait)]
-pub type HttpFn = fn(HttpRequest) -> Vec<u8>;
+pub type HttpFn = fn(HttpRequest) -> HttpResponse;
+
+/// A route handler. Implemented for plain functions like `HttpFn` and for
+/// any `Fn(HttpRequest) -> impl Responder` closure.
+pub trait Handler: Send + Sync {
+    fn call(&self, request: HttpRequest) -> HttpResponse;
+}
+
+impl<F, R> Handler for F
+where
+    F: Fn(HttpRequest) -> R + Send + Sync,
+    R: Responder,
+{
+    fn call(&self, request: HttpRequest) -> HttpResponse {
+        self(request).respond()
+    }
+}
 
 
 
+/// Anything a route handler can return.
 pub trait Responder {
-    fn transform(&self) -> Vec<u8>;
+    fn respond(self) -> HttpResponse;
+}
+
+impl Responder for HttpResponse {
+    fn respond(self) -> HttpResponse {
+        self
+    }
 }
 
 impl Responder for Vec<u8> {
-    fn transform(&self) -> Vec<u8> {
-        self.to_owned()
+    fn respond(self) -> HttpResponse {
+        HttpResponse::from_body(self)
+    }
+}
+
+impl Responder for String {
+    fn respond(self) -> HttpResponse {
+        HttpResponse::from_body(self)
+    }
+}
+
+impl Responder for &'static str {
+    fn respond(self) -> HttpResponse {
+        HttpResponse::from_body(self)
+    }
+}
+
+impl<T: Responder, E: Responder> Responder for Result<T, E> {
+    fn respond(self) -> HttpResponse {
+        match self {
+            Ok(ok) => ok.respond(),
+            Err(err) => err.respond(),
+        }
     }
 }
@@ -0,0 +1,55 @@
+/// Upper bounds on the parts of a request, checked while it is read so an
+/// oversized request is rejected before it is buffered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// Bytes of the request line, answered with `414 URI Too Long`.
+    pub max_start_line: usize,
+    /// Number of header fields, answered with `431 Request Header Fields Too Large`.
+    pub max_headers: usize,
+    /// Bytes of all header lines together, answered with `431`.
+    pub max_header_bytes: usize,
+    /// Bytes of body, answered with `413 Content Too Large`.
+    pub max_body: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_start_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 16 * 1024,
+            max_body: 1024 * 1024,
+        }
+    }
+}
+
+impl RequestLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// No limits at all, as used by `HttpRequest::new`.
+    pub fn unbounded() -> Self {
+        Self { max_start_line: usize::MAX, max_headers: usize::MAX, max_header_bytes: usize::MAX, max_body: usize::MAX }
+    }
+
+    pub fn max_start_line(mut self, bytes: usize) -> Self {
+        self.max_start_line = bytes;
+        self
+    }
+
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.max_headers = count;
+        self
+    }
+
+    pub fn max_header_bytes(mut self, bytes: usize) -> Self {
+        self.max_header_bytes = bytes;
+        self
+    }
+
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+}
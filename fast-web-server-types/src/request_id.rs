@@ -0,0 +1,99 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{ExtractError, FromRequest, HttpRequest};
+
+
+/// Identifies a request across logs and services. The server takes it from
+/// the `X-Request-Id` header when the client sends a valid one, generates
+/// one otherwise, and echoes it in the response.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub const HEADER: &'static str = "X-Request-Id";
+    const MAX_LEN: usize = 200;
+
+    /// A random 128-bit identifier in hex.
+    pub fn generate() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let random = |salt: u64| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed) ^ salt);
+            hasher.finish()
+        };
+        Self(format!("{:016x}{:016x}", random(0), random(u64::MAX)))
+    }
+
+    /// Accepts a propagated identifier made of up to 200 visible ASCII characters.
+    pub fn from_header(value: &str) -> Option<Self> {
+        let valid = !value.is_empty()
+            && value.len() <= Self::MAX_LEN
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| Self(value.to_string()))
+    }
+
+    /// The identifier sent by the client, or a new one.
+    pub fn for_request(request: &HttpRequest) -> Self {
+        request.headers.get(Self::HEADER)
+            .and_then(|value| Self::from_header(value))
+            .unwrap_or_else(Self::generate)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    fn from_request(request: &HttpRequest) -> Result<Self, ExtractError> {
+        request.extensions.get::<RequestId>()
+            .cloned()
+            .ok_or(ExtractError::MissingExtension("RequestId"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn request(raw: &str) -> HttpRequest {
+        HttpRequest::new(&mut Cursor::new(raw.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_generate() {
+        let (a, b) = (RequestId::generate(), RequestId::generate());
+        assert_eq!(a.as_str().len(), 32);
+        assert!(a.as_str().bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_propagated() {
+        let id = RequestId::for_request(&request("GET / HTTP/1.1\r\nX-Request-Id: abc-123\r\n\r\n"));
+        assert_eq!(id.as_str(), "abc-123");
+
+        let id = RequestId::for_request(&request("GET / HTTP/1.1\r\nX-Request-Id: has space\r\n\r\n"));
+        assert_eq!(id.as_str().len(), 32);
+        assert_eq!(RequestId::from_header(&"a".repeat(201)), None);
+    }
+
+    #[test]
+    fn test_from_request() {
+        let mut request = request("GET / HTTP/1.1\r\n\r\n");
+        assert!(matches!(RequestId::from_request(&request), Err(ExtractError::MissingExtension("RequestId"))));
+        request.extensions.insert(RequestId("abc".to_string()));
+        assert_eq!(RequestId::from_request(&request).unwrap().as_str(), "abc");
+    }
+}
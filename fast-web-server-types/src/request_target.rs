@@ -4,6 +4,8 @@ use std::collections::HashMap;
 
 #[derive(Debug, PartialEq)]
 pub struct RequestTarget {
+    /// The target as sent by the client, including the query string.
+    pub raw: String,
     pub uri: String,
     pub request_params: HashMap<String, String>,
 }
@@ -13,13 +15,14 @@ impl RequestTarget {
         let parts: Vec<String> = s.split("?").map(str::to_string).collect();
         match &parts[..] {
             [uri] => Ok(Self  {
+                raw: s.to_owned(),
                 uri: uri.to_owned(),
                 request_params: HashMap::default(),
             }),
-            [uri, params] => {
-                Self::with_request_params(uri, params)
-
-            },
+            [uri, params] => Ok(Self {
+                raw: s.to_owned(),
+                ..Self::with_request_params(uri, params)?
+            }),
             _ => Err(String::from("Could not parse uri+params"))
         }
     }
@@ -34,6 +37,7 @@ impl RequestTarget {
             };
         }
         Ok(Self {
+            raw: format!("{}?{}", uri, params),
             uri: uri.to_owned(),
             request_params,
         })
@@ -49,6 +53,7 @@ mod tests {
         let s = String::from("/path/to/resource");
         let rt = RequestTarget::new(&s).unwrap();
         assert_eq!(rt.uri, "/path/to/resource");
+        assert_eq!(rt.raw, "/path/to/resource");
         assert!(rt.request_params.is_empty());
     }
 
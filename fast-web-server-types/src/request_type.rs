@@ -33,6 +33,26 @@ impl RequestType {
     pub fn from_str(s: &str) -> Result<Self, RequestTypeError> {
         Self::from_string(&String::from(s))
     }
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::GET => "GET",
+            Self::HEAD => "HEAD",
+            Self::POST => "POST",
+            Self::PUT => "PUT",
+            Self::DELETE => "DELETE",
+            Self::CONNECT => "CONNECT",
+            Self::OPTIONS => "OPTIONS",
+            Self::TRACE => "TRACE",
+            Self::PATCH => "PATCH",
+        }
+    }
+}
+
+impl fmt::Display for RequestType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 #[derive(Debug)]
@@ -56,6 +76,15 @@ mod tests {
         assert_eq!(RequestType::GET, actual.unwrap())
     }
 
+    #[test]
+    fn as_str_round_trip() {
+        for name in ["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"] {
+            let request_type = RequestType::from_str(name).unwrap();
+            assert_eq!(name, request_type.as_str());
+            assert_eq!(name, request_type.to_string());
+        }
+    }
+
 
     #[test]
     fn unimplemented() {
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Static(String),
+    Param(String),
+    /// A trailing `{*name}` segment, matching the rest of the path.
+    Wildcard(String),
+}
+
+/// A parsed route path such as `/users/{id}/posts`, shared by the route
+/// macros (for compile-time validation) and the router (for matching).
+/// The last segment may be a wildcard like `{*path}`, matching the rest of
+/// the path including slashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutePattern {
+    segments: Vec<Segment>,
+}
+
+impl RoutePattern {
+    pub fn parse(pattern: &str) -> Result<Self, RoutePatternError> {
+        let rest = match pattern.strip_prefix('/') {
+            Some(rest) => rest,
+            None => return Err(RoutePatternError::MissingLeadingSlash),
+        };
+        if let Some((position, c)) = pattern.char_indices()
+                .find(|(_, c)| c.is_whitespace() || c.is_control() || *c == '?' || *c == '#') {
+            return Err(RoutePatternError::InvalidCharacter(c, position));
+        }
+
+        let mut segments = vec![];
+        let mut position = 1;
+        let parts: Vec<&str> = rest.split('/').collect();
+        for (i, part) in parts.iter().enumerate() {
+            let is_last = i == parts.len() - 1;
+            if part.is_empty() && !is_last {
+                return Err(RoutePatternError::EmptySegment(position));
+            }
+            let segment = Self::parse_segment(part, position)?;
+            match &segment {
+                Segment::Wildcard(name) if !is_last => return Err(RoutePatternError::WildcardNotLast(name.to_owned())),
+                Segment::Param(name) | Segment::Wildcard(name) if segments.iter().any(|s: &Segment| s.param() == Some(name.as_str())) => {
+                    return Err(RoutePatternError::DuplicateParam(name.to_owned()));
+                },
+                _ => {},
+            }
+            segments.push(segment);
+            position += part.len() + 1;
+        }
+        Ok(Self { segments })
+    }
+
+    fn parse_segment(part: &str, position: usize) -> Result<Segment, RoutePatternError> {
+        if !part.contains(['{', '}']) {
+            return Ok(Segment::Static(part.to_owned()));
+        }
+        let name = match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+            Some(name) if !name.contains(['{', '}']) => name,
+            _ => return Err(RoutePatternError::UnbalancedBraces(part.to_owned(), position)),
+        };
+        let (name, wildcard) = match name.strip_prefix('*') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        if !Self::is_identifier(name) {
+            return Err(RoutePatternError::InvalidParamName(name.to_owned()));
+        }
+        match wildcard {
+            true => Ok(Segment::Wildcard(name.to_owned())),
+            false => Ok(Segment::Param(name.to_owned())),
+        }
+    }
+
+    fn is_identifier(name: &str) -> bool {
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {},
+            _ => return false,
+        }
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn params(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(Segment::param)
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.params().next().is_none()
+    }
+
+    /// Matches a request path against the pattern, returning the
+    /// percent-decoded values of the path parameters on success. A wildcard
+    /// also matches an empty rest, so `/assets/{*path}` matches `/assets/`.
+    pub fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let parts: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+        let wildcard = matches!(self.segments.last(), Some(Segment::Wildcard(_)));
+        if parts.len() != self.segments.len() && !(wildcard && parts.len() > self.segments.len()) {
+            return None;
+        }
+        let mut params = HashMap::new();
+        for (i, (segment, part)) in self.segments.iter().zip(&parts).enumerate() {
+            match segment {
+                Segment::Static(s) if s == part => {},
+                Segment::Param(name) if !part.is_empty() => {
+                    params.insert(name.to_owned(), percent_decode(part)?);
+                },
+                Segment::Wildcard(name) => {
+                    params.insert(name.to_owned(), percent_decode(&parts[i..].join("/"))?);
+                },
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+impl Segment {
+    /// The parameter name of a `{name}` or `{*name}` segment.
+    pub fn param(&self) -> Option<&str> {
+        match self {
+            Segment::Param(name) | Segment::Wildcard(name) => Some(name),
+            Segment::Static(_) => None,
+        }
+    }
+}
+
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum RoutePatternError {
+    #[error("route path must start with `/`")]
+    MissingLeadingSlash,
+    #[error("route path contains invalid character {0:?} at position {1}")]
+    InvalidCharacter(char, usize),
+    #[error("route path contains an empty segment (`//`) at position {0}")]
+    EmptySegment(usize),
+    #[error("unbalanced braces in segment `{0}` at position {1}")]
+    UnbalancedBraces(String, usize),
+    #[error("invalid path parameter name `{0}`")]
+    InvalidParamName(String),
+    #[error("duplicate path parameter `{0}`")]
+    DuplicateParam(String),
+    #[error("wildcard `{{*{0}}}` must be the last segment")]
+    WildcardNotLast(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_static() {
+        let pattern = RoutePattern::parse("/test/nested").unwrap();
+        assert!(pattern.is_static());
+        assert_eq!(pattern.segments(), &[Segment::Static("test".to_owned()), Segment::Static("nested".to_owned())]);
+    }
+
+    #[test]
+    fn test_parse_params() {
+        let pattern = RoutePattern::parse("/users/{id}/posts/{post_id}").unwrap();
+        assert!(!pattern.is_static());
+        assert_eq!(pattern.params().collect::<Vec<_>>(), vec!["id", "post_id"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(RoutePattern::parse("test"), Err(RoutePatternError::MissingLeadingSlash));
+        assert_eq!(RoutePattern::parse("/a//b"), Err(RoutePatternError::EmptySegment(3)));
+        assert_eq!(RoutePattern::parse("/a b"), Err(RoutePatternError::InvalidCharacter(' ', 2)));
+        assert_eq!(RoutePattern::parse("/users/{id"), Err(RoutePatternError::UnbalancedBraces("{id".to_owned(), 7)));
+        assert_eq!(RoutePattern::parse("/users/id}"), Err(RoutePatternError::UnbalancedBraces("id}".to_owned(), 7)));
+        assert_eq!(RoutePattern::parse("/users/x{id}"), Err(RoutePatternError::UnbalancedBraces("x{id}".to_owned(), 7)));
+        assert_eq!(RoutePattern::parse("/users/{1d}"), Err(RoutePatternError::InvalidParamName("1d".to_owned())));
+        assert_eq!(RoutePattern::parse("/{id}/{id}"), Err(RoutePatternError::DuplicateParam("id".to_owned())));
+        assert_eq!(RoutePattern::parse("/{*rest}/x"), Err(RoutePatternError::WildcardNotLast("rest".to_owned())));
+        assert_eq!(RoutePattern::parse("/{id}/{*id}"), Err(RoutePatternError::DuplicateParam("id".to_owned())));
+        assert_eq!(RoutePattern::parse("/{*}"), Err(RoutePatternError::InvalidParamName("".to_owned())));
+    }
+
+    #[test]
+    fn test_matches() {
+        let pattern = RoutePattern::parse("/users/{id}/posts").unwrap();
+        let params = pattern.matches("/users/42/posts").unwrap();
+        assert_eq!(params.get("id"), Some(&"42".to_owned()));
+        assert_eq!(pattern.matches("/users/42"), None);
+        assert_eq!(pattern.matches("/users//posts"), None);
+        assert_eq!(pattern.matches("/accounts/42/posts"), None);
+    }
+
+    #[test]
+    fn test_matches_percent_decodes() {
+        let pattern = RoutePattern::parse("/files/{name}").unwrap();
+        let params = pattern.matches("/files/a%20b").unwrap();
+        assert_eq!(params.get("name"), Some(&"a b".to_owned()));
+        assert_eq!(pattern.matches("/files/a%2"), None);
+    }
+
+    #[test]
+    fn test_matches_wildcard() {
+        let pattern = RoutePattern::parse("/assets/{*path}").unwrap();
+        assert!(!pattern.is_static());
+        assert_eq!(pattern.params().collect::<Vec<_>>(), vec!["path"]);
+        let rest = |path| pattern.matches(path).map(|params| params["path"].clone());
+        assert_eq!(rest("/assets/css/site%20v2.css").as_deref(), Some("css/site v2.css"));
+        assert_eq!(rest("/assets/").as_deref(), Some(""));
+        assert_eq!(rest("/assets"), None);
+        assert_eq!(rest("/other/x"), None);
+    }
+}
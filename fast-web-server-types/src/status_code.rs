@@ -1,10 +1,28 @@
 
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum StatusCode {
+    Code101,
     #[default]
     Code200,
+    Code204,
+    Code206,
+    Code304,
+    Code308,
+    Code400,
+    Code401,
     Code404,
+    Code408,
+    Code412,
+    Code413,
+    Code414,
+    Code415,
+    Code416,
+    Code417,
+    Code426,
+    Code431,
+    Code500,
+    Code503,
 }
 
 
@@ -12,8 +30,51 @@ pub enum StatusCode {
 impl StatusCode {
     pub const fn to_string(&self) -> &'static str {
         match self {
+            StatusCode::Code101 => "101 Switching Protocols",
             StatusCode::Code200 => "200 OK",
+            StatusCode::Code204 => "204 No Content",
+            StatusCode::Code206 => "206 Partial Content",
+            StatusCode::Code304 => "304 Not Modified",
+            StatusCode::Code308 => "308 Permanent Redirect",
+            StatusCode::Code400 => "400 Bad Request",
+            StatusCode::Code401 => "401 Unauthorized",
             StatusCode::Code404 => "404 Not Found",
+            StatusCode::Code408 => "408 Request Timeout",
+            StatusCode::Code412 => "412 Precondition Failed",
+            StatusCode::Code413 => "413 Content Too Large",
+            StatusCode::Code414 => "414 URI Too Long",
+            StatusCode::Code415 => "415 Unsupported Media Type",
+            StatusCode::Code416 => "416 Range Not Satisfiable",
+            StatusCode::Code417 => "417 Expectation Failed",
+            StatusCode::Code426 => "426 Upgrade Required",
+            StatusCode::Code431 => "431 Request Header Fields Too Large",
+            StatusCode::Code500 => "500 Internal Server Error",
+            StatusCode::Code503 => "503 Service Unavailable",
+        }
+    }
+
+    pub const fn as_u16(&self) -> u16 {
+        match self {
+            StatusCode::Code101 => 101,
+            StatusCode::Code200 => 200,
+            StatusCode::Code204 => 204,
+            StatusCode::Code206 => 206,
+            StatusCode::Code304 => 304,
+            StatusCode::Code308 => 308,
+            StatusCode::Code400 => 400,
+            StatusCode::Code401 => 401,
+            StatusCode::Code404 => 404,
+            StatusCode::Code408 => 408,
+            StatusCode::Code412 => 412,
+            StatusCode::Code413 => 413,
+            StatusCode::Code414 => 414,
+            StatusCode::Code415 => 415,
+            StatusCode::Code416 => 416,
+            StatusCode::Code417 => 417,
+            StatusCode::Code426 => 426,
+            StatusCode::Code431 => 431,
+            StatusCode::Code500 => 500,
+            StatusCode::Code503 => 503,
         }
     }
 }
@@ -42,6 +103,12 @@ mod tests {
         assert_eq!("200 OK", StatusCode::Code200.to_string());
     }
     
+    #[test]
+    fn as_u16() {
+        assert_eq!(404, StatusCode::Code404.as_u16());
+        assert!(StatusCode::Code404.to_string().starts_with("404 "));
+    }
+
     #[test]
     fn to_vec() {
         let expected = "200 OK".as_bytes().to_vec();
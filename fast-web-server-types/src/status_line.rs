@@ -9,17 +9,20 @@ pub struct StatusLine {
 
 }
 
+impl StatusLine {
+    /// Appends the status line, e.g. `HTTP/1.1 200 OK\r\n`.
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.protocol.as_str().as_bytes());
+        buf.push(b' ');
+        buf.extend_from_slice(self.status_code.to_string().as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
 impl Into<Vec<u8>> for StatusLine {
     fn into(self) -> Vec<u8> {
-        let mut protocol: Vec<u8> = self.protocol.into();
-        let mut status_code: Vec<u8> = self.status_code.into();
-        let size = protocol.len() + status_code.len() + 3;
-        let mut buf = Vec::with_capacity(size);
-
-        buf.append(&mut protocol);
-        buf.push(b' ');
-        buf.append(&mut status_code);
-        buf.extend(b"\r\n");
+        let mut buf = Vec::new();
+        self.write_to(&mut buf);
         buf
     }
 }
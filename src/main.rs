@@ -1,24 +1,93 @@
-use fast_web_server_impl::{FastWebServer, RegisterEndpoint, bind};
-use fast_web_server_macros::{get, post};
-use fast_web_server_types::{HttpRequest, RequestType};
+use fast_web_server_impl::{ConnectionLimits, Event, FastWebServer, LastEventId, Message, RouteGroup, Sse, StaticFiles, TlsConfig, WebSocket, bind};
+use fast_web_server_impl::middleware::{AccessLog, Auth, Compression, LogFormat};
+use fast_web_server_macros::{get, instrument, post, route, websocket};
+use fast_web_server_types::{Header, HttpRequest, Json, Path, RequestId, RequestType, State};
+use serde::Deserialize;
 
 fn main() -> Result<(), String> {
-    let mut server = FastWebServer::new("0.0.0.0:7878", 4);
-    bind![server, test_getter, test_getter2, mirror_response];
+    tracing_subscriber::fmt::init();
+    let mut server = FastWebServer::new("0.0.0.0:7878", 4)
+        .with_state(Config { name: String::from("fast-web-server") });
+    server.wrap(AccessLog::new(LogFormat::Combined));
+    server.wrap(Compression::new());
+    server.expose_metrics("/metrics");
+    server.connection_limits(ConnectionLimits::new().max_connections(256).max_queued(64));
+    if let (Ok(cert), Ok(key)) = (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        server.tls(TlsConfig::new(cert, key)).map_err(|e| e.to_string())?;
+    }
+    bind![server, test_getter, test_getter2, sized_getter, mirror_response, greet, server_name, request_id, echo, events];
+    server.body_limit(RequestType::POST, "/mirror", 8 * 1024 * 1024);
+    for (route, body) in [("/hello", "Hello!"), ("/bye", "Goodbye!")] {
+        server.bind(RequestType::GET, route, move |_request: HttpRequest| body);
+    }
+
+    let mut admin = RouteGroup::new("/admin");
+    admin.wrap(Auth::bearer("admin", |token| token == "secret"));
+    bind![admin, server_name];
+    server.mount(admin);
+    server.mount(StaticFiles::new("/assets", "./public").spa_fallback(true));
     server.run()
 }
 
 #[get("/test3")]
+#[instrument]
 fn test_getter2(_request: HttpRequest) -> Vec<u8> {
     vec![62; 1000000]
 }
 
+#[instrument]
+#[get("/test/{size}")]
+fn sized_getter(size: usize) -> Vec<u8> {
+    vec![62; size]
+}
+
 #[get("/test")]
 fn test_getter(_request: HttpRequest) -> Vec<u8> {
     "test".to_string().into_bytes()
 }
 
-#[post("/mirror")]
+#[get("/request-id")]
+fn request_id(id: RequestId) -> String {
+    id.to_string()
+}
+
+#[route("/mirror", method = "POST", method = "PUT")]
 fn mirror_response(request: HttpRequest) -> Vec<u8> {
     request.body.into_bytes()
 }
+
+#[websocket("/echo")]
+fn echo(mut socket: WebSocket) {
+    while let Ok(message) = socket.recv() {
+        if let Message::Text(_) | Message::Binary(_) = message {
+            if socket.send(message).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[get("/events")]
+fn events(LastEventId(last): LastEventId) -> Sse {
+    let start = last.and_then(|id| id.parse::<u32>().ok()).map_or(0, |id| id + 1);
+    Sse::from_events((start..start + 10).map(|i| Event::data(format!("tick {}", i)).id(i.to_string())))
+}
+
+struct Config {
+    name: String,
+}
+
+#[get("/name")]
+fn server_name(config: &Config) -> String {
+    config.name.clone()
+}
+
+#[derive(Deserialize)]
+struct Greeting {
+    greeting: String,
+}
+
+#[post("/greet/{name}")]
+fn greet(Path(name): Path<String>, Header(user_agent): Header<String>, Json(body): Json<Greeting>, State(config): State<Config>) -> String {
+    format!("{}, {}! ({} via {})", body.greeting, name, user_agent, config.name)
+}